@@ -0,0 +1,297 @@
+//! What the FastCGI path costs, next to serving the same Axum app over plain HTTP
+//! with `axum::serve`. For each kind of request, there's a timing group (in
+//! requests per second) and an allocation group (in allocations per request),
+//! each with an `fcgi` and an `http` side, so there's a baseline to compare
+//! against when something's supposed to make us faster (or might have made us
+//! slower).
+//!
+//! Both sides go over real sockets on the same machine, one request per
+//! connection: the FastCGI side uses the in-process client from the `testing`
+//! feature (a socket pair, through the real accept loop and handler), and the HTTP
+//! side uses a bare-bones HTTP/1.1 client over loopback TCP. Both servers get
+//! started once, before any measuring. The allocation counts include both
+//! clients, which are about equally lazy, so compare the two sides to each other
+//! (or to the same side on another commit), not to zero.
+//!
+//! Then there's one more FastCGI-only case, with every request going down the
+//! same kept-alive connection, the way mod_fcgid and nginx's `fastcgi_keep_conn`
+//! send them. That's where anything we hang on to per connection shows up. (No
+//! `http` side for that one; the HTTP client only knows how to hang up.)
+//!
+//! Run it with `cargo bench` on an otherwise quiet machine, and only compare
+//! numbers from the same machine.
+use axum::{
+    routing::{get, post},
+    Router,
+};
+use busride_rs::testing::{TestConnection, TestRequest, TestServer};
+use busride_rs::Settings;
+use bytes::Bytes;
+use criterion::measurement::{Measurement, ValueFormatter};
+use criterion::{criterion_group, criterion_main, BenchmarkGroup, Criterion, Throughput};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::runtime::Runtime;
+
+/// Counts every allocation, so we can see how many each request costs.
+struct CountingAlloc;
+
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+/// A Criterion measurement that counts allocations instead of time.
+struct Allocations;
+
+impl Measurement for Allocations {
+    type Intermediate = u64;
+    type Value = u64;
+
+    fn start(&self) -> u64 {
+        ALLOCATIONS.load(Ordering::Relaxed)
+    }
+
+    fn end(&self, started: u64) -> u64 {
+        ALLOCATIONS.load(Ordering::Relaxed) - started
+    }
+
+    fn add(&self, a: &u64, b: &u64) -> u64 {
+        a + b
+    }
+
+    fn zero(&self) -> u64 {
+        0
+    }
+
+    fn to_f64(&self, value: &u64) -> f64 {
+        *value as f64
+    }
+
+    fn formatter(&self) -> &dyn ValueFormatter {
+        &AllocationFormatter
+    }
+}
+
+/// Allocation counts are just numbers; there's nothing to scale.
+struct AllocationFormatter;
+
+impl ValueFormatter for AllocationFormatter {
+    fn scale_values(&self, _typical: f64, _values: &mut [f64]) -> &'static str {
+        "allocs"
+    }
+
+    fn scale_throughputs(
+        &self,
+        _typical: f64,
+        throughput: &Throughput,
+        values: &mut [f64],
+    ) -> &'static str {
+        let (per, unit) = match throughput {
+            Throughput::Bytes(n) | Throughput::BytesDecimal(n) => (*n, "allocs/byte"),
+            Throughput::Elements(n) => (*n, "allocs/req"),
+        };
+        for value in values {
+            *value /= per as f64;
+        }
+        unit
+    }
+
+    fn scale_for_machines(&self, _values: &mut [f64]) -> &'static str {
+        "allocs"
+    }
+}
+
+const LARGE_BODY_BYTES: usize = 1024 * 1024;
+const POST_BODY_BYTES: usize = 64 * 1024;
+
+/// One kind of request to measure.
+struct Case {
+    name: &'static str,
+    method: &'static str,
+    path: &'static str,
+    body: Vec<u8>,
+}
+
+fn cases() -> [Case; 3] {
+    [
+        Case {
+            name: "small GET",
+            method: "GET",
+            path: "/small",
+            body: Vec::new(),
+        },
+        Case {
+            name: "large GET (1 MiB)",
+            method: "GET",
+            path: "/large",
+            body: Vec::new(),
+        },
+        Case {
+            name: "POST (64 KiB body)",
+            method: "POST",
+            path: "/post",
+            body: vec![b'x'; POST_BODY_BYTES],
+        },
+    ]
+}
+
+/// The app under test: about as little as an app can do, so what's left is us.
+fn app() -> Router {
+    Router::new()
+        .route("/small", get(|| async { "hi" }))
+        .route("/large", get(|| async { vec![b'x'; LARGE_BODY_BYTES] }))
+        .route(
+            "/post",
+            post(|body: Bytes| async move { body.len().to_string() }),
+        )
+}
+
+/// Both servers, up and running, plus the runtime they're running on.
+struct Servers {
+    // Single-threaded, so the numbers are about our overhead, not the scheduler's.
+    rt: Runtime,
+    fcgi: TestServer,
+    http: SocketAddr,
+}
+
+impl Servers {
+    fn start() -> Self {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let (fcgi, http) = rt.block_on(async {
+            let fcgi = TestServer::new(app(), Settings::new());
+            let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+            let http = listener.local_addr().unwrap();
+            tokio::spawn(async move { axum::serve(listener, app()).await.unwrap() });
+            (fcgi, http)
+        });
+        Self { rt, fcgi, http }
+    }
+
+    /// Add the `fcgi` and `http` sides of a case to a group.
+    fn bench_case<M: Measurement>(&self, group: &mut BenchmarkGroup<'_, M>, case: &Case) {
+        group.throughput(Throughput::Elements(1));
+        group.bench_function("fcgi", |b| {
+            b.to_async(&self.rt).iter(|| via_fcgi(&self.fcgi, case))
+        });
+        group.bench_function("http", |b| {
+            b.to_async(&self.rt).iter(|| via_http(self.http, case))
+        });
+    }
+
+    /// Add the kept-alive version of a case to a group: just an `fcgi` side, on
+    /// one connection for the whole run.
+    fn bench_kept_alive<M: Measurement>(&self, group: &mut BenchmarkGroup<'_, M>, case: &Case) {
+        let conn = self.rt.block_on(async { self.fcgi.connect() }).unwrap();
+        // Every iteration needs the connection, and there's only one.
+        let conn = tokio::sync::Mutex::new(conn);
+        group.throughput(Throughput::Elements(1));
+        group.bench_function("fcgi", |b| {
+            b.to_async(&self.rt)
+                .iter(|| async { via_fcgi_connection(&mut *conn.lock().await, case).await })
+        });
+    }
+}
+
+/// The case that gets the kept-alive treatment.
+fn kept_alive_case() -> Case {
+    Case {
+        name: "small GET, kept alive",
+        method: "GET",
+        path: "/small",
+        body: Vec::new(),
+    }
+}
+
+fn requests_per_second(c: &mut Criterion) {
+    let servers = Servers::start();
+    for case in &cases() {
+        let mut group = c.benchmark_group(case.name);
+        servers.bench_case(&mut group, case);
+        group.finish();
+    }
+    let case = kept_alive_case();
+    let mut group = c.benchmark_group(case.name);
+    servers.bench_kept_alive(&mut group, &case);
+    group.finish();
+}
+
+fn allocations_per_request(c: &mut Criterion<Allocations>) {
+    let servers = Servers::start();
+    for case in &cases() {
+        let mut group = c.benchmark_group(format!("{} allocations", case.name));
+        servers.bench_case(&mut group, case);
+        group.finish();
+    }
+    let case = kept_alive_case();
+    let mut group = c.benchmark_group(format!("{} allocations", case.name));
+    servers.bench_kept_alive(&mut group, &case);
+    group.finish();
+}
+
+fn fcgi_request(case: &Case) -> TestRequest {
+    let req = TestRequest::new(case.method, case.path);
+    if case.body.is_empty() {
+        req
+    } else {
+        req.body(case.body.clone())
+    }
+}
+
+/// One round trip through the FastCGI path.
+async fn via_fcgi(server: &TestServer, case: &Case) {
+    let resp = server.send(fcgi_request(case)).await.unwrap();
+    assert_eq!(resp.status(), Some(axum::http::StatusCode::OK));
+}
+
+/// One round trip through the FastCGI path, on a connection that's already open.
+async fn via_fcgi_connection(conn: &mut TestConnection, case: &Case) {
+    let resp = conn.send(fcgi_request(case)).await.unwrap();
+    assert_eq!(resp.status(), Some(axum::http::StatusCode::OK));
+}
+
+/// One round trip over plain HTTP, with the least client we can get away with.
+async fn via_http(addr: SocketAddr, case: &Case) {
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    let head = format!(
+        "{} {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\nContent-Length: {}\r\n\r\n",
+        case.method,
+        case.path,
+        case.body.len()
+    );
+    stream.write_all(head.as_bytes()).await.unwrap();
+    stream.write_all(&case.body).await.unwrap();
+    let mut resp = Vec::new();
+    stream.read_to_end(&mut resp).await.unwrap();
+    assert!(resp.starts_with(b"HTTP/1.1 200"));
+}
+
+criterion_group!(timing, requests_per_second);
+criterion_group! {
+    name = allocations;
+    config = Criterion::default().with_measurement(Allocations);
+    targets = allocations_per_request
+}
+criterion_main!(timing, allocations);
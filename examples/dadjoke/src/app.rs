@@ -10,34 +10,18 @@ use std::sync::atomic::{AtomicU32, Ordering};
 #[derive(Clone)]
 struct DadState {
     counter: &'static AtomicU32,
-    mount_path: String,
 }
 
-/// Creates a new instance of dad app, to be mounted at the specified URI path.
-/// Mount paths should start with /; for a default mount at the root of the domain,
-/// pass either "/" or "".
-/// Doing this optional mount thing is honestly a little wonky, because Axum's
-/// Path extractors always get the whole path without de-nesting, so you have
-/// to thread handling for it through all your routes. But it makes a neat demo,
-/// and I can imagine a use for it IRL, fussy or no.
-pub fn dadapp(mount_path: &str) -> Router {
+/// Creates a new instance of dad app. It's written as if it lives at the root of
+/// the domain; to mount it somewhere else, wrap it in a busride_rs::MountLayer
+/// (see main.rs), and it'll never know the difference.
+pub fn dadapp() -> Router {
     static COUNTER: AtomicU32 = AtomicU32::new(0);
-    let state = DadState {
-        counter: &COUNTER,
-        mount_path: mount_path
-            .strip_prefix('/')
-            .unwrap_or(mount_path)
-            .to_string(),
-    };
-    let app = Router::new()
+    let state = DadState { counter: &COUNTER };
+    Router::new()
         .route("/*allyall", get(dad))
         .route("/", get(rooty).post(post_dad))
-        .with_state(state);
-    if mount_path == "/" || mount_path.is_empty() {
-        app
-    } else {
-        Router::new().nest(mount_path, app)
-    }
+        .with_state(state)
 }
 
 /// GET handler for main app route, which consumes the entire URI path beyond the root.
@@ -46,10 +30,9 @@ async fn dad(
     State(state): State<DadState>,
 ) -> Result<String, StatusCode> {
     let known_visits = state.counter.fetch_add(1, Ordering::Relaxed) + 1;
-    let name = path.strip_prefix(&state.mount_path).unwrap_or(&path);
     Ok(format!(
         "Hi {}, i'm dad\n\n{} dads joked so far this lifetime",
-        name, known_visits,
+        path, known_visits,
     ))
 }
 
@@ -3,8 +3,12 @@
 //! serve itself via FastCGI, using a socket passed to it by the web server
 //! that invoked it. In other words, you can give it its own server like normal,
 //! OR you can throw it up onto shared hosting and forget about it.
+use axum::ServiceExt;
+use busride_rs::MountLayer;
 use clap::Parser;
+use std::num::NonZeroUsize;
 use tokio::net::TcpListener;
+use tower::Layer;
 use tracing_subscriber::{fmt, layer::SubscriberExt, util::SubscriberInitExt};
 
 mod app;
@@ -23,18 +27,25 @@ struct Cli {
     /// trailing slash, like `/nested/`.
     #[arg(long, value_name = "PATH")]
     mount: Option<String>,
+
+    /// How many worker threads to run. Defaults to 4.
+    #[arg(long)]
+    threads: Option<NonZeroUsize>,
 }
 
 /// Word of warning about the Tokio multi-thread runtime: it defaults
 /// to spawning a worker thread per logical CPU core. That's fine for a
 /// single-purpose container or VM, but is NOT what you want in a
-/// shared hosting environment. Here we're hardcoding a tame thread
-/// limit, but in a real app you should skip the tokio::main macro and
-/// make the cap configurable at run time.
-#[tokio::main(flavor = "multi_thread", worker_threads = 4)]
-async fn main() {
+/// shared hosting environment. So instead of the tokio::main macro, we
+/// build a tame runtime ourselves, with a thread cap you can set at run time.
+fn main() {
     let args = Cli::parse();
+    let threads = args.threads.unwrap_or(NonZeroUsize::new(4).unwrap());
+    let runtime = busride_rs::tame_runtime(threads).expect("couldn't start the Tokio runtime");
+    runtime.block_on(serve(args));
+}
 
+async fn serve(args: Cli) {
     // validate and munge
     if args.fcgi && args.port.is_some() {
         panic!("The --fcgi and --port options are mutually exclusive. Choose one!");
@@ -42,8 +53,9 @@ async fn main() {
     let mount = args.mount.as_deref().unwrap_or("/");
     let port = args.port.unwrap_or(3000);
 
-    // get app
-    let dadapp = app::dadapp(mount);
+    // get app, and mount it wherever we were told to. This works the same in
+    // both modes, so the app itself doesn't have to care.
+    let dadapp = MountLayer::new(mount).layer(app::dadapp());
 
     // Set up tracing
     tracing_subscriber::registry()
@@ -59,13 +71,19 @@ async fn main() {
     // blast off
     if args.fcgi {
         println!("Serving in fcgi mode, mounted at {}...", mount);
-        busride_rs::serve_fcgid_with_graceful_shutdown(dadapp, 50.try_into().unwrap(), quit())
-            .await
-            .unwrap();
+        if let Err(e) =
+            busride_rs::serve_fcgid_with_graceful_shutdown(dadapp, 50.try_into().unwrap(), quit())
+                .await
+        {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
     } else {
         println!("Serving on port {}, mounted at {}...", port, mount);
         let listener = TcpListener::bind(("0.0.0.0", port)).await.unwrap();
-        axum::serve(listener, dadapp)
+        // Mount works with any request body type, so say which one axum::serve uses.
+        let make_service = ServiceExt::<axum::extract::Request>::into_make_service(dadapp);
+        axum::serve(listener, make_service)
             .with_graceful_shutdown(quit())
             .await
             .unwrap();
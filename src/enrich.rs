@@ -0,0 +1,503 @@
+//! The FastCGI-specific parts of building a request (reconstructing the URI,
+//! client address, scheme, and mount point from CGI vars), packaged up as tower
+//! middleware so you can compose it yourself or run it in tests without a web
+//! server in the way.
+use crate::{
+    ClientCert, FcgiVars, Gateway, RequestError, ScriptName, TransportInfo, HTTPS, HTTP_HOST,
+    REMOTE_PORT, REQUEST_SCHEME,
+};
+use axum::BoxError;
+use bytes::Bytes;
+use fastcgi_server::cgi;
+use futures_util::future::BoxFuture;
+use http_body::Body as HttpBody;
+use std::net::{IpAddr, SocketAddr};
+use std::task::{Context, Poll};
+use tower::{Layer, Service};
+
+/// A tower layer that fills in a request's URI, `Host` header, and extensions
+/// (`ConnectInfo`, `Scheme`, [`TransportInfo`], and maybe [`ScriptName`] and
+/// [`ClientCert`]) from the [`FcgiVars`] in its extensions, the same way the
+/// serve functions do for real FastCGI requests.
+/// Requests without any `FcgiVars` pass through untouched.
+///
+/// You don't need this to serve an app; the serve functions already do all this.
+/// It's for testing handlers that care about the FastCGI details over a plain
+/// HTTP transport, or for stacking the enrichment somewhere specific yourself.
+/// Requests whose vars don't add up to a valid URI get a `400 Bad Request`
+/// without reaching the inner service.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FcgiEnrichLayer {
+    path_from_path_info: bool,
+}
+
+impl FcgiEnrichLayer {
+    /// Same as `FcgiEnrichLayer::default()`.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Build request paths from `PATH_INFO`; see
+    /// [`Settings::path_from_path_info`](crate::Settings::path_from_path_info).
+    pub fn path_from_path_info(mut self, enabled: bool) -> Self {
+        self.path_from_path_info = enabled;
+        self
+    }
+}
+
+impl<S> Layer<S> for FcgiEnrichLayer {
+    type Service = FcgiEnrich<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        FcgiEnrich {
+            inner,
+            path_from_path_info: self.path_from_path_info,
+        }
+    }
+}
+
+/// The service made by [`FcgiEnrichLayer`].
+#[derive(Debug, Clone)]
+pub struct FcgiEnrich<S> {
+    inner: S,
+    path_from_path_info: bool,
+}
+
+impl<S, ReqBody, B> Service<http::Request<ReqBody>> for FcgiEnrich<S>
+where
+    S: Service<http::Request<ReqBody>, Response = http::Response<B>>,
+    S::Future: Send + 'static,
+    B: HttpBody<Data = Bytes> + Send + 'static,
+    B::Error: Into<BoxError>,
+{
+    type Response = http::Response<axum::body::Body>;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: http::Request<ReqBody>) -> Self::Future {
+        if let Some(vars) = req.extensions_mut().remove::<FcgiVars>() {
+            let enriched =
+                enrich_request(&mut req, &vars, self.path_from_path_info, Gateway::FastCgi);
+            req.extensions_mut().insert(vars);
+            if enriched.is_err() {
+                let mut resp = http::Response::new(axum::body::Body::empty());
+                *resp.status_mut() = http::StatusCode::BAD_REQUEST;
+                return Box::pin(async move { Ok(resp) });
+            }
+        }
+        let fut = self.inner.call(req);
+        Box::pin(async move { fut.await.map(|resp| resp.map(axum::body::Body::new)) })
+    }
+}
+
+/// Do the actual enriching, for both [`FcgiEnrich`] and the serve functions.
+pub(crate) fn enrich_request<ReqBody>(
+    req: &mut http::Request<ReqBody>,
+    vars: &FcgiVars,
+    path_from_path_info: bool,
+    gateway: Gateway,
+) -> Result<(), RequestError> {
+    let scheme = scheme_from_vars(vars);
+    let authority = authority_from_vars(vars, &scheme);
+    let path_and_query = path_and_query_from_vars(vars, path_from_path_info)?;
+    match authority {
+        Some(authority) => {
+            // If the client didn't send Host, give the app one to match the URI. If
+            // it did, leave it be; the URI is where our best guess goes.
+            if !req.headers().contains_key(http::header::HOST) {
+                let host =
+                    http::HeaderValue::from_str(authority.as_str()).map_err(http::Error::from)?;
+                req.headers_mut().insert(http::header::HOST, host);
+            }
+            *req.uri_mut() = http::Uri::builder()
+                .scheme(scheme.clone())
+                .authority(authority)
+                .path_and_query(path_and_query)
+                .build()?;
+        }
+        // Without a host, the best we can do is an origin-form URI.
+        None => *req.uri_mut() = http::Uri::from(path_and_query),
+    }
+    // Axum apps usually find the client address via ConnectInfo, which the normal
+    // axum::serve provides. Mimic that, so the same handlers work in both modes.
+    if let Some(addr) = remote_addr_from_vars(vars) {
+        req.extensions_mut()
+            .insert(axum::extract::ConnectInfo(addr));
+    }
+    req.extensions_mut().insert(TransportInfo {
+        gateway,
+        scheme: scheme.clone(),
+    });
+    req.extensions_mut().insert(scheme);
+    if let Some(cert) = ClientCert::from_vars(vars) {
+        req.extensions_mut().insert(cert);
+    }
+    if path_from_path_info {
+        if let Some(script_name) = vars.get(cgi::SCRIPT_NAME) {
+            let script_name = String::from_utf8_lossy(script_name).into_owned();
+            req.extensions_mut().insert(ScriptName(script_name));
+        }
+    }
+    Ok(())
+}
+
+/// Work out the path and query for a request. Normally that's just REQUEST_URI,
+/// verbatim. But REQUEST_URI isn't part of the CGI spec, and not every server sends
+/// it; without it, SCRIPT_NAME + PATH_INFO + QUERY_STRING add up to the same thing.
+/// With `path_from_path_info` on, we skip SCRIPT_NAME on purpose, which de-nests
+/// the path from the app's mount point. Either way, an empty path means "/".
+///
+/// Mind the encodings: REQUEST_URI and QUERY_STRING arrive raw, exactly as the
+/// client sent them, but the web server has already percent-decoded SCRIPT_NAME
+/// and PATH_INFO. So those two get re-encoded on the way in, and the app sees a
+/// path it can decode exactly once, same as with REQUEST_URI. The one thing that
+/// can't survive the round trip is an encoded slash (`%2F`), which is just a `/`
+/// by the time it's in PATH_INFO (if the web server let it through at all; Apache
+/// refuses them unless `AllowEncodedSlashes` says otherwise). Apps that care about
+/// telling those apart should leave `path_from_path_info` off.
+pub(crate) fn path_and_query_from_vars(
+    vars: &FcgiVars,
+    path_from_path_info: bool,
+) -> Result<http::uri::PathAndQuery, http::uri::InvalidUri> {
+    let path_info = vars.get(cgi::PATH_INFO).unwrap_or(b"");
+    if path_from_path_info {
+        return assemble_path_and_query(&[path_info], query_from_vars(vars));
+    }
+    match vars.get(cgi::REQUEST_URI) {
+        Some(uri) => http::uri::PathAndQuery::try_from(uri),
+        None => {
+            let script_name = vars.get(cgi::SCRIPT_NAME).unwrap_or(b"");
+            assemble_path_and_query(&[script_name, path_info], query_from_vars(vars))
+        }
+    }
+}
+
+/// The query string for a request, minus the leading "?". QUERY_STRING is in the
+/// CGI spec, so servers set it reliably (as an empty string when there's no query);
+/// the tail end of REQUEST_URI is only a fallback. Empty queries count as None.
+fn query_from_vars(vars: &FcgiVars) -> Option<&[u8]> {
+    vars.get(cgi::QUERY_STRING)
+        .or_else(|| {
+            let uri = vars.get(cgi::REQUEST_URI)?;
+            let start = uri.iter().position(|b| *b == b'?')? + 1;
+            Some(&uri[start..])
+        })
+        .filter(|q| !q.is_empty())
+}
+
+/// Glue some decoded path pieces and an optional raw query back together into a
+/// PathAndQuery, percent-encoding the path as we go.
+fn assemble_path_and_query(
+    path_parts: &[&[u8]],
+    query: Option<&[u8]>,
+) -> Result<http::uri::PathAndQuery, http::uri::InvalidUri> {
+    let mut buf: Vec<u8> = Vec::new();
+    for part in path_parts {
+        encode_path_into(&mut buf, part);
+    }
+    if buf.is_empty() {
+        buf.push(b'/');
+    }
+    if let Some(query) = query {
+        buf.push(b'?');
+        buf.extend_from_slice(query);
+    }
+    http::uri::PathAndQuery::try_from(buf.as_slice())
+}
+
+/// Percent-encode a decoded path, leaving alone everything RFC 3986 allows in a
+/// path as-is (including `/`). That covers `%` itself, so a literal percent sign
+/// in a file name doesn't turn into an escape.
+fn encode_path_into(buf: &mut Vec<u8>, path: &[u8]) {
+    const HEX: &[u8; 16] = b"0123456789ABCDEF";
+    for &b in path {
+        let allowed = b.is_ascii_alphanumeric() || b"-._~!$&'()*+,;=:@/".contains(&b);
+        if allowed {
+            buf.push(b);
+        } else {
+            buf.extend_from_slice(&[b'%', HEX[(b >> 4) as usize], HEX[(b & 0xf) as usize]]);
+        }
+    }
+}
+
+/// Reconstruct the client's socket address from the REMOTE_ADDR and REMOTE_PORT
+/// vars. Returns None if either one is missing or doesn't parse, since a
+/// half-known address isn't something handlers should have to second-guess.
+fn remote_addr_from_vars(vars: &FcgiVars) -> Option<SocketAddr> {
+    let ip: IpAddr = std::str::from_utf8(vars.get(cgi::REMOTE_ADDR)?)
+        .ok()?
+        .parse()
+        .ok()?;
+    let port: u16 = std::str::from_utf8(vars.get(REMOTE_PORT)?)
+        .ok()?
+        .parse()
+        .ok()?;
+    Some(SocketAddr::new(ip, port))
+}
+
+/// Figure out whether the original request arrived over TLS. Apache sets `HTTPS=on`
+/// for TLS requests, and newer versions also set `REQUEST_SCHEME`. When neither var
+/// is present, we treat the request as plain http; servers generally leave both
+/// unset for non-TLS requests, so that's the honest reading (and a safe one, since
+/// erring towards http just means an app won't set Secure cookies).
+fn scheme_from_vars(vars: &FcgiVars) -> http::uri::Scheme {
+    let https_on = vars
+        .get(HTTPS)
+        .is_some_and(|v| v.eq_ignore_ascii_case(b"on") || v == b"1");
+    let scheme_is_https = vars
+        .get(REQUEST_SCHEME)
+        .is_some_and(|v| v.eq_ignore_ascii_case(b"https"));
+    if https_on || scheme_is_https {
+        http::uri::Scheme::HTTPS
+    } else {
+        http::uri::Scheme::HTTP
+    }
+}
+
+/// Figure out the host (and maybe port) the client was trying to reach. The Host
+/// header is what the client actually asked for, so its name always wins, and so
+/// does its port if it has one, even when `SERVER_PORT` says something else (the
+/// web server's own port, behind a port-forwarding proxy, say). If it doesn't
+/// have a port, the port comes from `SERVER_PORT`. If there's no Host header, or
+/// it's too mangled to be an authority, we go by `SERVER_NAME` and `SERVER_PORT`
+/// instead. Either way, the port gets left off if it's the scheme's default.
+/// Returns None if nothing usable turns up.
+///
+/// This only decides the URI. The Host header itself stays exactly as the client
+/// sent it, so the two can differ by a port (or entirely, if the header was junk).
+fn authority_from_vars(
+    vars: &FcgiVars,
+    scheme: &http::uri::Scheme,
+) -> Option<http::uri::Authority> {
+    let port: Option<u16> = vars
+        .get(cgi::SERVER_PORT)
+        .and_then(|p| std::str::from_utf8(p).ok())
+        .and_then(|p| p.parse().ok());
+    let default_port = if *scheme == http::uri::Scheme::HTTPS {
+        443
+    } else {
+        80
+    };
+    let port = port.filter(|p| *p != default_port);
+    let from_host = vars
+        .get(HTTP_HOST)
+        .and_then(|host| http::uri::Authority::try_from(host).ok());
+    if let Some(authority) = from_host {
+        return match port {
+            Some(port) if authority.port().is_none() => {
+                http::uri::Authority::try_from(format!("{}:{}", authority.host(), port)).ok()
+            }
+            _ => Some(authority),
+        };
+    }
+    let name = std::str::from_utf8(vars.get(cgi::SERVER_NAME)?).ok()?;
+    let authority = match port {
+        Some(port) => format!("{}:{}", name, port),
+        None => name.to_string(),
+    };
+    http::uri::Authority::try_from(authority).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars(pairs: &[(&'static str, &'static str)]) -> FcgiVars {
+        pairs.iter().copied().collect()
+    }
+
+    fn path_and_query(pairs: &[(&'static str, &'static str)], from_path_info: bool) -> String {
+        path_and_query_from_vars(&vars(pairs), from_path_info)
+            .unwrap()
+            .to_string()
+    }
+
+    #[test]
+    fn request_uri_is_used_verbatim() {
+        let pairs = [
+            ("REQUEST_URI", "/app/items/a%20b?q=caf%C3%A9&x=1"),
+            ("SCRIPT_NAME", "/app"),
+            ("PATH_INFO", "/items/a b"),
+            ("QUERY_STRING", "q=caf%C3%A9&x=1"),
+        ];
+        assert_eq!(
+            path_and_query(&pairs, false),
+            "/app/items/a%20b?q=caf%C3%A9&x=1"
+        );
+    }
+
+    #[test]
+    fn path_info_gets_the_query_string() {
+        let pairs = [
+            ("REQUEST_URI", "/app/items/a%20b?q=caf%C3%A9&x=1"),
+            ("SCRIPT_NAME", "/app"),
+            ("PATH_INFO", "/items/a b"),
+            ("QUERY_STRING", "q=caf%C3%A9&x=1"),
+        ];
+        assert_eq!(path_and_query(&pairs, true), "/items/a%20b?q=caf%C3%A9&x=1");
+    }
+
+    #[test]
+    fn query_falls_back_to_the_end_of_request_uri() {
+        let pairs = [("REQUEST_URI", "/app/items?q=1"), ("PATH_INFO", "/items")];
+        assert_eq!(path_and_query(&pairs, true), "/items?q=1");
+    }
+
+    #[test]
+    fn empty_query_leaves_no_question_mark() {
+        let pairs = [
+            ("SCRIPT_NAME", "/app"),
+            ("PATH_INFO", "/items"),
+            ("QUERY_STRING", ""),
+        ];
+        assert_eq!(path_and_query(&pairs, false), "/app/items");
+        assert_eq!(path_and_query(&pairs, true), "/items");
+    }
+
+    #[test]
+    fn missing_request_uri_is_rebuilt_from_the_pieces() {
+        let pairs = [
+            ("SCRIPT_NAME", "/app"),
+            ("PATH_INFO", "/items/a b"),
+            ("QUERY_STRING", "q=%2F"),
+        ];
+        assert_eq!(path_and_query(&pairs, false), "/app/items/a%20b?q=%2F");
+    }
+
+    #[test]
+    fn nothing_at_all_means_the_root() {
+        assert_eq!(path_and_query(&[], false), "/");
+        assert_eq!(path_and_query(&[], true), "/");
+        assert_eq!(path_and_query(&[("PATH_INFO", "")], true), "/");
+    }
+
+    // We pass paths along as the client sent them; collapsing `..` or doubled
+    // slashes is the router's business (and the web server's, for files).
+    #[test]
+    fn dot_segments_and_doubled_slashes_pass_through() {
+        let pairs = [
+            ("REQUEST_URI", "/a//b/../c"),
+            ("SCRIPT_NAME", ""),
+            ("PATH_INFO", "/a//b/../c"),
+        ];
+        assert_eq!(path_and_query(&pairs, false), "/a//b/../c");
+        assert_eq!(path_and_query(&pairs, true), "/a//b/../c");
+    }
+
+    #[test]
+    fn unusable_request_uri_is_an_error() {
+        let pairs = [("REQUEST_URI", "/has a space")];
+        assert!(path_and_query_from_vars(&vars(&pairs), false).is_err());
+    }
+
+    // PATH_INFO arrives decoded, so everything here is the decoded form, and what
+    // comes out should decode back to exactly that, once.
+    #[test]
+    fn encode_path_into_cases() {
+        let cases: &[(&[u8], &str)] = &[
+            (b"/plain/path", "/plain/path"),
+            (b"/a b", "/a%20b"),
+            ("/caf\u{e9}/\u{1f68c}".as_bytes(), "/caf%C3%A9/%F0%9F%9A%8C"),
+            (b"/not-utf8-\xff", "/not-utf8-%FF"),
+            // A literal "%2F" in a decoded path was "%252F" on the wire; it's
+            // not a slash, and it mustn't turn into one.
+            (b"/a%2Fb", "/a%252Fb"),
+            (b"/already%25encoded", "/already%2525encoded"),
+            (b"/100%", "/100%25"),
+            (b"/what?#", "/what%3F%23"),
+            (b"/-._~!$&'()*+,;=:@", "/-._~!$&'()*+,;=:@"),
+        ];
+        for (decoded, expected) in cases {
+            let mut buf = Vec::new();
+            encode_path_into(&mut buf, decoded);
+            assert_eq!(
+                String::from_utf8(buf).unwrap(),
+                *expected,
+                "encoding {}",
+                decoded.escape_ascii()
+            );
+        }
+    }
+
+    type Pairs = [(&'static str, &'static str)];
+
+    fn uri_for(pairs: &Pairs) -> (String, Option<String>) {
+        let mut req = http::Request::new(());
+        if let Some((_, host)) = pairs.iter().find(|(k, _)| *k == "HTTP_HOST") {
+            req.headers_mut()
+                .insert(http::header::HOST, http::HeaderValue::from_static(host));
+        }
+        enrich_request(&mut req, &vars(pairs), false, Gateway::FastCgi).unwrap();
+        let host = req
+            .headers()
+            .get(http::header::HOST)
+            .map(|h| h.to_str().unwrap().to_string());
+        (req.uri().to_string(), host)
+    }
+
+    #[test]
+    fn authority_cases() {
+        let base = [("REQUEST_URI", "/x"), ("SERVER_NAME", "example.com")];
+        // (extra vars, expected URI, expected Host header)
+        let cases: &[(&Pairs, &str, Option<&str>)] = &[
+            // Host with a port beats SERVER_PORT.
+            (
+                &[("HTTP_HOST", "example.org:8443"), ("SERVER_PORT", "8080")],
+                "http://example.org:8443/x",
+                Some("example.org:8443"),
+            ),
+            // Host without a port borrows SERVER_PORT, but the header stays put.
+            (
+                &[("HTTP_HOST", "example.org"), ("SERVER_PORT", "8080")],
+                "http://example.org:8080/x",
+                Some("example.org"),
+            ),
+            // Default ports get left off.
+            (
+                &[("HTTP_HOST", "example.org"), ("SERVER_PORT", "80")],
+                "http://example.org/x",
+                Some("example.org"),
+            ),
+            (
+                &[("HTTP_HOST", "[::1]"), ("SERVER_PORT", "8080")],
+                "http://[::1]:8080/x",
+                Some("[::1]"),
+            ),
+            (
+                &[("HTTP_HOST", "[::1]:8443"), ("SERVER_PORT", "8080")],
+                "http://[::1]:8443/x",
+                Some("[::1]:8443"),
+            ),
+            // Junk Host falls back to SERVER_NAME, and stays junk in the header.
+            (
+                &[("HTTP_HOST", "bad host"), ("SERVER_PORT", "8080")],
+                "http://example.com:8080/x",
+                Some("bad host"),
+            ),
+            // No Host at all: SERVER_NAME, and a Host header to match.
+            (
+                &[("SERVER_PORT", "8080")],
+                "http://example.com:8080/x",
+                Some("example.com:8080"),
+            ),
+            (&[], "http://example.com/x", Some("example.com")),
+        ];
+        for (extra, uri, host) in cases {
+            let pairs: Vec<_> = base.iter().chain(extra.iter()).copied().collect();
+            let (got_uri, got_host) = uri_for(&pairs);
+            assert_eq!(got_uri, *uri, "with {:?}", extra);
+            assert_eq!(got_host.as_deref(), *host, "with {:?}", extra);
+        }
+    }
+
+    #[test]
+    fn no_name_at_all_means_origin_form() {
+        let (uri, host) = uri_for(&[("REQUEST_URI", "/x"), ("HTTP_HOST", "bad host")]);
+        assert_eq!(uri, "/x");
+        assert_eq!(host.as_deref(), Some("bad host"));
+    }
+}
@@ -0,0 +1,71 @@
+//! What can go wrong when starting (or, rarely, running) a server.
+use std::io;
+use std::os::fd::RawFd;
+
+const FD_0_IS_TOO_NORMAL: &str = r#"Fatal error: wasn't executed by a compatible FastCGI client!
+This server mode expects to be passed an open Unix socket on file descriptor 0,
+rather than the normal stdin stream. The main modern client that supports
+this is Apache's mod_fcgid."#;
+
+/// Why a server stopped serving (or never got started). Most of these mean we
+/// couldn't set up the listening socket, and never made it to the accept() loop.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum ServeError {
+    /// The descriptor we were told to adopt wasn't a socket. For fd 0, that almost
+    /// always means someone ran the app by hand instead of via mod_fcgid.
+    NotASocket(RawFd),
+    /// The descriptor was a socket, but not a listening Unix stream socket (a TCP
+    /// socket, say), which is the only kind we know how to adopt. Says what was off.
+    WrongKindOfSocket(RawFd, &'static str),
+    /// Something was off about the systemd socket activation environment variables.
+    NotSocketActivated(String),
+    /// The inherited descriptor looked like a socket, but we couldn't set it up
+    /// as a listener.
+    Adopt(io::Error),
+    /// We couldn't set up a listening socket of our own: binding a TCP address or a
+    /// Unix socket, or clearing away a stale socket file, or fixing up its
+    /// permissions or ownership afterwards. Says which address or path it was.
+    Bind(String, io::Error),
+    /// The listener broke while we were serving (for example, the inherited socket
+    /// was never put in listening mode), so we gave up on it. Errors that only
+    /// affect a single incoming connection just get logged.
+    Accept(io::Error),
+}
+
+impl std::fmt::Display for ServeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NotASocket(0) => f.write_str(FD_0_IS_TOO_NORMAL),
+            Self::NotASocket(fd) => write!(
+                f,
+                "Fatal error: expected an open Unix socket on file descriptor {}, but it wasn't one.",
+                fd
+            ),
+            Self::WrongKindOfSocket(fd, problem) => write!(
+                f,
+                "Fatal error: file descriptor {} is a socket, but {}; expected a listening Unix stream socket.",
+                fd, problem
+            ),
+            Self::NotSocketActivated(why) => write!(
+                f,
+                "Fatal error: not launched via systemd socket activation: {}",
+                why
+            ),
+            Self::Adopt(e) => write!(f, "Fatal error: couldn't listen on inherited socket: {}", e),
+            Self::Bind(addr, e) => {
+                write!(f, "Fatal error: couldn't set up listener at {}: {}", addr, e)
+            }
+            Self::Accept(e) => write!(f, "Fatal error: couldn't accept connections: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ServeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Adopt(e) | Self::Bind(_, e) | Self::Accept(e) => Some(e),
+            Self::NotASocket(_) | Self::WrongKindOfSocket(..) | Self::NotSocketActivated(_) => None,
+        }
+    }
+}
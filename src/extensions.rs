@@ -0,0 +1,323 @@
+//! Types we tuck into request extensions, for the CGI details that don't have a
+//! natural home in a plain `http::Request`.
+use crate::FcgiVars;
+
+/// The part of the original request path that the web server used to find the
+/// app (the CGI `SCRIPT_NAME`), when the request path was built from `PATH_INFO`
+/// instead; see [`Settings::path_from_path_info`](crate::Settings::path_from_path_info).
+/// In other words, where the app is mounted. Handy for building links back to
+/// yourself.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScriptName(pub String);
+
+impl ScriptName {
+    /// The mount point as a plain string, like `/app` (empty at the root).
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+/// How a request reached the app, for code that needs to know at request time
+/// instead of just in `main` (say, a shared middleware that should only trust
+/// `X-Forwarded-For` when there's no web server vouching for the client address).
+/// Every request we serve gets one. Plain HTTP serving, like `axum::serve`, doesn't
+/// add one, so if it's missing, you're not behind us.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct TransportInfo {
+    /// The protocol the web server used to hand us the request.
+    pub gateway: Gateway,
+    /// Whether the *original* request came in over `https` or plain `http`. Same
+    /// as the `Scheme` extension; it's here too so you can grab everything at once.
+    pub scheme: http::uri::Scheme,
+}
+
+impl TransportInfo {
+    /// Whether the request came in over FastCGI.
+    pub fn is_fcgi(&self) -> bool {
+        self.gateway == Gateway::FastCgi
+    }
+}
+
+/// The protocols we can be served over; see [`TransportInfo`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Gateway {
+    /// FastCGI, from any of the `serve_fcgi*` functions or [`FcgiServer`](crate::FcgiServer).
+    FastCgi,
+    /// SCGI, from [`FcgiServer::scgi_tcp`](crate::FcgiServer::scgi_tcp) or [`serve_scgi`](crate::serve_scgi).
+    Scgi,
+    /// Plain CGI, one process per request, from [`serve_cgi_once`](crate::serve_cgi_once).
+    Cgi,
+}
+
+/// The client certificate a request came with, when the web server handled
+/// mutual TLS and vouched for it. Only present if the client presented a
+/// certificate at all, so an app doing client-cert auth should treat a missing
+/// one the same as a failed one, and check [`is_verified`](Self::is_verified)
+/// before trusting what's in it.
+///
+/// This is all from mod_ssl's `SSL_CLIENT_*` CGI vars, which Apache only sends
+/// with `SSLOptions +StdEnvVars`; the [`pem`](Self::pem) also needs
+/// `+ExportCertData`. So for a typical setup:
+///
+/// ```text
+/// SSLVerifyClient optional
+/// SSLOptions +StdEnvVars +ExportCertData
+/// ```
+///
+/// Clients can't fake these with request headers (those all become `HTTP_*`
+/// vars), but anything that can talk FastCGI to us directly can, so don't lean on
+/// this if the socket's reachable by anyone but the web server.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct ClientCert {
+    /// What the web server made of the certificate, from `SSL_CLIENT_VERIFY`:
+    /// `SUCCESS`, `NONE`, `GENEROUS`, or `FAILED:` and a reason.
+    pub verify: Option<String>,
+    /// Who the certificate's for, from `SSL_CLIENT_S_DN`.
+    pub subject: DistinguishedName,
+    /// Who vouched for them, from `SSL_CLIENT_I_DN`.
+    pub issuer: DistinguishedName,
+    /// The serial number in hex, from `SSL_CLIENT_M_SERIAL`.
+    pub serial: Option<String>,
+    /// When the certificate became valid, from `SSL_CLIENT_V_START`, in mod_ssl's
+    /// format (like `Oct 14 00:00:00 2026 GMT`).
+    pub not_before: Option<String>,
+    /// When the certificate stops being valid, from `SSL_CLIENT_V_END`.
+    pub not_after: Option<String>,
+    /// The whole certificate in PEM form, from `SSL_CLIENT_CERT`, for checking
+    /// anything we don't pick out (or pinning the exact certificate).
+    pub pem: Option<String>,
+}
+
+impl ClientCert {
+    /// Whether the web server verified the certificate against its CAs.
+    pub fn is_verified(&self) -> bool {
+        self.verify.as_deref() == Some("SUCCESS")
+    }
+
+    /// Collect the certificate vars, if the client sent a certificate.
+    pub(crate) fn from_vars(vars: &FcgiVars) -> Option<Self> {
+        let text = |name| {
+            vars.get(name)
+                .map(|v| String::from_utf8_lossy(v).into_owned())
+        };
+        let subject = text("SSL_CLIENT_S_DN")?;
+        Some(Self {
+            verify: text("SSL_CLIENT_VERIFY"),
+            subject: DistinguishedName::parse(subject),
+            issuer: DistinguishedName::parse(text("SSL_CLIENT_I_DN").unwrap_or_default()),
+            serial: text("SSL_CLIENT_M_SERIAL"),
+            not_before: text("SSL_CLIENT_V_START"),
+            not_after: text("SSL_CLIENT_V_END"),
+            pem: text("SSL_CLIENT_CERT").filter(|pem| !pem.is_empty()),
+        })
+    }
+}
+
+/// A certificate's subject or issuer, with the usual fields picked out. If a
+/// field shows up more than once (multiple `OU`s, say), we keep the first; the
+/// [`raw`](Self::raw) string has the rest.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct DistinguishedName {
+    /// The whole thing, as the web server sent it.
+    pub raw: String,
+    /// `CN`
+    pub common_name: Option<String>,
+    /// `O`
+    pub organization: Option<String>,
+    /// `OU`
+    pub organizational_unit: Option<String>,
+    /// `C`
+    pub country: Option<String>,
+    /// `emailAddress`
+    pub email: Option<String>,
+}
+
+impl DistinguishedName {
+    /// mod_ssl writes DNs the RFC 2253 way (`CN=me,O=Example\, Inc.`), or the old
+    /// OpenSSL way (`/O=Example, Inc./CN=me`) with `SSLOptions +LegacyDNStringFormat`.
+    /// We take either.
+    fn parse(raw: String) -> Self {
+        let mut dn = Self::default();
+        let parts = match raw.strip_prefix('/') {
+            Some(legacy) => legacy.split('/').map(str::to_string).collect(),
+            None => split_rfc2253(&raw),
+        };
+        for part in parts {
+            let Some((key, value)) = part.split_once('=') else {
+                continue;
+            };
+            let field = match key.trim() {
+                k if k.eq_ignore_ascii_case("CN") => &mut dn.common_name,
+                k if k.eq_ignore_ascii_case("O") => &mut dn.organization,
+                k if k.eq_ignore_ascii_case("OU") => &mut dn.organizational_unit,
+                k if k.eq_ignore_ascii_case("C") => &mut dn.country,
+                k if k.eq_ignore_ascii_case("emailAddress") => &mut dn.email,
+                _ => continue,
+            };
+            field.get_or_insert_with(|| value.trim().to_string());
+        }
+        dn.raw = raw;
+        dn
+    }
+}
+
+/// Split an RFC 2253 DN into `key=value` parts, at its unescaped commas (and the
+/// `+`s that join several parts into one component, like `CN=me+UID=123`), and
+/// undo the value encodings as we go.
+fn split_rfc2253(raw: &str) -> Vec<String> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    let mut escaped = false;
+    for (i, b) in raw.bytes().enumerate() {
+        match b {
+            _ if escaped => escaped = false,
+            b'\\' => escaped = true,
+            b',' | b'+' => {
+                parts.push(decode_rfc2253_part(&raw[start..i]));
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    parts.push(decode_rfc2253_part(&raw[start..]));
+    parts
+}
+
+/// One `key=value` part, with its value decoded: backslash escapes (`\,`, or hex
+/// pairs like `\C3\A9` for an `é`), or a whole value in hex (`#0C026D65`), which is
+/// the DER encoding of the string. We can only read the plain string types out of
+/// that; anything fancier stays hex.
+fn decode_rfc2253_part(part: &str) -> String {
+    let Some((key, value)) = part.split_once('=') else {
+        return part.to_string();
+    };
+    let value = match value.trim_start().strip_prefix('#') {
+        Some(hex) => decode_der_string(hex.trim_end()).unwrap_or_else(|| value.to_string()),
+        None => unescape_rfc2253(value),
+    };
+    format!("{}={}", key, value)
+}
+
+/// Undo backslash escapes. Bytes, not chars, since a run of hex escapes can spell
+/// out one UTF-8 character between them.
+fn unescape_rfc2253(value: &str) -> String {
+    let mut out = Vec::with_capacity(value.len());
+    let mut bytes = value.bytes().peekable();
+    while let Some(b) = bytes.next() {
+        if b != b'\\' {
+            out.push(b);
+            continue;
+        }
+        match bytes.next() {
+            Some(hi)
+                if hi.is_ascii_hexdigit() && bytes.peek().is_some_and(u8::is_ascii_hexdigit) =>
+            {
+                let lo = bytes.next().unwrap_or_default();
+                out.push(hex_value(hi) << 4 | hex_value(lo));
+            }
+            Some(other) => out.push(other),
+            None => {}
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// The text in a hex-encoded DER string value, if it's one of the string types.
+fn decode_der_string(hex: &str) -> Option<String> {
+    if !hex.len().is_multiple_of(2) || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    let der: Vec<u8> = hex
+        .as_bytes()
+        .chunks(2)
+        .map(|pair| hex_value(pair[0]) << 4 | hex_value(pair[1]))
+        .collect();
+    // UTF8String, PrintableString, T61String, or IA5String, short enough that its
+    // length fits in one byte.
+    let (&[tag, len], contents) = der.split_first_chunk::<2>()?;
+    let plain_string = matches!(tag, 0x0C | 0x13 | 0x14 | 0x16);
+    (plain_string && usize::from(len) == contents.len())
+        .then(|| String::from_utf8_lossy(contents).into_owned())
+}
+
+fn hex_value(digit: u8) -> u8 {
+    match digit {
+        b'0'..=b'9' => digit - b'0',
+        b'a'..=b'f' => digit - b'a' + 10,
+        b'A'..=b'F' => digit - b'A' + 10,
+        _ => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dn(raw: &str) -> DistinguishedName {
+        DistinguishedName::parse(raw.to_string())
+    }
+
+    #[test]
+    fn rfc2253_names_get_picked_apart() {
+        let name = dn("emailAddress=me@example.com,CN=Me,OU=Web,OU=Ops,O=Example,C=US");
+        assert_eq!(name.common_name.as_deref(), Some("Me"));
+        assert_eq!(name.organization.as_deref(), Some("Example"));
+        // The first of several wins.
+        assert_eq!(name.organizational_unit.as_deref(), Some("Web"));
+        assert_eq!(name.country.as_deref(), Some("US"));
+        assert_eq!(name.email.as_deref(), Some("me@example.com"));
+    }
+
+    #[test]
+    fn escaped_commas_stay_in_the_value() {
+        let name = dn(r"CN=Me,O=Example\, Inc.,C=US");
+        assert_eq!(name.organization.as_deref(), Some("Example, Inc."));
+        assert_eq!(name.country.as_deref(), Some("US"));
+        assert_eq!(name.raw, r"CN=Me,O=Example\, Inc.,C=US");
+        let name = dn(r"CN=a\+b\=c\\d,O=x");
+        assert_eq!(name.common_name.as_deref(), Some(r"a+b=c\d"));
+    }
+
+    #[test]
+    fn plus_joined_parts_are_separate_fields() {
+        let name = dn("CN=Me+emailAddress=me@example.com,O=Example");
+        assert_eq!(name.common_name.as_deref(), Some("Me"));
+        assert_eq!(name.email.as_deref(), Some("me@example.com"));
+        assert_eq!(name.organization.as_deref(), Some("Example"));
+        let name = dn("UID=123+CN=Me,O=Example");
+        assert_eq!(name.common_name.as_deref(), Some("Me"));
+    }
+
+    #[test]
+    fn hex_escapes_are_decoded() {
+        let name = dn(r"CN=Ren\C3\A9e,O=Example\2C Inc.");
+        assert_eq!(name.common_name.as_deref(), Some("Renée"));
+        assert_eq!(name.organization.as_deref(), Some("Example, Inc."));
+    }
+
+    #[test]
+    fn hex_encoded_values_are_decoded() {
+        // A UTF8String and a PrintableString.
+        let name = dn("CN=#0C024D65,C=#13025553");
+        assert_eq!(name.common_name.as_deref(), Some("Me"));
+        assert_eq!(name.country.as_deref(), Some("US"));
+        // Something that isn't a plain string, or is broken, stays as it was.
+        assert_eq!(dn("CN=#020101").common_name.as_deref(), Some("#020101"));
+        assert_eq!(dn("CN=#0C05").common_name.as_deref(), Some("#0C05"));
+        assert_eq!(dn("CN=#zz").common_name.as_deref(), Some("#zz"));
+        // And an escaped # is just a #.
+        assert_eq!(dn(r"CN=\#1").common_name.as_deref(), Some("#1"));
+    }
+
+    #[test]
+    fn legacy_openssl_names_work_too() {
+        let name = dn("/C=US/O=Example, Inc./CN=Me");
+        assert_eq!(name.organization.as_deref(), Some("Example, Inc."));
+        assert_eq!(name.common_name.as_deref(), Some("Me"));
+        assert_eq!(name.country.as_deref(), Some("US"));
+    }
+}
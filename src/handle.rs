@@ -0,0 +1,209 @@
+//! A window into a running server, for health checks and the like.
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Notify;
+use tokio::time::Instant;
+
+/// Reports how busy a server is. Get one from [`FcgiServer::handle`](crate::FcgiServer::handle)
+/// before you start serving; it's cheap to clone, so pass copies to whatever
+/// needs to check in (a health check endpoint, a shutdown coordinator...).
+///
+/// The counts are live, so they might have changed by the time you look at them.
+#[derive(Debug, Clone, Default)]
+pub struct ServerHandle {
+    counters: Arc<Counters>,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    connections: AtomicUsize,
+    requests: AtomicUsize,
+    /// Every request that's finished, for the server's `max_requests`.
+    requests_served: AtomicU64,
+    /// Pinged whenever a request finishes.
+    served: Notify,
+    /// Every connection we've ever accepted, so we can notice short-lived ones
+    /// that came and went between glances at the live count.
+    connections_seen: AtomicUsize,
+    /// Backwards, so the default is ready.
+    warming_up: AtomicBool,
+    /// The server's `max_connections`, which is as high as the limit can go,
+    /// since fastcgi-server sizes its pool of connection tokens once, up front.
+    /// 0 means no ceiling.
+    connection_ceiling: AtomicUsize,
+    /// The limit as set through the handle; 0 means it's the ceiling.
+    connection_limit: AtomicUsize,
+    /// Pinged whenever there might be room for another connection.
+    room: Notify,
+}
+
+impl ServerHandle {
+    /// How many FastCGI connections are currently open. Each one holds one of
+    /// the server's `max_connections` slots.
+    pub fn active_connections(&self) -> usize {
+        self.counters.connections.load(Ordering::Relaxed)
+    }
+
+    /// The most connections we'll serve at once, as of right now.
+    pub fn max_connections(&self) -> usize {
+        let unset_is_unlimited = |n| if n == 0 { usize::MAX } else { n };
+        let ceiling = unset_is_unlimited(self.counters.connection_ceiling.load(Ordering::Relaxed));
+        let limit = unset_is_unlimited(self.counters.connection_limit.load(Ordering::Relaxed));
+        limit.min(ceiling)
+    }
+
+    /// Change how many connections we'll serve at once, while the server's running
+    /// (or before it starts). Lowering it doesn't cut anybody off; we just stop
+    /// accepting new connections until enough of the current ones finish. It can't
+    /// go past the server's [`max_connections`](crate::FcgiServer::max_connections),
+    /// because fastcgi-server can't grow its pool after startup, so set that to the
+    /// most you'll ever want and turn it down from there.
+    pub fn set_max_connections(&self, limit: NonZeroUsize) {
+        self.counters
+            .connection_limit
+            .store(limit.get(), Ordering::Relaxed);
+        self.counters.room.notify_waiters();
+    }
+
+    pub(crate) fn set_connection_ceiling(&self, ceiling: NonZeroUsize) {
+        self.counters
+            .connection_ceiling
+            .store(ceiling.get(), Ordering::Relaxed);
+        self.counters.room.notify_waiters();
+    }
+
+    /// How many requests are currently being served.
+    pub fn active_requests(&self) -> usize {
+        self.counters.requests.load(Ordering::Relaxed)
+    }
+
+    /// How many requests we've finished serving since startup, counting errors
+    /// and all.
+    pub fn requests_served(&self) -> u64 {
+        self.counters.requests_served.load(Ordering::Relaxed)
+    }
+
+    /// Whether the app's ready for requests. Always true, unless the server was
+    /// told to wait for something with [`FcgiServer::ready_when`](crate::FcgiServer::ready_when)
+    /// and it hasn't happened yet.
+    pub fn is_ready(&self) -> bool {
+        !self.counters.warming_up.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn set_ready(&self, ready: bool) {
+        self.counters.warming_up.store(!ready, Ordering::Relaxed);
+    }
+
+    /// Claim a connection slot under the current limit, waiting for one to open
+    /// up if we're full. The connection counts as active until the returned guard
+    /// is dropped. Claiming and counting are the same step, so accept loops on
+    /// several listeners can't both squeeze into the last slot.
+    pub(crate) async fn connection_slot(&self) -> ActiveGuard {
+        loop {
+            // Start listening before checking, so a slot that frees up in between
+            // can't slip past us.
+            let room = self.counters.room.notified();
+            tokio::pin!(room);
+            room.as_mut().enable();
+            let limit = self.max_connections();
+            let claimed =
+                self.counters
+                    .connections
+                    .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| {
+                        (n < limit).then_some(n + 1)
+                    });
+            if claimed.is_ok() {
+                self.counters
+                    .connections_seen
+                    .fetch_add(1, Ordering::Relaxed);
+                return ActiveGuard {
+                    counters: self.counters.clone(),
+                    which: |c| &c.connections,
+                    then: |_| {},
+                };
+            }
+            room.await;
+        }
+    }
+
+    /// Count a request as active until the returned guard is dropped, and as
+    /// served after that.
+    pub(crate) fn track_request(&self) -> ActiveGuard {
+        ActiveGuard::new(
+            self.counters.clone(),
+            |c| &c.requests,
+            |c| {
+                c.requests_served.fetch_add(1, Ordering::Relaxed);
+                c.served.notify_waiters();
+            },
+        )
+    }
+
+    /// Finishes once we've served at least `count` requests.
+    pub(crate) async fn served(&self, count: u64) {
+        loop {
+            // Same dance as connection_slot, so we can't miss the last one.
+            let served = self.counters.served.notified();
+            tokio::pin!(served);
+            served.as_mut().enable();
+            if self.requests_served() >= count {
+                return;
+            }
+            served.await;
+        }
+    }
+
+    /// Finishes once there have been no connections for `timeout`. We check in
+    /// a few times per timeout period instead of getting woken up on every
+    /// change, so it can overshoot by a bit; nobody's counting that closely.
+    pub(crate) async fn idle_for(&self, timeout: Duration) {
+        let check_every = (timeout / 4).max(Duration::from_millis(100));
+        let mut seen = self.counters.connections_seen.load(Ordering::Relaxed);
+        let mut idle_since = Instant::now();
+        loop {
+            tokio::time::sleep(check_every).await;
+            let now_seen = self.counters.connections_seen.load(Ordering::Relaxed);
+            if self.active_connections() > 0 || now_seen != seen {
+                seen = now_seen;
+                idle_since = Instant::now();
+            } else if idle_since.elapsed() >= timeout {
+                return;
+            }
+        }
+    }
+}
+
+/// Decrements its counter on drop, so the counts stay right even when a task
+/// gets aborted or a handler bails out early.
+pub(crate) struct ActiveGuard {
+    counters: Arc<Counters>,
+    which: fn(&Counters) -> &AtomicUsize,
+    /// Anything else to do once it's over.
+    then: fn(&Counters),
+}
+
+impl ActiveGuard {
+    fn new(
+        counters: Arc<Counters>,
+        which: fn(&Counters) -> &AtomicUsize,
+        then: fn(&Counters),
+    ) -> Self {
+        which(&counters).fetch_add(1, Ordering::Relaxed);
+        Self {
+            counters,
+            which,
+            then,
+        }
+    }
+}
+
+impl Drop for ActiveGuard {
+    fn drop(&mut self) {
+        (self.which)(&self.counters).fetch_sub(1, Ordering::Relaxed);
+        (self.then)(&self.counters);
+        // Only matters for connections, but it's cheap enough to do for requests too.
+        self.counters.room.notify_waiters();
+    }
+}
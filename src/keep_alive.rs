@@ -0,0 +1,108 @@
+//! Closing FastCGI connections sooner than the web server asked us to, for
+//! [`Settings::keep_alive`](crate::Settings::keep_alive).
+//!
+//! fastcgi-server decides whether to read another request off a connection, and
+//! all it goes on is the web server's "keep conn" flag. The one thing that'll make
+//! it stop early is running out of input, so that's what we give it: once a
+//! connection's served its last request, its read half starts reporting EOF, and
+//! fastcgi-server hangs up like the web server had left.
+use crate::KeepAlive;
+use futures_util::task::AtomicWaker;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, ReadBuf};
+use tracing::debug;
+
+/// How many requests a connection has left, shared between its reader and its
+/// requests.
+#[derive(Debug)]
+pub(crate) struct RequestBudget {
+    limit: usize,
+    /// Requests finished, and requests in progress. One lock for both, so the last
+    /// request out can't miss one that's just coming in.
+    counts: Mutex<(usize, usize)>,
+    spent: AtomicBool,
+    /// Whoever's waiting on the reader, so we can tell them it's over.
+    reader: AtomicWaker,
+}
+
+impl RequestBudget {
+    /// A budget for one connection, or `None` if the policy's to leave it to the
+    /// web server.
+    pub(crate) fn new(policy: KeepAlive) -> Option<Arc<Self>> {
+        let limit = match policy {
+            KeepAlive::Auto => return None,
+            KeepAlive::Never => 1,
+            KeepAlive::UpTo(limit) => limit.get(),
+        };
+        Some(Arc::new(Self {
+            limit,
+            counts: Mutex::new((0, 0)),
+            spent: AtomicBool::new(false),
+            reader: AtomicWaker::new(),
+        }))
+    }
+
+    /// Count a request as in progress until the guard's dropped.
+    pub(crate) fn spend(self: &Arc<Self>) -> Spending {
+        self.counts.lock().unwrap_or_else(PoisonError::into_inner).1 += 1;
+        Spending(self.clone())
+    }
+}
+
+/// A request in progress on a [`RequestBudget`]'s connection.
+pub(crate) struct Spending(Arc<RequestBudget>);
+
+impl Drop for Spending {
+    fn drop(&mut self) {
+        let budget = &self.0;
+        let mut counts = budget.counts.lock().unwrap_or_else(PoisonError::into_inner);
+        let (finished, in_progress) = &mut *counts;
+        *finished += 1;
+        *in_progress -= 1;
+        if *finished >= budget.limit && *in_progress == 0 {
+            debug!(
+                target: "busride_rs",
+                requests = *finished,
+                "connection has served all the requests keep_alive allows; closing it"
+            );
+            budget.spent.store(true, Ordering::Release);
+            budget.reader.wake();
+        }
+    }
+}
+
+/// A connection's read half, which runs dry once its [`RequestBudget`] is spent.
+/// Without a budget, it's just the read half.
+pub(crate) struct BudgetedReader<R> {
+    inner: R,
+    budget: Option<Arc<RequestBudget>>,
+}
+
+impl<R> BudgetedReader<R> {
+    pub(crate) fn new(inner: R, budget: Option<Arc<RequestBudget>>) -> Self {
+        Self { inner, budget }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for BudgetedReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if let Some(budget) = &self.budget {
+            // Register first, so a budget that runs out right after we look still
+            // wakes us up.
+            budget.reader.register(cx.waker());
+            if budget.spent.load(Ordering::Acquire) {
+                // Reading nothing is how AsyncRead says EOF.
+                return Poll::Ready(Ok(()));
+            }
+        }
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
@@ -9,53 +9,303 @@
 //! get the benefits of older hosting models without having to contort your
 //! main app code around their oddities and limitations.
 //!
-//! This crate is in an experimental state, and currently only suppors the
+//! This crate is in an experimental state, and is mostly aimed at the
 //! [Axum](https://github.com/tokio-rs/axum) web framework... because that's
-//! what I'm interested in using it with, and I couldn't justify the extra
-//! work of generalizing it before even learning whether others are interested.
-//! (Should be feasible, though.)
-use bytes::BytesMut;
+//! what I'm interested in using it with. Under the hood it'll serve any
+//! tower `Service`, but request bodies are always `axum::body::Body`s.
+//!
+//! There's also SCGI (FastCGI's simpler sibling), for web servers where that's
+//! easier to set up, as an [`FcgiServer::scgi_tcp`] listener or with
+//! [`serve_scgi`]; and [`serve_cgi_once`], for plain CGI, where there's no
+//! persistent process at all. Apps see the same requests either way.
+//!
+//! ## What your app sees
+//!
+//! Requests arrive as normal `http::Request`s. Their URIs are absolute (scheme,
+//! host, and path), reassembled from the CGI vars. The path is the full original
+//! `REQUEST_URI`, unless [`Settings::path_from_path_info`] says to de-nest it
+//! from the app's mount point. The authority comes from the
+//! client's `Host` header if it sent one, otherwise from `SERVER_NAME`. Its port
+//! is the `Host` header's if that has one, even if `SERVER_PORT` disagrees, and
+//! otherwise `SERVER_PORT` (left off if it's the default for the scheme). A `Host`
+//! header too mangled to use counts as no `Host` header. Without a `Host` header,
+//! we also fill one in, since the app will expect one; with one, we leave it
+//! alone, even when the URI ended up with a port it doesn't have. So the header
+//! is what the client said, and the URI is our best reconstruction of where it
+//! was going; build absolute URLs from `req.uri()`, not the `Host` header.
+//!
+//! The request's HTTP version is whatever the client spoke to the web server,
+//! going by `SERVER_PROTOCOL` (1.1 if that's missing or unfamiliar).
+//!
+//! Repeated request headers generally arrive joined into a single comma-separated
+//! header, which means the same thing for list-valued headers. The exception is
+//! `Cookie`, which we re-join with `; ` so cookie parsers see every pair.
+//!
+//! Header names get turned back from CGI form (`HTTP_IF_NONE_MATCH`) into the
+//! usual lowercase, hyphenated form (`if-none-match`), and values pass through
+//! byte-for-byte, quotes and all. So conditional requests work the same as over
+//! plain HTTP: `If-Modified-Since` dates, `If-None-Match` with quoted or weak
+//! (`W/"..."`) ETags, and `If-Range` all reach the app intact, for it (or
+//! tower-http's `ServeDir`) to answer with a `304` or `412` as usual. The one
+//! thing that can't round-trip is a header with an underscore in its name, since
+//! `X_Thing` and `X-Thing` both become `HTTP_X_THING`; Apache drops those before
+//! we ever see them, for exactly that reason.
+//!
+//! An `Expect: 100-continue` header passes through, but it's already been dealt
+//! with by the time the app sees it: the web server is the one talking HTTP to
+//! the client, so it sends the `100 Continue` itself once it starts passing the
+//! body along. That goes for every way of listening, including the ones where we
+//! bind our own socket ([`FcgiServer::tcp`], [`FcgiServer::bind_unix`],
+//! [`FcgiServer::scgi_tcp`]). Those might look standalone, but they're not: only
+//! a FastCGI or SCGI client can connect to them, never a browser, so there's always
+//! a web server in between to have handled it. So we don't answer it in any mode,
+//! and couldn't if we wanted to. CGI responses don't have room for interim ones,
+//! and a web server would take a `Status: 100` as the final answer, body and all.
+//!
+//! There are also a few extras tucked into the request extensions to make up for
+//! what FastCGI takes away:
+//!
+//! - `axum::extract::ConnectInfo<SocketAddr>`: the client's address, from the
+//!   `REMOTE_ADDR` and `REMOTE_PORT` vars. Absent if either is missing.
+//! - `http::uri::Scheme`: whether the *original* request came in over `https`
+//!   or plain `http`, from the `HTTPS` and `REQUEST_SCHEME` vars. If the web
+//!   server didn't set either of them, it's `http`.
+//! - [`ScriptName`]: where the app is mounted, if you've opted into building
+//!   request paths from `PATH_INFO`.
+//! - [`TransportInfo`]: that the request came through us at all (and over which
+//!   protocol), for shared code that has to tell serving modes apart.
+//! - [`ClientCert`]: the client's TLS certificate, if the web server did mutual
+//!   TLS and passed along mod_ssl's `SSL_CLIENT_*` vars.
+//! - [`FcgiVars`]: all the raw CGI vars, for anything we didn't cover above.
+//!
+//! One thing you won't find in there is the FastCGI protocol's own request ID.
+//! fastcgi-server's `Request` doesn't expose it, and it wouldn't be much use if it
+//! did: it only tells apart the requests multiplexed over a single connection, so
+//! mod_fcgid (which never multiplexes) sends 1 for every request, and it can't be
+//! matched up with anything in the web server's logs. For that, use the ID in
+//! [`Settings::request_id_header`], which we log with each request.
+//!
+//! The URI and extension parts of that are also available on their own as
+//! [`FcgiEnrichLayer`], if you want to exercise them in tests over plain HTTP.
+//!
+//! To mount an app somewhere other than the root of the domain without the app
+//! knowing about it, either turn on [`Settings::path_from_path_info`] (if your web
+//! server's `PATH_INFO` cooperates) or wrap the app in a [`MountLayer`], which
+//! works the same in FastCGI and plain HTTP modes. And to serve several sites
+//! from one process (one per vhost that points at it), put their apps in a
+//! [`VhostRouter`], which picks one by `SERVER_NAME` or `Host`.
+//!
+//! ## Logging
+//!
+//! We log with `tracing`, and every event and span we emit uses the `busride_rs`
+//! target, no matter which of our modules it comes from. So a filter directive
+//! like `busride_rs=warn` quiets us down (or `busride_rs=trace` opens the
+//! floodgates) without touching your app's own logs, and it won't break if we
+//! shuffle our code around. Most events carry a `blame` field, with our best
+//! guess at whose problem it is.
+//!
+//! Under [`serve_cgi_once`], stdout is the response, so point your subscriber
+//! at stderr (`.with_writer(std::io::stderr)`); see its docs.
+//!
+//! ## What the client sees
+//!
+//! Responses go out the way the app made them: the body byte-for-byte, and the
+//! headers as-is, including `Content-Encoding` and `Content-Length`. So if the
+//! app compresses its responses (with tower-http's `CompressionLayer`, say), the
+//! web server gets the compressed body with headers that match it, and should
+//! pass both along without compressing them again. (If you've also got the web
+//! server's own compression turned on, like Apache's `mod_deflate`, check that
+//! it skips responses that already have a `Content-Encoding`.) The only things
+//! we add are the request ID header, if [`Settings::request_id_header`] has one
+//! to echo, a `Date` header if you've turned on [`Settings::date_header`], a
+//! `Content-Length` when the body knows its length but the app didn't say (so
+//! the web server can keep the client's connection alive), and a last-resort
+//! `Content-Type` on `200`s that don't have one (CGI needs *something* there).
+//!
+//! That also means you can hand big static files off to the web server, which is
+//! better at serving them than we are. Respond with an empty body and an
+//! `X-Sendfile` header (for Apache's `mod_xsendfile`) or an `X-Accel-Redirect`
+//! header (for nginx) naming the file, and the web server takes it from there:
+//!
+//! ```ignore
+//! async fn download() -> impl IntoResponse {
+//!     let headers = [
+//!         ("x-sendfile", "/home/me/files/big.zip"),
+//!         ("content-type", "application/zip"),
+//!     ];
+//!     (headers, ())
+//! }
+//! ```
+//!
+//! The web server has to be configured to honor those headers (and to allow that
+//! path), or else the client just gets the empty body.
+//! For files under the web server's `DOCUMENT_ROOT`, the `static_files` module
+//! (behind the `static-files` feature) can do the looking-up and the headers for
+//! you, or stream the files itself.
+//!
+//! Connection upgrades (WebSockets, mostly) can't work over FastCGI, since the
+//! connection belongs to the web server. If the app answers with a
+//! `101 Switching Protocols` anyway, the client gets a `501 Not Implemented`.
+use axum::BoxError;
+use bytes::{Bytes, BytesMut};
 use fastcgi_server::async_io::Runner;
 use fastcgi_server::{cgi, Config, ExitStatus};
-use futures_util::AsyncWrite;
-use futures_util::{io::BufWriter, AsyncWriteExt, FutureExt, StreamExt};
+use futures_util::stream::FuturesUnordered;
+use futures_util::{io::BufWriter, AsyncRead, AsyncWrite, AsyncWriteExt, FutureExt, StreamExt};
+use http_body::Body as HttpBody;
+use response_buffer::{ConnectionBuffers, ResponseBuffer, SpareBuffer};
 use std::future::Future;
 use std::io;
+use std::net::SocketAddr;
 use std::num::NonZeroUsize;
 use std::os::fd::*;
-use std::os::unix::fs::FileTypeExt;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
 use std::os::unix::net::UnixListener as StdUnixListener;
-use tokio::net::UnixListener;
-use tokio::sync::mpsc;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::io::{AsyncRead as TokioAsyncRead, AsyncWrite as TokioAsyncWrite};
+use tokio::net::{TcpListener, UnixListener};
+use tokio::sync::{mpsc, Semaphore};
+use tokio::task::JoinSet;
 use tokio_util::codec::{BytesCodec, FramedRead};
 use tokio_util::compat::{
     FuturesAsyncReadCompatExt, TokioAsyncReadCompatExt, TokioAsyncWriteCompatExt,
 };
+use tokio_util::either::Either;
 use tower::Service;
-use tracing::{debug, error, info, trace, Instrument};
+// Events here get the `busride_rs` target for free, since that's this module's
+// path; events in the other modules have to say `target: "busride_rs"` to match.
+use tracing::{debug, error, info, trace, warn, Instrument};
 
-// Shorthand types for working with fastcgi_server::async_io
-type FcgiReader<'a> = tokio_util::compat::Compat<tokio::net::unix::ReadHalf<'a>>;
-type FcgiWriter<'a> = tokio_util::compat::Compat<tokio::net::unix::WriteHalf<'a>>;
-type FcgiRequest<'a, 'b, 'c> =
-    fastcgi_server::async_io::Request<'a, FcgiReader<'b>, FcgiWriter<'c>>;
+mod enrich;
+mod error;
+mod extensions;
+mod handle;
+mod keep_alive;
+mod mount;
+mod observer;
+mod plain_cgi;
+mod request_id;
+mod response_buffer;
+mod runtime;
+mod scgi;
+mod server;
+mod settings;
+#[cfg(feature = "static-files")]
+pub mod static_files;
+#[cfg(feature = "testing")]
+pub mod testing;
+mod vars;
+mod vhost;
 
-const FD_0_IS_TOO_NORMAL: &str = r#"Fatal error: wasn't executed by a compatible FastCGI client!
-This server mode expects to be passed an open Unix socket on file descriptor 0,
-rather than the normal stdin stream. The main modern client that supports
-this is Apache's mod_fcgid."#;
+pub use enrich::{FcgiEnrich, FcgiEnrichLayer};
+pub use error::ServeError;
+pub use extensions::{ClientCert, DistinguishedName, Gateway, ScriptName, TransportInfo};
+pub use handle::ServerHandle;
+pub use mount::{Mount, MountLayer};
+pub use observer::{RequestFinished, RequestObserver, RequestStarted, RequestStats};
+pub use plain_cgi::serve_cgi_once;
+pub use runtime::{tame_current_thread_runtime, tame_runtime};
+pub use scgi::serve_scgi;
+pub use server::{FcgiServer, Shutdown};
+pub use settings::{KeepAlive, Role, Settings};
+pub use vars::FcgiVars;
+pub use vhost::VhostRouter;
 
-#[derive(Debug)]
-struct Fd0IsTooNormal;
-impl std::fmt::Display for Fd0IsTooNormal {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.write_str(FD_0_IS_TOO_NORMAL)
+use enrich::{enrich_request, path_and_query_from_vars};
+use keep_alive::{BudgetedReader, RequestBudget};
+use request_id::RequestId;
+
+// Shorthand types for working with fastcgi_server::async_io. These are generic
+// over the connection's stream type, so the same handler can serve Unix and TCP.
+type FcgiReader<C> = tokio_util::compat::Compat<BudgetedReader<tokio::io::ReadHalf<C>>>;
+type FcgiWriter<C> = tokio_util::compat::Compat<tokio::io::WriteHalf<C>>;
+type FcgiRequest<'a, C> = fastcgi_server::async_io::Request<'a, FcgiReader<C>, FcgiWriter<C>>;
+
+// Extra CGI vars that aren't part of the CGI/1.1 spec, but that Apache and
+// friends set anyway.
+const REMOTE_PORT: &str = "REMOTE_PORT";
+const HTTPS: &str = "HTTPS";
+const REQUEST_SCHEME: &str = "REQUEST_SCHEME";
+const HTTP_HOST: &str = "HTTP_HOST";
+
+/// A listening socket that we can accept FastCGI connections on. This lets the
+/// accept-and-serve loop stay the same regardless of transport.
+trait Listener {
+    /// The connection type this listener hands out.
+    type Stream: TokioAsyncRead + TokioAsyncWrite + Send + 'static;
+
+    /// Short transport name, for log fields.
+    fn protocol(&self) -> &'static str;
+
+    fn accept(&self) -> impl Future<Output = io::Result<Self::Stream>> + Send;
+}
+
+impl Listener for UnixListener {
+    type Stream = tokio::net::UnixStream;
+
+    fn protocol(&self) -> &'static str {
+        "unix"
+    }
+
+    async fn accept(&self) -> io::Result<Self::Stream> {
+        UnixListener::accept(self).await.map(|(stream, _)| stream)
+    }
+}
+
+impl Listener for TcpListener {
+    type Stream = tokio::net::TcpStream;
+
+    fn protocol(&self) -> &'static str {
+        "tcp"
+    }
+
+    async fn accept(&self) -> io::Result<Self::Stream> {
+        TcpListener::accept(self).await.map(|(stream, _)| stream)
+    }
+}
+
+/// Any of the listeners we know how to serve on. Wrapping them all up in one type
+/// lets a single server run accept loops on a mix of Unix and TCP sockets.
+enum AnyListener {
+    Unix(UnixListener),
+    /// A Unix socket we created ourselves, which gets cleaned up when we're done.
+    /// Nothing reads the SocketFile; it's only there to be dropped along with us.
+    BoundUnix(UnixListener, #[allow(dead_code)] SocketFile),
+    Tcp(TcpListener),
+}
+
+impl Listener for AnyListener {
+    type Stream = Either<tokio::net::UnixStream, tokio::net::TcpStream>;
+
+    fn protocol(&self) -> &'static str {
+        match self {
+            Self::Unix(l) | Self::BoundUnix(l, _) => l.protocol(),
+            Self::Tcp(l) => l.protocol(),
+        }
+    }
+
+    async fn accept(&self) -> io::Result<Self::Stream> {
+        match self {
+            Self::Unix(l) | Self::BoundUnix(l, _) => Listener::accept(l).await.map(Either::Left),
+            Self::Tcp(l) => Listener::accept(l).await.map(Either::Right),
+        }
     }
 }
-impl std::error::Error for Fd0IsTooNormal {}
 
 /// Like [`serve_fcgid_with_graceful_shutdown`], but punts on the graceful shutdown.
-pub async fn serve_fcgid(app: axum::Router, max_connections: NonZeroUsize) -> io::Result<()> {
+pub async fn serve_fcgid<S, B>(app: S, max_connections: NonZeroUsize) -> Result<(), ServeError>
+where
+    S: Service<http::Request<axum::body::Body>, Response = http::Response<B>>
+        + Clone
+        + Send
+        + 'static,
+    S::Future: Send,
+    S::Error: Into<BoxError>,
+    B: HttpBody<Data = Bytes> + Send + 'static,
+    B::Error: Into<BoxError>,
+{
     let never = futures_util::future::pending::<()>();
     serve_fcgid_with_graceful_shutdown(app, max_connections, never).await
 }
@@ -66,91 +316,988 @@ pub async fn serve_fcgid(app: axum::Router, max_connections: NonZeroUsize) -> io
 /// the last major client that knows how to start FastCGI servers on demand like
 /// this, so it gets a shout-out in the function name.
 ///
+/// The app can be an `axum::Router`, or any other cloneable tower `Service` that
+/// handles `http::Request<axum::body::Body>`s (like a `tower::ServiceBuilder` stack).
+/// The rest of the serve functions in this crate accept the same kinds of apps.
+///
+/// The free `serve_*` functions are shorthand for common [`FcgiServer`] setups;
+/// reach for the builder when you need more control.
+///
 /// Errors: In normal operation, this function just loops until the program is
-/// terminated. An error return means we were unable to start listening on
-/// our expected Unix socket, and never made it to the accept() loop.
-pub async fn serve_fcgid_with_graceful_shutdown<F>(
-    app: axum::Router,
+/// terminated. An error return almost always means we were unable to start
+/// listening on our expected Unix socket, and never made it to the accept() loop;
+/// match on the [`ServeError`] if you want to tell the cases apart. We don't print
+/// anything to stderr ourselves, so it's up to you to report it; the error's
+/// Display impl explains what went wrong in terms a confused human can act on.
+pub async fn serve_fcgid_with_graceful_shutdown<S, B, F>(
+    app: S,
     max_connections: NonZeroUsize,
     signal: F,
-) -> io::Result<()>
+) -> Result<(), ServeError>
+where
+    S: Service<http::Request<axum::body::Body>, Response = http::Response<B>>
+        + Clone
+        + Send
+        + 'static,
+    S::Future: Send,
+    S::Error: Into<BoxError>,
+    B: HttpBody<Data = Bytes> + Send + 'static,
+    B::Error: Into<BoxError>,
+    F: Future<Output = ()> + Send + 'static,
+{
+    FcgiServer::new()
+        .max_connections(max_connections)
+        .graceful_shutdown(signal)
+        .serve(app)
+        .await
+        .map(|_| ()) // no drain deadline, so it's always a clean shutdown
+}
+
+/// Like [`serve_fcgid_on_fd_with_graceful_shutdown`], but punts on the graceful shutdown.
+pub async fn serve_fcgid_on_fd<S, B>(
+    app: S,
+    max_connections: NonZeroUsize,
+    fd: RawFd,
+) -> Result<(), ServeError>
+where
+    S: Service<http::Request<axum::body::Body>, Response = http::Response<B>>
+        + Clone
+        + Send
+        + 'static,
+    S::Future: Send,
+    S::Error: Into<BoxError>,
+    B: HttpBody<Data = Bytes> + Send + 'static,
+    B::Error: Into<BoxError>,
+{
+    let never = futures_util::future::pending::<()>();
+    serve_fcgid_on_fd_with_graceful_shutdown(app, max_connections, fd, never).await
+}
+
+/// Like [`serve_fcgid_with_graceful_shutdown`], but adopts the listening Unix socket
+/// from an arbitrary inherited file descriptor instead of fd 0. Useful when a
+/// process manager hands over its sockets somewhere else (systemd socket
+/// activation starts at fd 3, for example).
+///
+/// We take ownership of the descriptor, and it gets closed when the server stops.
+///
+/// Errors: Same as [`serve_fcgid_with_graceful_shutdown`]; in particular, returns
+/// an error without touching the descriptor if it isn't a socket.
+pub async fn serve_fcgid_on_fd_with_graceful_shutdown<S, B, F>(
+    app: S,
+    max_connections: NonZeroUsize,
+    fd: RawFd,
+    signal: F,
+) -> Result<(), ServeError>
+where
+    S: Service<http::Request<axum::body::Body>, Response = http::Response<B>>
+        + Clone
+        + Send
+        + 'static,
+    S::Future: Send,
+    S::Error: Into<BoxError>,
+    B: HttpBody<Data = Bytes> + Send + 'static,
+    B::Error: Into<BoxError>,
+    F: Future<Output = ()> + Send + 'static,
+{
+    FcgiServer::new()
+        .max_connections(max_connections)
+        .fd(fd)
+        .graceful_shutdown(signal)
+        .serve(app)
+        .await
+        .map(|_| ()) // no drain deadline, so it's always a clean shutdown
+}
+
+/// Like [`serve_fcgid_with_graceful_shutdown`], but with non-default [`Settings`].
+/// (This predates [`FcgiServer`], which can do everything this can and more.)
+pub async fn serve_fcgid_with_settings<S, B, F>(
+    app: S,
+    max_connections: NonZeroUsize,
+    settings: Settings,
+    signal: F,
+) -> Result<(), ServeError>
 where
+    S: Service<http::Request<axum::body::Body>, Response = http::Response<B>>
+        + Clone
+        + Send
+        + 'static,
+    S::Future: Send,
+    S::Error: Into<BoxError>,
+    B: HttpBody<Data = Bytes> + Send + 'static,
+    B::Error: Into<BoxError>,
     F: Future<Output = ()> + Send + 'static,
 {
-    // Verify that fd 0 is a unix socket before continuing.
-
-    // SAFETY: We just want to do a metadata check on a file descriptor whose path on disk
-    // we don't know... but there's no specific facility for that in std. The only way to
-    // get metadata for an already open file like that is to wrap it in a File struct, but
-    // for later code to be sound, we must ensure we never run its Drop impl. Hence using
-    // a ManuallyDrop as an intermediate value.
-    let fd_0_file_type = std::mem::ManuallyDrop::new(unsafe { std::fs::File::from_raw_fd(0) })
-        .metadata()?
+    FcgiServer::new()
+        .max_connections(max_connections)
+        .settings(settings)
+        .graceful_shutdown(signal)
+        .serve(app)
+        .await
+        .map(|_| ()) // no drain deadline, so it's always a clean shutdown
+}
+
+/// Serve an Axum app over FastCGI on a Unix listener you've already set up
+/// yourself, instead of one we adopt or bind. We skip all the socket checks
+/// the other serve functions do, and the listener gets closed when the server stops.
+///
+/// Errors: Since we don't have to set up a socket, an error return means the
+/// listener broke while we were using it.
+pub async fn serve_fcgi_listener<S, B, F>(
+    app: S,
+    max_connections: NonZeroUsize,
+    listener: UnixListener,
+    signal: F,
+) -> Result<(), ServeError>
+where
+    S: Service<http::Request<axum::body::Body>, Response = http::Response<B>>
+        + Clone
+        + Send
+        + 'static,
+    S::Future: Send,
+    S::Error: Into<BoxError>,
+    B: HttpBody<Data = Bytes> + Send + 'static,
+    B::Error: Into<BoxError>,
+    F: Future<Output = ()> + Send + 'static,
+{
+    FcgiServer::new()
+        .max_connections(max_connections)
+        .unix_listener(listener)
+        .graceful_shutdown(signal)
+        .serve(app)
+        .await
+        .map(|_| ()) // no drain deadline, so it's always a clean shutdown
+}
+
+/// Serve a single FastCGI request from a connection you've already got, and
+/// report how it went, instead of only logging it. That's for one-shot,
+/// CGI-style setups (something like inetd, or a systemd socket with `Accept=yes`,
+/// starting the app once per connection), and for tests and benchmarks that want
+/// numbers to look at. The request gets the same treatment as any other, settings
+/// and all, and the access log and observer still hear about it.
+///
+/// A web server that doesn't ask to keep the connection open (mod_fcgid never
+/// does) hangs up after the response, and then we return. One that does ask can
+/// send more requests on it, and those get served too, until it hangs up (or
+/// [`Settings::keep_alive`] says we should); but only the first one gets reported.
+///
+/// Returns None if the connection closed (or broke) before a request came in.
+pub async fn serve_one_fcgi_request<S, B, C>(
+    app: S,
+    settings: Settings,
+    connection: C,
+) -> Option<RequestStats>
+where
+    S: Service<http::Request<axum::body::Body>, Response = http::Response<B>>
+        + Clone
+        + Send
+        + 'static,
+    S::Future: Send,
+    S::Error: Into<BoxError>,
+    B: HttpBody<Data = Bytes> + Send + 'static,
+    B::Error: Into<BoxError>,
+    C: TokioAsyncRead + TokioAsyncWrite + Send + 'static,
+{
+    let runner = Config::with_conns(NonZeroUsize::MIN).async_runner();
+    let token = runner.get_token().await;
+    let settings = Arc::new(settings);
+    let handle = ServerHandle::default();
+    let first = Arc::new(std::sync::Mutex::new(None));
+    let report = first.clone();
+    let budget = RequestBudget::new(settings.keep_alive);
+    let (t_r, t_w) = tokio::io::split(connection);
+    let r = BudgetedReader::new(t_r, budget.clone());
+    let mut buffers = ConnectionBuffers::default();
+    token
+        .run(r.compat(), t_w.compat_write(), move |r| {
+            let app = app.clone();
+            let settings = settings.clone();
+            let handle = handle.clone();
+            let report = report.clone();
+            let budget = budget.clone();
+            let spare = buffers.for_next_request();
+            async move {
+                let _spending = budget.as_ref().map(|b| b.spend());
+                let (result, stats) =
+                    handle_fcgi_request_with_axum_app(app, settings, handle, spare, r).await;
+                report
+                    .lock()
+                    .unwrap_or_else(|poisoned| poisoned.into_inner())
+                    .get_or_insert(stats);
+                result
+            }
+            .boxed()
+        })
+        .instrument(tracing::error_span!(
+            "fastcgi_connection",
+            protocol = "one-shot"
+        ))
+        .await;
+    let mut reported = first
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    reported.take()
+}
+
+/// Take ownership of an inherited file descriptor as a tokio UnixListener, after
+/// making sure it's actually a socket.
+///
+/// About ownership: until we've checked the descriptor out, it isn't ours. The
+/// checks only ever touch duplicates of it (see [`dup_for_inspection`]), so if
+/// one fails, the original's left open and exactly how we found it. Once they
+/// pass, we adopt it in one step, and from then on the listener is its one and
+/// only owner: it gets closed when the listener drops, whether that's at shutdown
+/// or because the rest of the setup failed. Nothing ever closes it twice.
+fn adopt_unix_listener(fd: RawFd) -> Result<UnixListener, ServeError> {
+    // Verify that the fd is a unix socket before continuing. It's a file descriptor
+    // whose path on disk we don't know, and the only way std will give us metadata
+    // for one is via a File, which closes it on drop; hence the duplicate.
+    let inspect = dup_for_inspection(fd).map_err(ServeError::Adopt)?;
+    let fd_file_type = std::fs::File::from(inspect)
+        .metadata()
+        .map_err(ServeError::Adopt)?
         .file_type();
-    if !fd_0_file_type.is_socket() {
-        eprintln!("{}", FD_0_IS_TOO_NORMAL);
-        return Err(io::Error::other(Fd0IsTooNormal));
+    if !fd_file_type.is_socket() {
+        return Err(ServeError::NotASocket(fd));
     }
+    check_unix_listener(fd)?;
     // SAFETY: Yes, it is unsafe to pick a raw file descriptor up off the ground and lick it.
-    // But, we verified above that it's what we expect it to be.
-    let std_listener = unsafe { StdUnixListener::from_raw_fd(0) };
+    // But, we verified above that it's what we expect it to be, and it's ours now:
+    // whoever handed it over isn't supposed to use it anymore (for fd 0, that means
+    // nobody should be reading stdin).
+    let std_listener = StdUnixListener::from(unsafe { OwnedFd::from_raw_fd(fd) });
+
+    // Inherited sockets are often unnamed, so the address alone doesn't say much.
+    // Whoever set the socket up usually does, when you're trying to work out which
+    // web server (or systemd unit) is feeding which app.
+    let addr = std_listener.local_addr().map_err(ServeError::Adopt)?;
+    let creator = socket_creator(fd);
+    info!(
+        protocol = "unix",
+        fd,
+        addr = %describe_unix_addr(&addr),
+        creator_pid = creator.map(|(pid, _, _)| pid),
+        creator_uid = creator.map(|(_, uid, _)| uid),
+        creator_gid = creator.map(|(_, _, gid)| gid),
+        "listener created"
+    );
 
     // Set up tokio UnixListener
-    std_listener.set_nonblocking(true)?;
-    let listener = UnixListener::from_std(std_listener)?;
-    let local_addr = listener.local_addr()?;
-    info!(protocol = "unix", ?local_addr, "listener created");
+    std_listener
+        .set_nonblocking(true)
+        .map_err(ServeError::Adopt)?;
+    let listener = UnixListener::from_std(std_listener).map_err(ServeError::Adopt)?;
+    Ok(listener)
+}
+
+/// A Unix socket address, the way you'd want to see it in a log: the path, an
+/// `@name` for Linux's abstract namespace, or `(unnamed)`.
+fn describe_unix_addr(addr: &std::os::unix::net::SocketAddr) -> String {
+    if let Some(path) = addr.as_pathname() {
+        return path.display().to_string();
+    }
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::linux::net::SocketAddrExt;
+        if let Some(name) = addr.as_abstract_name() {
+            return format!("@{}", name.escape_ascii());
+        }
+    }
+    "(unnamed)".to_string()
+}
+
+/// Who set up a listening socket, as (pid, uid, gid). For a listener, Linux's
+/// `SO_PEERCRED` reports whoever called listen() on it, which for an inherited
+/// socket is the web server or systemd. Only for logging, so None means we
+/// couldn't tell, and that's fine.
+#[cfg(target_os = "linux")]
+fn socket_creator(fd: RawFd) -> Option<(libc::pid_t, libc::uid_t, libc::gid_t)> {
+    let mut cred = libc::ucred {
+        pid: 0,
+        uid: 0,
+        gid: 0,
+    };
+    let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    // SAFETY: cred and len are valid for writes, and len matches cred's size.
+    let result = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            (&mut cred as *mut libc::ucred).cast(),
+            &mut len,
+        )
+    };
+    // A pid of 0 means nobody ever filled it in.
+    (result == 0 && cred.pid != 0).then_some((cred.pid, cred.uid, cred.gid))
+}
+
+/// Other platforms have their own ways of asking, none of them worth the bother
+/// for a log line.
+#[cfg(not(target_os = "linux"))]
+fn socket_creator(_fd: RawFd) -> Option<(libc::pid_t, libc::uid_t, libc::gid_t)> {
+    None
+}
+
+/// Being a socket isn't enough; it has to be the kind we can serve on. Someone
+/// invoking us with a TCP socket (or a datagram one) on the fd would otherwise get
+/// a baffling failure somewhere down the line, instead of a clear one up front.
+fn check_unix_listener(fd: RawFd) -> Result<(), ServeError> {
+    let wrong = |problem| ServeError::WrongKindOfSocket(fd, problem);
+    // Same deal as the metadata check in adopt_unix_listener: look at a duplicate,
+    // so dropping it doesn't close the original. std checks the address family for us
+    // when looking up the local address, and errors if it isn't AF_UNIX.
+    let inspect = StdUnixListener::from(dup_for_inspection(fd).map_err(ServeError::Adopt)?);
+    if inspect.local_addr().is_err() {
+        return Err(wrong("not a Unix domain socket"));
+    }
+    let sock_type = get_socket_option(fd, libc::SO_TYPE).map_err(ServeError::Adopt)?;
+    // Message-oriented sockets would get past everything else, then mangle the
+    // FastCGI byte stream into baffling protocol errors later on, so name names.
+    match sock_type {
+        libc::SOCK_STREAM => {}
+        libc::SOCK_SEQPACKET => return Err(wrong("it's SOCK_SEQPACKET instead of SOCK_STREAM")),
+        libc::SOCK_DGRAM => return Err(wrong("it's SOCK_DGRAM instead of SOCK_STREAM")),
+        _ => return Err(wrong("not a stream socket")),
+    }
+    let listening = get_socket_option(fd, libc::SO_ACCEPTCONN).map_err(ServeError::Adopt)?;
+    if listening == 0 {
+        return Err(wrong("not in listening mode"));
+    }
+    Ok(())
+}
+
+/// A duplicate of an inherited descriptor, for poking at without taking ownership
+/// of the original. The duplicate shares everything that matters (it's the same
+/// open socket), but closing it leaves the original open.
+fn dup_for_inspection(fd: RawFd) -> io::Result<OwnedFd> {
+    // SAFETY: fcntl doesn't touch our memory, and a descriptor that isn't open just
+    // gets us EBADF. Close-on-exec, so the copy can't leak into child processes.
+    let dup = unsafe { libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, 0) };
+    if dup == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: fcntl just made this descriptor for us, so nothing else owns it.
+    Ok(unsafe { OwnedFd::from_raw_fd(dup) })
+}
+
+/// Read an integer-valued SOL_SOCKET option.
+fn get_socket_option(fd: RawFd, option: libc::c_int) -> io::Result<libc::c_int> {
+    let mut value: libc::c_int = 0;
+    let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    // SAFETY: value and len are valid for writes, and len matches value's size.
+    let result = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            option,
+            (&mut value as *mut libc::c_int).cast(),
+            &mut len,
+        )
+    };
+    if result == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(value)
+}
+
+/// The first file descriptor systemd passes to a socket-activated service. (It's
+/// `SD_LISTEN_FDS_START` in sd-daemon.h.)
+const SYSTEMD_LISTEN_FDS_START: RawFd = 3;
+
+/// Like [`serve_fcgid_systemd_with_graceful_shutdown`], but punts on the graceful shutdown.
+pub async fn serve_fcgid_systemd<S, B>(
+    app: S,
+    max_connections: NonZeroUsize,
+) -> Result<(), ServeError>
+where
+    S: Service<http::Request<axum::body::Body>, Response = http::Response<B>>
+        + Clone
+        + Send
+        + 'static,
+    S::Future: Send,
+    S::Error: Into<BoxError>,
+    B: HttpBody<Data = Bytes> + Send + 'static,
+    B::Error: Into<BoxError>,
+{
+    let never = futures_util::future::pending::<()>();
+    serve_fcgid_systemd_with_graceful_shutdown(app, max_connections, never).await
+}
+
+/// Serve an Axum app over FastCGI using a Unix socket inherited via systemd socket
+/// activation (i.e. from a `.socket` unit). We check `LISTEN_PID` and `LISTEN_FDS`
+/// the same way `sd_listen_fds()` does, then serve on the first passed descriptor.
+/// If the unit passes more than one socket, the rest are ignored.
+///
+/// We don't unset the environment variables afterwards (that's not thread-safe
+/// once the runtime is up), so be aware that any child processes inherit them.
+///
+/// Errors: Returns an error if the activation environment variables are missing,
+/// malformed, or meant for some other process, plus anything that
+/// [`serve_fcgid_on_fd_with_graceful_shutdown`] can return.
+pub async fn serve_fcgid_systemd_with_graceful_shutdown<S, B, F>(
+    app: S,
+    max_connections: NonZeroUsize,
+    signal: F,
+) -> Result<(), ServeError>
+where
+    S: Service<http::Request<axum::body::Body>, Response = http::Response<B>>
+        + Clone
+        + Send
+        + 'static,
+    S::Future: Send,
+    S::Error: Into<BoxError>,
+    B: HttpBody<Data = Bytes> + Send + 'static,
+    B::Error: Into<BoxError>,
+    F: Future<Output = ()> + Send + 'static,
+{
+    FcgiServer::new()
+        .max_connections(max_connections)
+        .systemd()
+        .graceful_shutdown(signal)
+        .serve(app)
+        .await
+        .map(|_| ()) // no drain deadline, so it's always a clean shutdown
+}
+
+/// Reads the socket activation environment variables and returns the first
+/// passed file descriptor, if they check out.
+fn systemd_listen_fd() -> Result<RawFd, ServeError> {
+    let pid = std::env::var("LISTEN_PID").map_err(|_| {
+        ServeError::NotSocketActivated("LISTEN_PID is missing or not unicode".to_string())
+    })?;
+    let pid: u32 = pid.parse().map_err(|_| {
+        ServeError::NotSocketActivated(format!("LISTEN_PID isn't a number: {:?}", pid))
+    })?;
+    if pid != std::process::id() {
+        return Err(ServeError::NotSocketActivated(format!(
+            "LISTEN_PID is {}, but we're process {}",
+            pid,
+            std::process::id()
+        )));
+    }
+
+    let fds = std::env::var("LISTEN_FDS").map_err(|_| {
+        ServeError::NotSocketActivated("LISTEN_FDS is missing or not unicode".to_string())
+    })?;
+    let fds: u32 = fds.parse().map_err(|_| {
+        ServeError::NotSocketActivated(format!("LISTEN_FDS isn't a number: {:?}", fds))
+    })?;
+    if fds == 0 {
+        return Err(ServeError::NotSocketActivated(
+            "LISTEN_FDS is 0".to_string(),
+        ));
+    }
+    if fds > 1 {
+        debug!(
+            listen_fds = fds,
+            "systemd passed more than one socket; only serving on the first"
+        );
+    }
+
+    Ok(SYSTEMD_LISTEN_FDS_START)
+}
+
+/// Like [`serve_fcgi_bind_with_graceful_shutdown`], but punts on the graceful shutdown.
+pub async fn serve_fcgi_bind<S, B>(
+    app: S,
+    max_connections: NonZeroUsize,
+    path: &Path,
+) -> Result<(), ServeError>
+where
+    S: Service<http::Request<axum::body::Body>, Response = http::Response<B>>
+        + Clone
+        + Send
+        + 'static,
+    S::Future: Send,
+    S::Error: Into<BoxError>,
+    B: HttpBody<Data = Bytes> + Send + 'static,
+    B::Error: Into<BoxError>,
+{
+    let never = futures_util::future::pending::<()>();
+    serve_fcgi_bind_with_graceful_shutdown(app, max_connections, path, never).await
+}
+
+/// Serve an Axum app over FastCGI, creating our own Unix socket at the provided
+/// path. This is the PHP-FPM arrangement: the app runs as a long-lived service,
+/// and the web server connects to a known socket path (like nginx's
+/// `fastcgi_pass unix:/run/myapp.sock`).
+///
+/// If there's already a socket at that path (say, from a previous run that
+/// crashed), we replace it; if there's some other kind of file there, we refuse to
+/// touch it. The new socket is readable and writable by its owner and group, and
+/// gets removed once the server stops accepting connections. If the web server
+/// runs as a different user (nginx as www-data, say), see
+/// [`FcgiServer::unix_socket_mode`] and [`FcgiServer::unix_socket_owner`].
+///
+/// Errors: In normal operation, this function just loops until the program is
+/// terminated. An error return means we were unable to create the socket, and
+/// never made it to the accept() loop.
+pub async fn serve_fcgi_bind_with_graceful_shutdown<S, B, F>(
+    app: S,
+    max_connections: NonZeroUsize,
+    path: &Path,
+    signal: F,
+) -> Result<(), ServeError>
+where
+    S: Service<http::Request<axum::body::Body>, Response = http::Response<B>>
+        + Clone
+        + Send
+        + 'static,
+    S::Future: Send,
+    S::Error: Into<BoxError>,
+    B: HttpBody<Data = Bytes> + Send + 'static,
+    B::Error: Into<BoxError>,
+    F: Future<Output = ()> + Send + 'static,
+{
+    FcgiServer::new()
+        .max_connections(max_connections)
+        .bind_unix(path)
+        .graceful_shutdown(signal)
+        .serve(app)
+        .await
+        .map(|_| ()) // no drain deadline, so it's always a clean shutdown
+}
+
+/// Create a Unix socket at a path and listen on it, clearing away a stale socket
+/// first if one's in the way. The permissions and ownership get fixed up before
+/// we hand the listener back, so nobody can connect until they're right.
+fn bind_unix_listener(
+    path: &Path,
+    mode: u32,
+    owner: Option<u32>,
+    group: Option<u32>,
+) -> Result<AnyListener, ServeError> {
+    let bind_err = |e| ServeError::Bind(path.display().to_string(), e);
+    match std::fs::symlink_metadata(path) {
+        Ok(meta) if meta.file_type().is_socket() => {
+            // We can't tell a stale socket from one another server is still using,
+            // but two servers fighting over one path is a config bug either way.
+            debug!(path = %path.display(), "removing leftover socket");
+            std::fs::remove_file(path).map_err(bind_err)?;
+        }
+        Ok(_) => {
+            return Err(bind_err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "file already exists and isn't a socket",
+            )));
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(bind_err(e)),
+    }
+    let listener = UnixListener::bind(path).map_err(bind_err)?;
+    // Take charge of cleanup right away, so the socket goes away even if the
+    // rest of setup fails.
+    let socket_file = SocketFile(path.to_path_buf());
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode)).map_err(bind_err)?;
+    if owner.is_some() || group.is_some() {
+        // Giving a file away usually takes root (or at least CAP_CHOWN), unless
+        // you're only switching to another group you're in.
+        std::os::unix::fs::chown(path, owner, group).map_err(bind_err)?;
+    }
+    info!(protocol = "unix", path = %path.display(), mode = format_args!("{:o}", mode), "listener created");
+    Ok(AnyListener::BoundUnix(listener, socket_file))
+}
+
+/// Create a Unix socket in Linux's abstract namespace and listen on it. Abstract
+/// sockets don't live in the filesystem, so there's nothing stale to clear away
+/// beforehand, nothing to delete afterwards, and no permissions to set: anyone
+/// in the same network namespace can connect.
+#[cfg(target_os = "linux")]
+fn bind_abstract_unix_listener(name: &[u8]) -> Result<UnixListener, ServeError> {
+    use std::os::linux::net::SocketAddrExt;
+    let bind_err = |e| ServeError::Bind(format!("@{}", name.escape_ascii()), e);
+    let addr = std::os::unix::net::SocketAddr::from_abstract_name(name).map_err(bind_err)?;
+    let std_listener = StdUnixListener::bind_addr(&addr).map_err(bind_err)?;
+    std_listener.set_nonblocking(true).map_err(bind_err)?;
+    let listener = UnixListener::from_std(std_listener).map_err(bind_err)?;
+    info!(protocol = "unix", name = %name.escape_ascii(), "abstract listener created");
+    Ok(listener)
+}
+
+/// A socket file we created, which we delete when we're done with it.
+struct SocketFile(PathBuf);
+
+impl Drop for SocketFile {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.0) {
+            debug!(path = %self.0.display(), "couldn't remove socket file: {}", e);
+        }
+    }
+}
+
+/// Like [`serve_fcgi_tcp_with_graceful_shutdown`], but punts on the graceful shutdown.
+pub async fn serve_fcgi_tcp<S, B>(
+    app: S,
+    max_connections: NonZeroUsize,
+    addr: SocketAddr,
+) -> Result<(), ServeError>
+where
+    S: Service<http::Request<axum::body::Body>, Response = http::Response<B>>
+        + Clone
+        + Send
+        + 'static,
+    S::Future: Send,
+    S::Error: Into<BoxError>,
+    B: HttpBody<Data = Bytes> + Send + 'static,
+    B::Error: Into<BoxError>,
+{
+    let never = futures_util::future::pending::<()>();
+    serve_fcgi_tcp_with_graceful_shutdown(app, max_connections, addr, never).await
+}
+
+/// Serve an Axum app over FastCGI, binding a TCP listener at the provided address.
+/// This is for clients that expect to find a long-running FastCGI server at a
+/// well-known address (like nginx's `fastcgi_pass 127.0.0.1:9000`, or the
+/// `cgi-fcgi` bridge), rather than starting the app process themselves.
+///
+/// Errors: In normal operation, this function just loops until the program is
+/// terminated. An error return means we were unable to bind the requested
+/// address, and never made it to the accept() loop.
+pub async fn serve_fcgi_tcp_with_graceful_shutdown<S, B, F>(
+    app: S,
+    max_connections: NonZeroUsize,
+    addr: SocketAddr,
+    signal: F,
+) -> Result<(), ServeError>
+where
+    S: Service<http::Request<axum::body::Body>, Response = http::Response<B>>
+        + Clone
+        + Send
+        + 'static,
+    S::Future: Send,
+    S::Error: Into<BoxError>,
+    B: HttpBody<Data = Bytes> + Send + 'static,
+    B::Error: Into<BoxError>,
+    F: Future<Output = ()> + Send + 'static,
+{
+    FcgiServer::new()
+        .max_connections(max_connections)
+        .tcp(addr)
+        .graceful_shutdown(signal)
+        .serve(app)
+        .await
+        .map(|_| ()) // no drain deadline, so it's always a clean shutdown
+}
+
+/// Bind a TCP listener for clients that connect to us at a known address.
+async fn bind_tcp_listener(addr: SocketAddr) -> Result<TcpListener, ServeError> {
+    let bind_err = |e| ServeError::Bind(addr.to_string(), e);
+    let listener = TcpListener::bind(addr).await.map_err(bind_err)?;
+    let local_addr = listener.local_addr().map_err(bind_err)?;
+    info!(protocol = "tcp", ?local_addr, "listener created");
+    Ok(listener)
+}
 
-    // Build fastcgi-server config and runner
+/// The transport-agnostic tail end of the public serve functions: builds the
+/// fastcgi-server runner, runs an accept loop on each listener (FastCGI or SCGI,
+/// as it says) until the signal fires, and then shuts down gracefully. All the
+/// FastCGI listeners share the runner, and every listener shares the handle's
+/// connection slots, so `max_connections` is a limit for the whole server, not
+/// per listener. If any
+/// listener breaks, we shut down the rest of them too. If there's a drain deadline
+/// and the in-flight connections blow past it, we abort them and report a forced
+/// shutdown.
+async fn serve_listeners_with_graceful_shutdown<S, B, F>(
+    app: SharedApp<S>,
+    max_connections: NonZeroUsize,
+    listeners: Vec<(Gateway, AnyListener)>,
+    settings: Settings,
+    signal: F,
+    drain_deadline: Option<Duration>,
+    handle: ServerHandle,
+) -> Result<Shutdown, ServeError>
+where
+    S: Service<http::Request<axum::body::Body>, Response = http::Response<B>>
+        + Clone
+        + Send
+        + 'static,
+    S::Future: Send,
+    S::Error: Into<BoxError>,
+    B: HttpBody<Data = Bytes> + Send + 'static,
+    B::Error: Into<BoxError>,
+    F: Future<Output = ()> + Send + 'static,
+{
+    // Build fastcgi-server config and runner. This is the only limit we tell
+    // fastcgi-server about, so it's also what it has to go on when a web server
+    // asks (with an FCGI_GET_VALUES management record) how many connections and
+    // requests we'll take. Settings::max_requests_per_connection doesn't figure
+    // into its answer, and neither does the handle's runtime limit. Answering
+    // those records is entirely fastcgi-server's business; we never see them.
     let config = Config::with_conns(max_connections);
     let runner = config.async_runner();
+    let settings = Arc::new(settings);
+    // Hang onto the connection tasks, so we can abort any stragglers. Each loop
+    // gets its own set, so they don't have to take turns borrowing one.
+    let mut connections: Vec<JoinSet<()>> = listeners.iter().map(|_| JoinSet::new()).collect();
 
     // Loop to accept connections and serve
-    tokio::select! {
+    let mut loops: FuturesUnordered<_> = listeners
+        .into_iter()
+        .zip(connections.iter_mut())
+        .map(|((gateway, listener), conns)| match gateway {
+            Gateway::Scgi => futures_util::future::Either::Left(scgi::serve_scgi_loop(
+                app.clone(),
+                listener,
+                settings.clone(),
+                conns,
+                handle.clone(),
+            )),
+            _ => futures_util::future::Either::Right(serve_loop(
+                &runner,
+                app.clone(),
+                listener,
+                settings.clone(),
+                conns,
+                handle.clone(),
+            )),
+        })
+        .collect();
+    // About `biased`: this select only ever runs once, with the accept loops
+    // inside it running for the life of the server, so it can't starve anything.
+    // All the bias does is decide what happens on a wakeup where the signal has
+    // fired *and* there's a connection waiting: we check the signal first, so
+    // once shutdown's been asked for, we stop accepting right away instead of
+    // taking one more connection. Checking a fired-or-not signal first costs
+    // about nothing, so there's no throughput to gain from making it fair.
+    let fatal = tokio::select! {
         biased;  // poll in order, so check the cancel future first
-        _ = signal => {},
-        _ = serve_loop(&runner, app, listener) => {}, // runs forever
+        _ = signal => None,
+        e = loops.next() => e, // runs forever, unless a listener breaks
     };
 
-    // Gracefully shut down
+    // Shutting down happens in two steps, in this order. First, stop accepting:
+    // the accept loops own the listeners, so dropping the loops closes every
+    // listening socket (and deletes the ones we bound) right now. From here on,
+    // new connections get refused instead of piling up in a backlog that nobody's
+    // going to get to, and a replacement process can bind the same address while
+    // we're still wrapping up. (A connection caught between accept() and getting
+    // a slot goes with them; it hadn't been read from yet.) The connection tasks
+    // don't belong to the loops, so they keep running.
+    drop(loops);
+    debug!("stopped accepting connections");
+
+    // Then, drain. Even if a listener broke, the connections we already accepted
+    // deserve a chance to finish.
+    let shutdown = drain_connections(&runner, &mut connections, drain_deadline).await;
+    match fatal {
+        Some(e) => Err(e),
+        None => Ok(shutdown),
+    }
+}
+
+/// Wait for in-flight connections to finish, giving up and aborting them once
+/// the deadline (if any) passes.
+async fn drain_connections(
+    runner: &Runner,
+    connections: &mut [JoinSet<()>],
+    drain_deadline: Option<Duration>,
+) -> Shutdown {
+    let Some(deadline) = drain_deadline else {
+        join_connections(runner, connections).await;
+        return Shutdown::Clean;
+    };
+    debug!(?deadline, "draining in-flight connections");
+    // (Finish awaiting before the match, so the borrow of `connections` is over
+    // by the time we need it again.)
+    let drained = tokio::time::timeout(deadline, join_connections(runner, connections)).await;
+    match drained {
+        Ok(()) => Shutdown::Clean,
+        Err(_) => {
+            // Don't count the ones that finished on their own.
+            for set in connections.iter_mut() {
+                while set.try_join_next().is_some() {}
+            }
+            let aborted = connections.iter().map(JoinSet::len).sum();
+            warn!(
+                aborted,
+                "connections still open after drain deadline; aborting them"
+            );
+            for set in connections.iter_mut() {
+                set.shutdown().await;
+            }
+            Shutdown::Forced { aborted }
+        }
+    }
+}
+
+/// Shut down the runner, then wait for every connection task to actually exit.
+/// The runner only knows about its own tokens, so a task can outlive it briefly
+/// (say, while it's dropping a connection); waiting on the join sets too means
+/// nothing's left running behind our backs once we report a clean shutdown.
+async fn join_connections(runner: &Runner, connections: &mut [JoinSet<()>]) {
     runner.shutdown().await;
-    Ok(())
+    for set in connections.iter_mut() {
+        while let Some(joined) = set.join_next().await {
+            if let Err(e) = joined {
+                if e.is_panic() {
+                    error!(blame = "nick", "connection task panicked: {}", e);
+                }
+            }
+        }
+    }
+}
+
+/// Whether an accept() error means the listener is broken for good, instead of
+/// just this once.
+fn accept_error_is_fatal(e: &io::Error) -> bool {
+    // EINVAL: the socket isn't listening.
+    if e.kind() == io::ErrorKind::InvalidInput {
+        return true;
+    }
+    matches!(
+        e.raw_os_error(),
+        // Not open anymore, not a socket at all, or a socket that doesn't do
+        // connections (a datagram one, say).
+        Some(libc::EBADF | libc::ENOTSOCK | libc::EOPNOTSUPP)
+    )
+}
+
+/// The app, as the accept loops see it. Each new connection takes its own clone,
+/// and a reload (see [`FcgiServer::serve_with_reloads`]) swaps in a new app for
+/// connections accepted after that; the ones already open keep the app they
+/// started with.
+type SharedApp<S> = Arc<std::sync::Mutex<S>>;
+
+/// A clone of the current app, for a new connection.
+fn current_app<S: Clone>(app: &SharedApp<S>) -> S {
+    // Cloning an app shouldn't panic, but if one ever did, the app itself is fine.
+    app.lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .clone()
 }
 
+/// Bounds for how long [`serve_loop`] waits out a run of failed accepts.
+const ACCEPT_BACKOFF_MIN: Duration = Duration::from_millis(10);
+const ACCEPT_BACKOFF_MAX: Duration = Duration::from_secs(1);
+
 /// Perform the main accept-and-serve loop for translating FastCGI requests to
-/// app-level HTTP requests (and back again).
-async fn serve_loop(runner: &Runner, app: axum::Router, listener: UnixListener) {
+/// app-level HTTP requests (and back again). This only returns if the listener
+/// itself breaks.
+async fn serve_loop<L, S, B>(
+    runner: &Runner,
+    app: SharedApp<S>,
+    listener: L,
+    settings: Arc<Settings>,
+    connections: &mut JoinSet<()>,
+    handle: ServerHandle,
+) -> ServeError
+where
+    L: Listener,
+    S: Service<http::Request<axum::body::Body>, Response = http::Response<B>>
+        + Clone
+        + Send
+        + 'static,
+    S::Future: Send,
+    S::Error: Into<BoxError>,
+    B: HttpBody<Data = Bytes> + Send + 'static,
+    B::Error: Into<BoxError>,
+{
+    // How long to wait before accepting again after an error. Doubles with each
+    // consecutive failure, so a crunch that lasts (like running out of fds) doesn't
+    // spin the CPU, and resets on the first success.
+    let mut backoff = ACCEPT_BACKOFF_MIN;
     // Loop to accept connections and serve
     loop {
+        // Reap finished connection tasks as we go, so the set doesn't grow forever.
+        while connections.try_join_next().is_some() {}
         let token = runner.get_token().await;
         match listener.accept().await {
+            // Some errors mean the listener itself is done for, and whoever's
+            // supervising us should hear about it. Anything else (aborted handshakes,
+            // running out of fds) is probably about one connection or a passing
+            // crunch, so keep going.
+            Err(e) if accept_error_is_fatal(&e) => {
+                error!(
+                    protocol = listener.protocol(),
+                    "accept failed, giving up: {}", &e
+                );
+                return ServeError::Accept(e);
+            }
             Err(e) => {
-                error!(protocol = "unix", "accept failed: {}", &e);
+                error!(
+                    protocol = listener.protocol(),
+                    retry_in = ?backoff,
+                    "accept failed: {}", &e
+                );
+                // Give back the token while we wait, in case it's fds we're out of.
+                drop(token);
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(ACCEPT_BACKOFF_MAX);
                 continue;
             }
-            Ok((mut connection, _)) => {
+            Ok(connection) => {
+                backoff = ACCEPT_BACKOFF_MIN;
+                // The runner's tokens cap connections at the server's max_connections,
+                // but the handle can turn the limit down from there at runtime, so
+                // the connection might have to wait its turn. (We already accepted
+                // it, but it'd be waiting in the listen backlog otherwise anyway.)
+                let active = handle.connection_slot().await;
                 // Tracing span for the task that'll handle this connection
-                let span = tracing::error_span!("fastcgi_connection", protocol = "unix",);
+                let span =
+                    tracing::error_span!("fastcgi_connection", protocol = listener.protocol());
                 // Good thing Axum apps are cheap to clone, cuz we need several.
                 // This one belongs to the connection, which might serve several requests.
-                let app_for_conn = app.clone();
+                let app_for_conn = current_app(&app);
+                let settings = settings.clone();
+                let handle = handle.clone();
+                // Requests on this connection take turns, if the settings say so.
+                let request_slots = settings
+                    .max_requests_per_connection
+                    .map(|limit| Arc::new(Semaphore::new(limit.get())));
+                // And it gets closed once it's served enough of them, if the settings
+                // say that's sooner than the client would.
+                let budget = RequestBudget::new(settings.keep_alive);
+                // And its requests pass the response buffer along, instead of each
+                // allocating their own.
+                let mut buffers = ConnectionBuffers::default();
 
                 // Spawn a separate task to handle this connection
-                tokio::spawn(
+                connections.spawn(
                     async move {
                         debug!("new connection accepted on dedicated task");
-                        let (t_r, t_w) = connection.split();
-                        // Tokio's UnixStream uses Tokio's Async IO traits; convert that to
+                        let _active = active;
+                        let (t_r, t_w) = tokio::io::split(connection);
+                        // Tokio's streams use Tokio's Async IO traits; convert that to
                         // the futures_util::io traits that fastcgi-server uses.
-                        let r = t_r.compat();
+                        let r = BudgetedReader::new(t_r, budget.clone()).compat();
                         let w = t_w.compat_write();
                         // Then, handle the connection! The handler might get called several
                         // times, so it performs its own additional clone of the app.
+                        // How many is up to the client's keep-conn flag, which only
+                        // fastcgi-server sees; the budget's how we close any sooner
+                        // than that. See Settings::keep_alive.
                         token
                             .run(r, w, move |r| {
-                                handle_fcgi_request_with_axum_app(app_for_conn.clone(), r).boxed()
+                                let app = app_for_conn.clone();
+                                let settings = settings.clone();
+                                let handle = handle.clone();
+                                let request_slots = request_slots.clone();
+                                let budget = budget.clone();
+                                let spare = buffers.for_next_request();
+                                async move {
+                                    // Counted from before it waits for a slot, so the
+                                    // connection can't close on a request that's queued.
+                                    let _spending = budget.as_ref().map(|b| b.spend());
+                                    // The semaphore never gets closed, so this can't fail.
+                                    let _slot = match request_slots {
+                                        Some(slots) => slots.acquire_owned().await.ok(),
+                                        None => None,
+                                    };
+                                    handle_fcgi_request_with_axum_app(
+                                        app, settings, handle, spare, r,
+                                    )
+                                    .await
+                                    .0
+                                }
+                                .boxed()
                             })
-                            .await
+                            .await;
+                        // Protocol errors (malformed records, a params stream too big to
+                        // buffer...) get handled inside fastcgi-server, which closes the
+                        // connection and doesn't hand us anything to tell them apart from
+                        // a normal hangup. So all we know is that it's over. Either way,
+                        // run() consumed the token, so the slot's already free again.
+                        debug!("connection closed");
                     }
                     .instrument(span),
                 );
@@ -161,13 +1308,189 @@ async fn serve_loop(runner: &Runner, app: axum::Router, listener: UnixListener)
 
 /// Translates an incoming FastCGI request to an HTTP request, handles it with the
 /// provided Axum app, and sends the result back to the client as a FastCGI response.
-/// This all happens in one function, because fastcgi_server::async_io::Request is
-/// a hefty beast that also includes a response writer handle. This function is
-/// meant to be called in the handler closure passed to Token::run().
-async fn handle_fcgi_request_with_axum_app(
-    mut app: axum::Router,
-    req: &mut FcgiRequest<'_, '_, '_>,
-) -> std::io::Result<ExitStatus> {
+/// This function is meant to be called in the handler closure passed to Token::run();
+/// it leaves the per-request bookkeeping to [`handle_request`] and the actual work
+/// to [`respond_to_fcgi_request`]. The stats are for whoever wants them; most
+/// callers don't.
+async fn handle_fcgi_request_with_axum_app<S, B, C>(
+    app: S,
+    settings: Arc<Settings>,
+    handle: ServerHandle,
+    spare: Option<SpareBuffer>,
+    req: &mut FcgiRequest<'_, C>,
+) -> (std::io::Result<ExitStatus>, RequestStats)
+where
+    S: Service<http::Request<axum::body::Body>, Response = http::Response<B>>
+        + Clone
+        + Send
+        + 'static,
+    S::Future: Send,
+    S::Error: Into<BoxError>,
+    B: HttpBody<Data = Bytes> + Send + 'static,
+    B::Error: Into<BoxError>,
+    C: TokioAsyncRead + TokioAsyncWrite + Send + 'static,
+{
+    let vars = FcgiVars::from_env(req.env_iter());
+    let ready = handle.is_ready();
+    let settings = &*settings;
+    handle_request(settings, &handle, vars, |vars, request_id| async move {
+        let mut outcome = RequestOutcome::default();
+        let result = respond_to_fcgi_request(
+            app,
+            settings,
+            req,
+            spare,
+            vars,
+            request_id.as_ref(),
+            ready,
+            &mut outcome,
+        )
+        .await;
+        (result, outcome)
+    })
+    .await
+}
+
+/// The per-request bookkeeping that goes around every transport's responder:
+/// counting the request on the handle, working out its ID, putting everything
+/// (including whatever the app logs) in a span for it, and telling the observer
+/// and the access log how it went. `respond` gets the vars and the request ID,
+/// and does the rest.
+async fn handle_request<T, F, Fut>(
+    settings: &Settings,
+    handle: &ServerHandle,
+    mut vars: FcgiVars,
+    respond: F,
+) -> (io::Result<T>, RequestStats)
+where
+    F: FnOnce(FcgiVars, Option<RequestId>) -> Fut,
+    Fut: Future<Output = (io::Result<T>, RequestOutcome)>,
+{
+    let started = Instant::now();
+    let _active = handle.track_request();
+    // Not the FastCGI protocol's request ID; see the crate docs for why not.
+    let request_id = RequestId::from_vars(&mut vars, settings);
+    // Everything that happens from here on (including whatever the app logs) goes
+    // in a span for this request.
+    let span = match &settings.request_span {
+        Some(make_span) => make_span(&vars),
+        None => default_request_span(&vars),
+    };
+    if let Some(id) = request_id.as_ref().and_then(|id| id.value.to_str().ok()) {
+        span.record("request_id", id);
+    }
+    async move {
+        if let Some(observer) = &settings.observer {
+            observer.request_started(&RequestStarted {
+                role: settings.role,
+            });
+        }
+        // Read these before the app gets a chance to consume anything. Only allocates
+        // when the access log is on.
+        let access_log_info = settings.access_log.then(|| {
+            let method = vars.get(cgi::REQUEST_METHOD).unwrap_or(b"GET");
+            let uri = vars.get(cgi::REQUEST_URI).unwrap_or(b"/");
+            (
+                String::from_utf8_lossy(method).into_owned(),
+                String::from_utf8_lossy(uri).into_owned(),
+            )
+        });
+
+        let (result, outcome) = respond(vars, request_id).await;
+        let elapsed = started.elapsed();
+
+        if let Some((method, uri)) = access_log_info {
+            info!(
+                %method,
+                %uri,
+                status = outcome.status.map(|s| s.as_u16()),
+                header_bytes = outcome.size.header_bytes,
+                body_bytes = outcome.size.body_bytes,
+                elapsed_ms = elapsed.as_millis() as u64,
+                "request served"
+            );
+        }
+        if let Some(observer) = &settings.observer {
+            observer.request_finished(&RequestFinished {
+                role: settings.role,
+                status: outcome.status,
+                elapsed,
+            });
+        }
+        let stats = RequestStats {
+            status: outcome.status,
+            bytes_in: outcome.bytes_in,
+            bytes_out: outcome.size.header_bytes + outcome.size.body_bytes,
+            elapsed,
+        };
+        (result, stats)
+    }
+    .instrument(span)
+    .await
+}
+
+/// The span we put each request in, unless the settings say otherwise. The
+/// request ID gets recorded later, if there is one.
+fn default_request_span(vars: &FcgiVars) -> tracing::Span {
+    let method = String::from_utf8_lossy(vars.get(cgi::REQUEST_METHOD).unwrap_or(b"GET"));
+    let uri = String::from_utf8_lossy(vars.get(cgi::REQUEST_URI).unwrap_or(b"/"));
+    tracing::info_span!(
+        "request",
+        %method,
+        %uri,
+        request_id = tracing::field::Empty
+    )
+}
+
+/// How many seconds to tell clients to wait, when they catch us warming up. Most
+/// warm-ups are quick, and clients that honor this at all tend to take it literally.
+const WARMING_UP_RETRY_AFTER_SECS: u32 = 5;
+
+/// Whether a request is for the configured health check path. A path that won't
+/// parse isn't a health check; the app can have the privilege of rejecting it.
+fn is_health_check(vars: &FcgiVars, health_check_path: &str, path_from_path_info: bool) -> bool {
+    path_and_query_from_vars(vars, path_from_path_info)
+        .is_ok_and(|pq| pq.path() == health_check_path)
+}
+
+/// What happened while responding to a request, for the bookkeeping in
+/// [`handle_request`].
+#[derive(Debug, Default)]
+struct RequestOutcome {
+    /// The status we sent, if we got as far as sending one.
+    status: Option<http::StatusCode>,
+    size: ResponseSize,
+    /// How much of the request body we read.
+    bytes_in: u64,
+}
+
+/// Does the actual work of [`handle_fcgi_request_with_axum_app`]. This all happens
+/// in one function, because fastcgi_server::async_io::Request is a hefty beast that
+/// also includes a response writer handle. Whenever we send a response, we note
+/// what it was in `outcome`, and apply whatever response options the settings call for.
+/// The response goes out through the connection's spare buffer, if it's got one.
+#[allow(clippy::too_many_arguments)]
+async fn respond_to_fcgi_request<S, B, C>(
+    app: S,
+    settings: &Settings,
+    req: &mut FcgiRequest<'_, C>,
+    spare: Option<SpareBuffer>,
+    vars: FcgiVars,
+    request_id: Option<&RequestId>,
+    ready: bool,
+    outcome: &mut RequestOutcome,
+) -> std::io::Result<ExitStatus>
+where
+    S: Service<http::Request<axum::body::Body>, Response = http::Response<B>>
+        + Clone
+        + Send
+        + 'static,
+    S::Future: Send,
+    S::Error: Into<BoxError>,
+    B: HttpBody<Data = Bytes> + Send + 'static,
+    B::Error: Into<BoxError>,
+    C: TokioAsyncRead + TokioAsyncWrite + Send + 'static,
+{
     // About that return type: it's tied to both the CGI programming model and the
     // FastCGI network protocol.
     //
@@ -182,11 +1505,19 @@ async fn handle_fcgi_request_with_axum_app(
     // set up a tracing fmt subscriber and rely on the fact that stdout ends up in
     // Apache's error_log.
 
-    // FastCGI's programming model had several roles, but we only care about "responder".
-    if req.role() != fastcgi_server::protocol::Role::Responder {
+    let opts = ResponseOptions::new(settings, request_id, &vars, Gateway::FastCgi);
+
+    // FastCGI's programming model had several roles, but we only care about "responder"
+    // (and "authorizer", if the app's been set up for it).
+    let expected_role = match settings.role {
+        Role::Responder => fastcgi_server::protocol::Role::Responder,
+        Role::Authorizer => fastcgi_server::protocol::Role::Authorizer,
+    };
+    if req.role() != expected_role {
         error!(
             blame = "end user",
-            "App received a request for a non-Responder role; the client must be misconfigured"
+            expected_role = ?settings.role,
+            "App received a request for an unexpected role; the client must be misconfigured"
         );
         return Ok(ExitStatus::Complete(1));
     }
@@ -195,168 +1526,1216 @@ async fn handle_fcgi_request_with_axum_app(
     // stream. Semantics are somewhat different for non-Responder roles, but we don't care.
     req.writeable().await?;
 
+    // Grab the output handle early, before we borrow req as mut for an extended read
+    let w = req.output_stream(fastcgi_server::protocol::RecordType::Stdout);
+
+    // Everything in between reading the request and writing the response is the
+    // same as for the other transports. Reading the body off the request is still
+    // reading FastCGI records, though, so if that breaks, the connection's gone.
+    let reply = respond_to_request(
+        app,
+        settings,
+        vars,
+        &mut *req,
+        Gateway::FastCgi,
+        ready,
+        &mut outcome.bytes_in,
+    )
+    .await?;
+    let (resp, exit_status) = match reply {
+        Reply::Respond(resp) => (Some(resp), ExitStatus::SUCCESS),
+        Reply::Failed(resp, note) => {
+            write_stderr_diagnostic(req, settings, &note).await;
+            (resp, ExitStatus::Complete(1))
+        }
+    };
+    if let Some(resp) = resp {
+        let mut buffered = ResponseBuffer::with_spare(opts.buffer, w, spare);
+        // If this write hits an error, either we literally can't write output anymore
+        // or the app's body broke halfway; either way, return an io::Error instead of
+        // an exit code, so the runner drops the connection instead of ending the request
+        // like everything went fine.
+        trace!("writing response as fcgi response");
+        let status = resp.status();
+        let size = write_http_response(&mut buffered, resp, &opts).await?;
+        trace!("finished writing fcgi response and flushing output");
+        outcome.status = Some(status);
+        outcome.size = size;
+    }
+
+    Ok(exit_status)
+}
+
+/// What to send back for a request, once [`respond_to_request`] is done with it.
+enum Reply {
+    /// A response to send: the app's, or one we answered with ourselves.
+    Respond(http::Response<axum::body::Body>),
+    /// The app couldn't handle the request. Send the response if there is one.
+    /// There isn't when the app failed without leaving us anything better to say
+    /// (its service returned an error, say); FastCGI reports that with an exit
+    /// status instead, and the other transports make a 500 of it. The note is for
+    /// the web server's error log, on transports that have a way to get it there.
+    Failed(Option<http::Response<axum::body::Body>>, String),
+}
+
+/// The part of answering a request that doesn't care how it got here, shared by
+/// FastCGI, SCGI, and plain CGI: turn away the requests we can answer without the
+/// app, build the http::Request, stream the body in from `body` while the app
+/// works on it, and touch up whatever the app sends back (error pages, HEAD
+/// requests, and the Authorizer role's conventions, for FastCGI). The transport
+/// reads the vars before this and writes the reply after, and both of those are
+/// all it has to do. We note how much of the body we read in `bytes_in`.
+///
+/// Whatever's left of the body once we've got a reply gets read and thrown away,
+/// however we got there: we answered without the app, the app didn't want the
+/// body, we cut it off at max_body_bytes or its Content-Length, or the app timed
+/// out. Over FastCGI, the body's records have to come off the connection before
+/// it can carry another request; over SCGI or CGI, hanging up on a web server
+/// that's still sending can cost it the response. (Web servers mostly send the
+/// whole body before they read any of the response anyway, so this doesn't hold
+/// up the reply any longer than it already was. A client that's still trickling
+/// in a body does hold it up, though, timeout or no.)
+///
+/// Errors: Returns an io::Error if the connection broke while we were reading the
+/// body, in which case there's nobody left to reply to.
+async fn respond_to_request<S, B, R>(
+    app: S,
+    settings: &Settings,
+    vars: FcgiVars,
+    mut body: R,
+    gateway: Gateway,
+    ready: bool,
+    bytes_in: &mut u64,
+) -> io::Result<Reply>
+where
+    S: Service<http::Request<axum::body::Body>, Response = http::Response<B>> + Send,
+    S::Future: Send,
+    S::Error: Into<BoxError>,
+    B: HttpBody<Data = Bytes> + Send + 'static,
+    B::Error: Into<BoxError>,
+    R: AsyncRead + Unpin,
+{
+    let reply =
+        respond_before_draining(app, settings, vars, &mut body, gateway, ready, bytes_in).await?;
+    let discarded = match futures_util::io::copy(&mut body, &mut futures_util::io::sink()).await {
+        Ok(discarded) => discarded,
+        Err(e) => {
+            debug!("Connection broke while discarding the request body: {}", e);
+            return Err(e);
+        }
+    };
+    if discarded > 0 {
+        trace!(discarded, "discarded the rest of the request body");
+    }
+    Ok(reply)
+}
+
+/// Does the actual work of [`respond_to_request`], which cleans up whatever of the
+/// body we leave behind.
+async fn respond_before_draining<S, B, R>(
+    mut app: S,
+    settings: &Settings,
+    vars: FcgiVars,
+    body: R,
+    gateway: Gateway,
+    ready: bool,
+    bytes_in: &mut u64,
+) -> io::Result<Reply>
+where
+    S: Service<http::Request<axum::body::Body>, Response = http::Response<B>> + Send,
+    S::Future: Send,
+    S::Error: Into<BoxError>,
+    B: HttpBody<Data = Bytes> + Send + 'static,
+    B::Error: Into<BoxError>,
+    R: AsyncRead + Unpin,
+{
+    // If the web server's supposed to vouch for every request, make sure it did.
+    if let Some((name, expected)) = &settings.required_var {
+        if vars.get(name) != Some(expected.as_bytes()) {
+            error!(
+                blame = "end user or web server config",
+                var = %name,
+                "Request is missing a required CGI var (or has the wrong value); refusing"
+            );
+            return Ok(Reply::Respond(status_response(http::StatusCode::FORBIDDEN)));
+        }
+    }
+
+    // Still warming up (see FcgiServer::ready_when), so the app can't take it yet.
+    if !ready {
+        warn!("App isn't ready yet; sending a 503");
+        let mut resp = status_response(http::StatusCode::SERVICE_UNAVAILABLE);
+        resp.headers_mut().insert(
+            http::header::RETRY_AFTER,
+            http::HeaderValue::from(WARMING_UP_RETRY_AFTER_SECS),
+        );
+        return Ok(Reply::Respond(resp));
+    }
+
+    // Health checks get their answer from us, so the app never hears about them.
+    if let Some(path) = &settings.health_check_path {
+        if is_health_check(&vars, path, settings.path_from_path_info) {
+            debug!("Answering health check");
+            return Ok(Reply::Respond(status_response(http::StatusCode::OK)));
+        }
+    }
+
+    let content_length = vars
+        .get_str(cgi::CONTENT_LENGTH)
+        .and_then(|v| v.parse::<u64>().ok());
+
+    // If the client told us up front that the body's over the limit, we can turn
+    // it away right now, without reading any of it or bothering the app.
+    if let Some(limit) = settings.max_body_bytes {
+        if content_length.is_some_and(|len| len > limit as u64) {
+            error!(
+                blame = "end user",
+                limit, content_length, "Request body is larger than max_body_bytes; rejecting"
+            );
+            let status = http::StatusCode::PAYLOAD_TOO_LARGE;
+            return Ok(Reply::Respond(status_response(status)));
+        }
+    }
+
+    let head = vars.get(cgi::REQUEST_METHOD) == Some(b"HEAD");
+
     // Construct an http::Request for our inner app
-    let (http_req, body_tx) = match http_request_from_fcgi_request(req) {
+    let (http_req, body_tx) = match http_request_from_vars(vars, settings, gateway) {
         Ok(stuff) => stuff,
         Err(e) => {
-            // This means the http headers, URI, or method failed to parse.
+            // This means the http headers, URI, or method failed to parse, or there
+            // were too many headers. That's probably garbage from the client that the
+            // web server passed along as-is, so tell the client it messed up (instead
+            // of leaving it with an empty reply) and keep the connection going.
+            let status = e.status();
             error!(
-                blame = "apache, fastcgi-server, or nick",
-                "Failed to finalize http::Request: {}", e
+                blame = "end user, web server, fastcgi-server, or nick",
+                status = status.as_u16(),
+                "Failed to finalize http::Request: {}",
+                e
             );
-            return Ok(ExitStatus::Complete(1));
+            return Ok(Reply::Respond(status_response(status)));
         }
     };
     trace!("Constructed http request");
 
-    // Grab the output handle early, before we borrow req as mut for an extended read
-    let w = req.output_stream(fastcgi_server::protocol::RecordType::Stdout);
-
     // well, I'd like to just ::spawn the body transmission, but it has borrowed
     // data that I don't want to copy. So!
+    let body_tx_fut = forward_body(body, body_tx, content_length, settings, bytes_in);
 
-    // Stream the decoded request body into the HTTP request
-    let body_tx_fut = async {
-        trace!("Started polling body transmit future");
-        // Or I could stack-allocate a fixed-size buffer and loop on
-        // poll_read. But what I'm banking on here is that the tokio_stream/_util authors
-        // know more than me about how to cheat their way out of copies.
-        let mut bytes_stream = FramedRead::new(req.compat(), BytesCodec::new());
-        while let Some(x) = bytes_stream.next().await {
-            trace!("streaming bytes...");
-            if let Err(e) = body_tx.send(x) {
-                // I think this can happen if the axum app detects something wrong with the
-                // request before it finishes slurping the body, and decides to just bail;
-                // for example, route's got a Json() extractor but the incoming content-type
-                // is wrong. So, we'll log an error event here, but allow the app to finish
-                // responding with whatever its actual complaint was.
-                error!(
-                    blame = "end user or app",
-                    "Body bytes receiver got dropped, probably bc the app didn't want any: {}", e
-                );
-                break;
-            };
-        }
-        // Once the send loop is done, gotta explicitly drop the transmitter so that
-        // the stream on the other side knows we're done.
-        drop(body_tx);
-    };
+    // Tower services are supposed to be polled for readiness before every call.
+    // Axum Routers are always ready, but hand-rolled services might not be.
+    // The app's errors get boxed right away, since its own error type might not
+    // be Send, and we've got awaits to get through while we're still holding them.
+    let ready = futures_util::future::poll_fn(|cx| app.poll_ready(cx)).await;
+    if let Err(e) = ready.map_err(Into::<BoxError>::into) {
+        error!(blame = "app", "App service failed to become ready: {}", e);
+        let note = format!("app service failed to become ready: {}", e);
+        return Ok(Reply::Failed(None, note));
+    }
 
-    // Actually call our inner HTTP app!
-    let app_response_fut = app.call(http_req);
+    // Actually call our inner HTTP app! If it panics, we'd rather send a 500 than
+    // let the unwind take the whole connection down. The call itself goes inside
+    // catch_unwind too, since a service can panic before handing back a future.
+    // Awaiting inside this outer block matters: it means a panicked app future gets
+    // dropped right away, which drops the body receiver and lets body_tx_fut bail
+    // out, instead of leaving it stuck on a full channel that nobody will drain.
+    let app_response_fut = async {
+        std::panic::AssertUnwindSafe(async {
+            app.call(http_req).await.map_err(Into::<BoxError>::into)
+        })
+        .catch_unwind()
+        .await
+    };
 
     // Since routes can extract a completed body before they start to return a response,
-    // we now need to await these two futures in tandem.
+    // we now need to await these two futures in tandem. Normally that means waiting
+    // for both, but if the connection breaks while we're reading the body, we drop
+    // the app's future on the spot (cancelling it) instead of letting it finish.
     trace!("Polling body stream and app futures in tandem:");
-    let (_, app_response) = tokio::join!(body_tx_fut, app_response_fut);
+    let joint_fut = async {
+        tokio::pin!(body_tx_fut, app_response_fut);
+        tokio::select! {
+            read = &mut body_tx_fut => match read {
+                Ok(()) => Ok(app_response_fut.await),
+                Err(e) => Err(e),
+            },
+            resp = &mut app_response_fut => body_tx_fut.await.map(|()| resp),
+        }
+    };
+    let app_response = match settings.request_timeout {
+        None => joint_fut.await,
+        Some(limit) => match tokio::time::timeout(limit, joint_fut).await {
+            Ok(stuff) => stuff,
+            Err(_) => {
+                // Timing out dropped both futures, which cancels the app call and
+                // drops the body sender; respond_to_request reads off the rest.
+                error!(
+                    blame = "app",
+                    timeout = ?limit,
+                    "App didn't respond in time; sending a 504 instead"
+                );
+                let note = format!("app didn't respond within {:?}", limit);
+                let resp = status_response(http::StatusCode::GATEWAY_TIMEOUT);
+                return Ok(Reply::Failed(Some(resp), note));
+            }
+        },
+    };
+    // Returning the error ends the connection, which frees its slot for someone else.
+    let app_response = app_response?;
     trace!("successfully finished polling joint futures, received app response");
-    // neat can't-panic unwrap trick for Infallible, from the axum repo's examples
     let app_response = match app_response {
         Ok(x) => x,
-        Err(e) => match e {},
+        Err(panic) => {
+            let why = panic_message(&*panic);
+            error!(
+                blame = "app",
+                "App panicked while handling a request; sending a 500 instead: {}", why
+            );
+            let note = format!("app panicked: {}", why);
+            let resp = status_response(http::StatusCode::INTERNAL_SERVER_ERROR);
+            return Ok(Reply::Failed(Some(resp), note));
+        }
+    };
+    // Axum Routers can't fail here (their error type is Infallible), but other
+    // services can. Either way, the response body gets boxed into an axum Body.
+    let app_response = match app_response {
+        Ok(x) => x.map(axum::body::Body::new),
+        Err(e) => {
+            error!(
+                blame = "app",
+                "App service returned an error instead of a response: {}", e
+            );
+            let note = format!("app returned an error instead of a response: {}", e);
+            return Ok(Reply::Failed(None, note));
+        }
+    };
+    // There's no connection to upgrade on our end (it belongs to the web server, and
+    // all we carry is request/response pairs), so WebSockets and friends are out.
+    // Passing the 101 along would leave the client waiting on a protocol switch that
+    // never happens; a 501 at least makes the problem obvious.
+    if app_response.status() == http::StatusCode::SWITCHING_PROTOCOLS {
+        error!(
+            blame = "app",
+            "App tried to upgrade the connection (a WebSocket?), which we can't pass along; sending a 501 instead"
+        );
+        let note = "app tried to upgrade the connection".to_string();
+        let resp = status_response(http::StatusCode::NOT_IMPLEMENTED);
+        return Ok(Reply::Failed(Some(resp), note));
+    }
+    let app_response = with_error_page(app_response, settings);
+    // Roles are a FastCGI thing; the other transports only have responders.
+    let app_response = match (gateway, settings.role) {
+        (Gateway::FastCgi, Role::Authorizer) => authorizer_response(app_response),
+        _ => app_response,
+    };
+    let app_response = if head {
+        head_response(app_response)
+    } else {
+        app_response
+    };
+    Ok(Reply::Respond(app_response))
+}
+
+/// Stream a request body from the transport into the app's request, keeping count
+/// in `bytes_in` as we go, until the body's over or the app stops listening. Along
+/// the way, this holds the body to its Content-Length (if it has one) and to
+/// [`Settings::max_body_bytes`]. No sender means the app gets no body, so there's
+/// nothing to do. Whatever we don't forward stays in `body`, for
+/// [`respond_to_request`] to throw away.
+///
+/// Errors: Returns an io::Error if the connection broke partway through.
+async fn forward_body<R>(
+    body: R,
+    body_tx: Option<BodySender>,
+    content_length: Option<u64>,
+    settings: &Settings,
+    bytes_in: &mut u64,
+) -> io::Result<()>
+where
+    R: AsyncRead + Unpin,
+{
+    let Some(body_tx) = body_tx else {
+        // No body coming, so the app's already got an empty one, and we're done.
+        return Ok(());
+    };
+    trace!("Started polling body transmit future");
+    // Or I could stack-allocate a fixed-size buffer and loop on
+    // poll_read. But what I'm banking on here is that the tokio_stream/_util authors
+    // know more than me about how to cheat their way out of copies.
+    let mut bytes_stream = FramedRead::with_capacity(
+        body.compat(),
+        BytesCodec::new(),
+        settings.body_read_buffer.get(),
+    );
+    let mut forwarded: usize = 0;
+    // Whether the stream ran dry on its own, as opposed to us bailing early.
+    let mut reached_end = true;
+    while let Some(read) = bytes_stream.next().await {
+        trace!("streaming bytes...");
+        // A read error means the connection went away mid-body, which mostly
+        // happens when the client hangs up and the web server gives up on the
+        // request. That's routine, so it's not worth an error event. There's
+        // nobody left to respond to, so bail and let the caller cancel the app.
+        let mut chunk = match read {
+            Ok(chunk) => chunk,
+            Err(e) => {
+                debug!("Connection broke while reading the request body: {}", e);
+                return Err(e);
+            }
+        };
+        // If Content-Length was given, the app's going to expect exactly that many
+        // bytes, so anything past it gets dropped on the floor. (mod_fcgid won't
+        // send more than it advertised, but other clients might.)
+        let mut overlong = false;
+        if let Some(expected) = content_length {
+            let remaining = expected.saturating_sub(forwarded as u64);
+            if chunk.len() as u64 > remaining {
+                chunk.truncate(remaining as usize);
+                overlong = true;
+            }
+        }
+        if settings.dump_bytes {
+            trace!(len = chunk.len(), data = %chunk.escape_ascii(), "request body bytes");
+        }
+        // Enforce the body limit in case Content-Length was missing or lied. We hand
+        // the app an error rather than a silently truncated body, so it doesn't
+        // mistake a partial upload for a complete one.
+        forwarded = forwarded.saturating_add(chunk.len());
+        if let Some(limit) = settings.max_body_bytes {
+            if forwarded > limit {
+                error!(
+                    blame = "end user",
+                    limit, "Request body exceeded max_body_bytes; cutting it off"
+                );
+                let too_big = io::Error::other("request body exceeded the size limit");
+                let _ = body_tx.send(Err(too_big)).await;
+                reached_end = false;
+                break;
+            }
+        }
+        *bytes_in = forwarded as u64;
+        if overlong {
+            warn!(
+                blame = "web server",
+                content_length, "Request body ran past its Content-Length; ignoring the rest"
+            );
+            reached_end = false;
+            if !chunk.is_empty() {
+                let _ = body_tx.send(Ok(chunk)).await;
+            }
+            break;
+        }
+        // Awaiting the send is what gives us backpressure: if the app isn't keeping
+        // up, we stop reading from the connection until it makes room.
+        if let Err(e) = body_tx.send(Ok(chunk)).await {
+            // I think this can happen if the axum app detects something wrong with the
+            // request before it finishes slurping the body, and decides to just bail;
+            // for example, route's got a Json() extractor but the incoming content-type
+            // is wrong. Or it just didn't want the body: a route that never takes one,
+            // or a 413 for an upload it won't bother reading. So, we'll log an event
+            // here, but allow the app to finish responding with whatever it had to say.
+            debug!(
+                blame = "end user or app",
+                "Body bytes receiver got dropped, probably bc the app didn't want any: {}", e
+            );
+            // The rest of the body's still on its way, though; respond_to_request
+            // throws it on the floor once the app's done.
+            reached_end = false;
+            break;
+        };
+    }
+    // A body that ends early just means EOF for the app, which is what dropping
+    // the sender says. We only note it, since the app will probably complain on
+    // its own if the partial body doesn't parse.
+    if let Some(expected) = content_length {
+        if reached_end && (forwarded as u64) < expected {
+            warn!(
+                blame = "end user or web server",
+                content_length,
+                received = forwarded,
+                "Request body ended before its Content-Length"
+            );
+        }
+    }
+    // Once the send loop is done, gotta explicitly drop the transmitter so that
+    // the stream on the other side knows we're done.
+    drop(body_tx);
+    Ok(())
+}
+
+/// Swap in the configured error page for an app response with an empty body, if
+/// there's one for its status; see [`Settings::error_page`].
+fn with_error_page(
+    resp: http::Response<axum::body::Body>,
+    settings: &Settings,
+) -> http::Response<axum::body::Body> {
+    let Some(page) = settings
+        .error_pages
+        .iter()
+        .find(|page| page.status == resp.status())
+    else {
+        return resp;
     };
+    if HttpBody::size_hint(resp.body()).exact() != Some(0) {
+        return resp;
+    }
+    let (mut parts, _) = resp.into_parts();
+    // An explicit `Content-Length: 0` would be a lie now.
+    parts.headers.remove(http::header::CONTENT_LENGTH);
+    parts
+        .headers
+        .insert(http::header::CONTENT_TYPE, page.content_type.clone());
+    http::Response::from_parts(parts, axum::body::Body::from(page.body.clone()))
+}
 
+/// Massage an app's response into what the FastCGI Authorizer role expects; see
+/// [`Role::Authorizer`]. The spec only counts a literal 200 as authorized, but
+/// we're generous with the rest of the 2xx range.
+fn authorizer_response(resp: http::Response<axum::body::Body>) -> http::Response<axum::body::Body> {
+    if !resp.status().is_success() {
+        return resp;
+    }
+    let mut authorized = http::Response::new(axum::body::Body::empty());
+    for (name, value) in resp.headers() {
+        // HeaderName is always lowercase.
+        if name.as_str().starts_with("variable-") {
+            authorized.headers_mut().append(name.clone(), value.clone());
+        }
+    }
+    authorized
+}
+
+/// Dig the message out of a caught panic, if it had one. Panics made with a format
+/// string carry a String; ones with a plain literal carry a &str.
+fn panic_message(panic: &(dyn std::any::Any + Send)) -> &str {
+    if let Some(s) = panic.downcast_ref::<String>() {
+        s
+    } else if let Some(s) = panic.downcast_ref::<&'static str>() {
+        s
+    } else {
+        "(no message)"
+    }
+}
+
+/// Responses to HEAD requests aren't allowed to have a body, but handlers don't
+/// always get the memo (and axum only strips it when it's the one serving HTTP).
+/// So, drop the body here, but keep the headers describing it, including a
+/// Content-Length if we can tell what it would have been.
+fn head_response(resp: http::Response<axum::body::Body>) -> http::Response<axum::body::Body> {
+    let (mut parts, body) = resp.into_parts();
+    if !parts.headers.contains_key(http::header::CONTENT_LENGTH) {
+        if let Some(len) = HttpBody::size_hint(&body).exact() {
+            parts
+                .headers
+                .insert(http::header::CONTENT_LENGTH, http::HeaderValue::from(len));
+        }
+    }
+    http::Response::from_parts(parts, axum::body::Body::empty())
+}
+
+/// If the settings call for it, write a note about a failure to the FastCGI stderr
+/// stream, so it lands in the web server's error_log next to the request it was
+/// about. This is strictly a bonus on top of the tracing event, so if the write
+/// fails, we just shrug; the stdout side will find out soon enough if the
+/// connection's actually broken.
+async fn write_stderr_diagnostic<C>(req: &mut FcgiRequest<'_, C>, settings: &Settings, note: &str)
+where
+    C: TokioAsyncRead + TokioAsyncWrite + Send + 'static,
+{
+    if !settings.stderr_diagnostics {
+        return;
+    }
+    let w = req.output_stream(fastcgi_server::protocol::RecordType::Stderr);
     let mut buffered = BufWriter::new(w);
-    // If this write hits an error we literally can't write output anymore,
-    // so probably the connection's hosed; return an io::Error instead of an exit code.
-    trace!("writing app response as fcgi response");
-    write_http_response(&mut buffered, app_response).await?;
+    let line = format!("busride-rs: {}\n", note);
+    let written = async {
+        buffered.write_all(line.as_bytes()).await?;
+        buffered.flush().await
+    };
+    if let Err(e) = written.await {
+        debug!("couldn't write to fcgi stderr stream: {}", e);
+    }
+}
+
+/// A bodyless response with the given status.
+fn status_response(status: http::StatusCode) -> http::Response<axum::body::Body> {
+    let mut resp = http::Response::new(axum::body::Body::empty());
+    *resp.status_mut() = status;
+    resp
+}
 
-    // ok, done!
-    buffered.flush().await?;
-    trace!("finished writing fcgi response and flushing output");
+/// Reasons we might fail to turn a FastCGI request into an http::Request.
+#[derive(Debug)]
+enum RequestError {
+    /// Something (the URI, a header...) didn't parse.
+    Invalid(http::Error),
+    /// More headers, or more bytes of headers, than we're willing to accept.
+    HeadersTooLarge,
+    /// One header (named here, in CGI form) bigger than we're willing to accept.
+    HeaderTooLarge(String),
+}
+
+impl RequestError {
+    /// The status code to send the client about it.
+    fn status(&self) -> http::StatusCode {
+        match self {
+            Self::Invalid(_) => http::StatusCode::BAD_REQUEST,
+            Self::HeadersTooLarge | Self::HeaderTooLarge(_) => {
+                http::StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE
+            }
+        }
+    }
+}
 
-    Ok(ExitStatus::SUCCESS)
+impl std::fmt::Display for RequestError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Invalid(e) => e.fmt(f),
+            Self::HeadersTooLarge => f.write_str("request headers exceeded the configured limits"),
+            Self::HeaderTooLarge(name) => {
+                write!(f, "request header {} exceeded max_header_value_bytes", name)
+            }
+        }
+    }
 }
 
+impl From<http::Error> for RequestError {
+    fn from(e: http::Error) -> Self {
+        Self::Invalid(e)
+    }
+}
+
+impl From<http::uri::InvalidUri> for RequestError {
+    fn from(e: http::uri::InvalidUri) -> Self {
+        Self::Invalid(e.into())
+    }
+}
+
+/// The sending end of a request body, for whoever's reading it off the connection.
+type BodySender = mpsc::Sender<std::io::Result<BytesMut>>;
+
 /// Build an http::Request with a streaming body, and return it along with
-/// a sender handle for streaming bytes into the body.
+/// a sender handle for streaming bytes into the body. Requests that don't have a
+/// body (see [`expects_body`]) get an empty one instead, and no sender, so
+/// there's nothing to stream and nothing for the app to wait on.
 ///
 /// Errors: Returns an error if the resulting HTTP request wasn't valid,
 /// probably because the headers failed to parse; this probably means a bug in
 /// either fastcgi-server or the fastcgi client that sent the original request.
-fn http_request_from_fcgi_request(
-    req: &mut FcgiRequest<'_, '_, '_>,
-) -> Result<
-    (
-        http::Request<axum::body::Body>,
-        mpsc::UnboundedSender<std::io::Result<BytesMut>>,
-    ),
-    http::Error,
-> {
-    // About HTTP version: the web server might be speaking whatever, and
-    // cgi::SERVER_PROTOCOL will tell the truth about it. But over here
-    // across the fastcgi barrier, it's gonna ACT like h1 no matter what.
+/// Also returns an error if there were more headers than the settings allow.
+fn http_request_from_vars(
+    vars: FcgiVars,
+    settings: &Settings,
+    gateway: Gateway,
+) -> Result<(http::Request<axum::body::Body>, Option<BodySender>), RequestError> {
+    // About HTTP version: over here across the fastcgi barrier, everything
+    // ACTS like h1 no matter what. But the web server might be speaking
+    // whatever with the client, and apps that log or branch on the version
+    // want the truth, which cgi::SERVER_PROTOCOL tells.
     let mut h_req = http::Request::builder()
-        .version(http::Version::HTTP_11)
-        .method(req.get_var(cgi::REQUEST_METHOD).unwrap_or(b"GET"))
-        .uri(req.get_var(cgi::REQUEST_URI).unwrap_or(b"/"));
+        .version(http_version_from_vars(&vars))
+        .method(vars.get(cgi::REQUEST_METHOD).unwrap_or(b"GET"));
     // Special headers: content-type and content-length aren't prefixed w/ HTTP_
-    if let Some(v) = req.get_var(cgi::CONTENT_TYPE) {
+    if let Some(v) = vars.get(cgi::CONTENT_TYPE) {
         h_req = h_req.header("Content-Type", v);
     }
-    if let Some(v) = req.get_var(cgi::CONTENT_LENGTH) {
+    if let Some(v) = vars.get(cgi::CONTENT_LENGTH) {
         h_req = h_req.header("Content-Length", v);
     }
-    // But the rest of the headers all became vars prefixed w/ HTTP_
-    h_req = req.env_iter().fold(h_req, |memo, (k, v)| {
-        if k.as_ref().starts_with("HTTP_") {
-            let var_name = &k.as_ref()[5..];
-            // Env vars use underscore separators, but header names use hyphens.
-            let header_name = var_name.replace('_', "-");
-            // don't sweat the allcaps, http crate doesn't mind
-            memo.header(header_name, v)
-        } else {
-            memo
+    // But the rest of the headers all became vars prefixed w/ HTTP_. Since we're
+    // copying every one of them into the request, cap how many (and how much) we'll
+    // take, so a hostile or broken client can't make us balloon before the app even
+    // sees the request.
+    let mut header_count: usize = 0;
+    let mut header_bytes: usize = 0;
+    for (k, v) in vars.iter() {
+        let Some(var_name) = k.strip_prefix("HTTP_") else {
+            continue;
+        };
+        header_count += 1;
+        header_bytes = header_bytes.saturating_add(var_name.len() + v.len());
+        if header_count > settings.max_header_count || header_bytes > settings.max_header_bytes {
+            return Err(RequestError::HeadersTooLarge);
+        }
+        if v.len() > settings.max_header_value_bytes {
+            return Err(RequestError::HeaderTooLarge(k.to_string()));
         }
-    });
+        // Env vars use underscore separators, but header names use hyphens.
+        let header_name = var_name.replace('_', "-");
+        // don't sweat the allcaps, http crate doesn't mind.
+        // Repeated request headers arrive pre-joined into one var with ", " (that's
+        // what Apache does), which is equivalent for list-valued headers, so they
+        // pass through as one header. Cookie is the exception; see below.
+        h_req = if header_name == "COOKIE" {
+            h_req.header(header_name, rejoin_cookie_header(v))
+        } else {
+            h_req.header(header_name, v)
+        };
+    }
 
     // We use a channel, because the body needs an owned value as its stream.
-    // I'm using Unbounded, because... well, mostly because I'm Baby. I *suspect*
-    // Bounded is more correct, but I couldn't reason out what message limit
-    // would do the right thing with the BytesCodec we're using in the caller.
-    // LMK if you know why to use Bounded and what number to give it. 🌻
-    let (body_tx, body_rx) = mpsc::unbounded_channel();
+    // It's bounded, so a client that uploads faster than the app reads can't
+    // make us buffer the whole body in memory. Each message is one BytesCodec
+    // chunk (whatever a single read off the connection produced), so the bound
+    // is in chunks, not bytes; see Settings::body_channel_capacity.
+    let (body, body_tx) = if expects_body(&vars) {
+        let (body_tx, body_rx) = mpsc::channel(settings.body_channel_capacity.get());
+        let rx_stream = tokio_stream::wrappers::ReceiverStream::new(body_rx);
+        (axum::body::Body::from_stream(rx_stream), Some(body_tx))
+    } else {
+        (axum::body::Body::empty(), None)
+    };
+    let mut h_req = h_req.body(body)?;
+    // Then everything else we can glean from the CGI vars (the real URI and such);
+    // that part's shared with FcgiEnrichLayer.
+    enrich_request(&mut h_req, &vars, settings.path_from_path_info, gateway)?;
+    h_req.extensions_mut().insert(vars);
+    // Last of all, let the settings have their say.
+    let h_req = match &settings.on_request {
+        Some(hook) => {
+            let (mut parts, body) = h_req.into_parts();
+            hook(&mut parts);
+            http::Request::from_parts(parts, body)
+        }
+        None => h_req,
+    };
+    Ok((h_req, body_tx))
+}
+
+/// Whether a request has a body worth streaming. An explicit `CONTENT_LENGTH` of
+/// 0 means no. CGI says a missing one means no, too, but some web servers leave
+/// it off for chunked uploads and stream the body anyway, so we only take its
+/// absence at its word for GETs and HEADs, which don't have bodies to begin with.
+fn expects_body(vars: &FcgiVars) -> bool {
+    match vars.get(cgi::CONTENT_LENGTH) {
+        Some(len) => {
+            let len = std::str::from_utf8(len).ok().map(str::trim);
+            len.and_then(|len| len.parse::<u64>().ok()) != Some(0)
+        }
+        None => !matches!(vars.get(cgi::REQUEST_METHOD), None | Some(b"GET" | b"HEAD")),
+    }
+}
 
-    let rx_stream = tokio_stream::wrappers::UnboundedReceiverStream::new(body_rx);
-    let stream_body = axum::body::Body::from_stream(rx_stream);
-    h_req.body(stream_body).map(|b| (b, body_tx))
+/// The HTTP version the client used, per `SERVER_PROTOCOL`. Anything we don't
+/// recognize (or a missing var) counts as 1.1, which is what we act like anyway.
+fn http_version_from_vars(vars: &FcgiVars) -> http::Version {
+    match vars.get(cgi::SERVER_PROTOCOL) {
+        Some(b"HTTP/0.9") => http::Version::HTTP_09,
+        Some(b"HTTP/1.0") => http::Version::HTTP_10,
+        Some(b"HTTP/2" | b"HTTP/2.0") => http::Version::HTTP_2,
+        Some(b"HTTP/3" | b"HTTP/3.0") => http::Version::HTTP_3,
+        _ => http::Version::HTTP_11,
+    }
 }
 
-/// Use a provided http::Response to write a CGI/1.1 response to the provided AsyncWriter.
-async fn write_http_response(
-    out: impl AsyncWrite,
-    resp: http::Response<axum::body::Body>,
-) -> std::io::Result<()> {
-    tokio::pin!(out);
-
-    // TODO: there's probably a good way to dump these headers directly into the
-    // buffered AsyncWrite without the extra sync copy, but it doesn't seem urgent rn.
-    let mut response_headers_bytes: Vec<u8> = Vec::new();
-    cgi::response::http_headers(&mut response_headers_bytes, &resp)?;
-    trace!("writing fcgi response headers...");
-    out.write_all(&response_headers_bytes).await?;
+/// The web server joins repeated request headers with commas, but Cookie uses
+/// semicolons to separate its pairs; `a=1, b=2` would look like one cookie named
+/// "a" with a value of "1, b=2". Since compliant cookie values can't contain commas,
+/// we can safely re-split them and rejoin with the proper separator.
+fn rejoin_cookie_header(v: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(v.len());
+    for (i, pair) in v.split(|b| *b == b',').enumerate() {
+        if i > 0 {
+            out.extend_from_slice(b"; ");
+        }
+        out.extend_from_slice(pair.trim_ascii());
+    }
+    out
+}
+
+/// Whether a CGI header block includes a `Status:` header.
+fn has_status_line(headers: &[u8]) -> bool {
+    headers
+        .split(|b| *b == b'\n')
+        .any(|line| line.len() >= 7 && line[..7].eq_ignore_ascii_case(b"status:"))
+}
+
+/// Whether a response body is meant to arrive bit by bit, like Server-Sent Events
+/// or some other open-ended stream. Those need flushing after every chunk, or else
+/// the output buffer sits on them and the client sees nothing until the end. We
+/// assume anything without a known length is a stream; ordinary bodies (strings,
+/// JSON, files read into memory) all know their length up front.
+fn is_streaming_response<B: HttpBody>(resp: &http::Response<B>) -> bool {
+    let event_stream = resp
+        .headers()
+        .get(http::header::CONTENT_TYPE)
+        .is_some_and(|v| v.as_bytes().starts_with(b"text/event-stream"));
+    let unknown_length = !resp.headers().contains_key(http::header::CONTENT_LENGTH)
+        && HttpBody::size_hint(resp.body()).exact().is_none();
+    event_stream || unknown_length
+}
+
+/// How much of a response we managed to write.
+#[derive(Debug, Clone, Copy, Default)]
+struct ResponseSize {
+    /// The CGI header block, including the blank line that ends it.
+    header_bytes: u64,
+    body_bytes: u64,
+}
+
+/// The parts of the settings that affect how we write responses.
+#[derive(Clone, Copy)]
+struct ResponseOptions<'a> {
+    /// Echo the request's ID; see [`Settings::request_id_header`].
+    request_id: Option<&'a RequestId>,
+    /// Add a `Date`; see [`Settings::date_header`].
+    date: bool,
+    /// Log everything we write; see [`Settings::dump_bytes`].
+    dump_bytes: bool,
+    /// Let the settings touch up the response; see [`Settings::on_response`].
+    on_response: Option<&'a settings::ResponseHookFn>,
+    /// The request was a HEAD, so the body's just an empty stand-in, and its length
+    /// says nothing about the real one.
+    head: bool,
+    /// How much to buffer output; see [`Settings::response_buffer`].
+    buffer: usize,
+    /// It's an Authorizer's verdict (see [`Role::Authorizer`]), which the web
+    /// server only reads for the status and `Variable-*` headers, so an authorized
+    /// one is a bare 200 on purpose.
+    authorizer: bool,
+}
+
+impl<'a> ResponseOptions<'a> {
+    /// The options for responding to a request, per the settings and its vars.
+    fn new(
+        settings: &'a Settings,
+        request_id: Option<&'a RequestId>,
+        vars: &FcgiVars,
+        gateway: Gateway,
+    ) -> Self {
+        Self {
+            request_id,
+            date: settings.date_header,
+            dump_bytes: settings.dump_bytes,
+            on_response: settings.on_response.as_deref(),
+            head: vars.get(cgi::REQUEST_METHOD) == Some(b"HEAD"),
+            buffer: settings.response_buffer.get(),
+            // Roles are a FastCGI thing; the other transports only have responders.
+            authorizer: gateway == Gateway::FastCgi && settings.role == Role::Authorizer,
+        }
+    }
+
+    /// Add the headers we're responsible for, when the app didn't set them itself.
+    fn add_headers(&self, headers: &mut http::HeaderMap) {
+        if let Some(id) = self.request_id {
+            if !headers.contains_key(&id.header) {
+                headers.insert(id.header.clone(), id.value.clone());
+            }
+        }
+        if self.date && !headers.contains_key(http::header::DATE) {
+            let now = http_date(SystemTime::now());
+            if let Ok(value) = http::HeaderValue::try_from(now) {
+                headers.insert(http::header::DATE, value);
+            }
+        }
+    }
+}
+
+/// Give a response a `Content-Length` if its body knows how long it is and the app
+/// didn't say. Without one, the web server has to mark the end of the body some
+/// other way (chunking it, or closing the client's connection), and the latter
+/// costs the client its keep-alive. Responses that can't have a body, or that
+/// already picked a framing, are left alone; so are streams, which don't know.
+fn add_content_length<B: HttpBody>(resp: &mut http::Response<B>) {
+    let status = resp.status();
+    let bodyless = status.is_informational()
+        || status == http::StatusCode::NO_CONTENT
+        || status == http::StatusCode::NOT_MODIFIED;
+    let headers = resp.headers();
+    if bodyless
+        || headers.contains_key(http::header::CONTENT_LENGTH)
+        || headers.contains_key(http::header::TRANSFER_ENCODING)
+    {
+        return;
+    }
+    if let Some(len) = HttpBody::size_hint(resp.body()).exact() {
+        resp.headers_mut()
+            .insert(http::header::CONTENT_LENGTH, http::HeaderValue::from(len));
+    }
+}
+
+/// Format a time the way HTTP wants it in `Date` headers (RFC 7231's IMF-fixdate),
+/// like `Sun, 06 Nov 1994 08:49:37 GMT`. Times before 1970 come out as 1970,
+/// which doesn't matter for "now".
+fn http_date(time: SystemTime) -> String {
+    const WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let secs = time
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    let days = secs / 86400;
+    let (hour, min, sec) = (secs % 86400 / 3600, secs % 3600 / 60, secs % 60);
+    // Turning days into a calendar date is Howard Hinnant's civil_from_days, minus
+    // the parts for negative days: count in 400-year eras starting from March 1st,
+    // so leap days land at the end of the year.
+    let z = days + 719468;
+    let era = z / 146097;
+    let doe = z % 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        WEEKDAYS[(days % 7) as usize],
+        day,
+        MONTHS[(month - 1) as usize],
+        year,
+        hour,
+        min,
+        sec
+    )
+}
+
+/// Use a provided http::Response to write a CGI/1.1 response to the provided AsyncWriter,
+/// after adding any headers `opts` calls for that the app didn't set itself. Other than that, the
+/// headers and body pass through untouched; in particular, we never second-guess an
+/// app's `Content-Encoding` or `Content-Length`.
+/// Flushes `out` at the end (and as it goes, if the response looks like a
+/// stream), and returns how many bytes of headers and body were written.
+///
+/// If the client hangs up partway through, we get a broken pipe or a reset
+/// instead. That's routine (they closed the tab, or the web server gave up on
+/// them), not the app's fault, so it only gets a debug event, and we return
+/// whatever we managed to write as if that were the whole thing; the connection's
+/// gone either way, and whoever's reading from it will find that out soon enough.
+///
+/// If the app's body errors out instead, that's an Err, same as a broken
+/// connection. By then the headers are already written, so there's no sending a
+/// clean error response; the caller should give up on the connection rather than
+/// end the request normally, so the web server sees the response was cut short.
+///
+/// This only cares that the body is an http_body::Body of Bytes, not that it's
+/// an axum one, so it stays usable if we ever branch out beyond axum.
+async fn write_http_response<B, W>(
+    out: &mut ResponseBuffer<W>,
+    resp: http::Response<B>,
+    opts: &ResponseOptions<'_>,
+) -> std::io::Result<ResponseSize>
+where
+    B: HttpBody<Data = Bytes>,
+    B::Error: Into<BoxError>,
+    W: AsyncWrite + Unpin,
+{
+    let mut size = ResponseSize::default();
+    match write_http_response_counting(out, resp, opts, &mut size).await {
+        Err(e) if is_client_disconnect(&e) => {
+            debug!(
+                blame = "client",
+                header_bytes = size.header_bytes,
+                body_bytes = size.body_bytes,
+                "Client went away mid-response: {}",
+                e
+            );
+            Ok(size)
+        }
+        written => written.map(|()| size),
+    }
+}
+
+/// Whether a write error just means the other end hung up on us.
+fn is_client_disconnect(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::BrokenPipe
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+    )
+}
+
+/// Does the actual work of [`write_http_response`], keeping `size` up to date as
+/// it goes so there's still a count if it doesn't make it to the end.
+async fn write_http_response_counting<B, W>(
+    out: &mut ResponseBuffer<W>,
+    mut resp: http::Response<B>,
+    opts: &ResponseOptions<'_>,
+    size: &mut ResponseSize,
+) -> std::io::Result<()>
+where
+    B: HttpBody<Data = Bytes>,
+    B::Error: Into<BoxError>,
+    W: AsyncWrite + Unpin,
+{
+    opts.add_headers(resp.headers_mut());
+    if !opts.head {
+        add_content_length(&mut resp);
+    }
+    if let Some(hook) = opts.on_response {
+        let (mut parts, body) = resp.into_parts();
+        hook(&mut parts);
+        resp = http::Response::from_parts(parts, body);
+    }
+    let streaming = is_streaming_response(&resp);
+
+    // http_headers only knows how to write to a sync Write, so the headers go
+    // straight into the output buffer (a Vec is a Write), and head out with the
+    // first of the body. Note that http_headers writes a separate line for each
+    // value in the HeaderMap, so multi-valued response headers like Set-Cookie come
+    // out intact. (CGI has no problem with repeated header lines; the web server
+    // passes them all through.)
+    let headers_start = out.buffer_mut().len();
+    cgi::response::http_headers(out.buffer_mut(), &resp)?;
+    // CGI wants every response to have at least one of Content-Type, Location, or
+    // Status, and web servers are within their rights to reject one that doesn't
+    // (Apache calls it a "malformed header from script" and sends a 500). The only
+    // way to end up there is a 200 with neither of the other two, so patch in the
+    // most noncommittal content type there is. Except for an Authorizer saying yes,
+    // which is supposed to look like that, and which the web server doesn't pass on.
+    let headers = resp.headers();
+    if !opts.authorizer
+        && !has_status_line(&out.buffer_mut()[headers_start..])
+        && !headers.contains_key(http::header::CONTENT_TYPE)
+        && !headers.contains_key(http::header::LOCATION)
+    {
+        warn!(
+            blame = "app",
+            status = resp.status().as_u16(),
+            "Response has no Content-Type, so the web server might reject it; using application/octet-stream"
+        );
+        resp.headers_mut().insert(
+            http::header::CONTENT_TYPE,
+            http::HeaderValue::from_static("application/octet-stream"),
+        );
+        out.buffer_mut().truncate(headers_start);
+        cgi::response::http_headers(out.buffer_mut(), &resp)?;
+    }
+    let response_headers_bytes = &out.buffer_mut()[headers_start..];
+    // The web server only learns the real status from the CGI Status header, and
+    // assumes 200 without one, so a missing one would quietly turn every 404 into
+    // a success. Double-check fastcgi-server's work in debug builds.
+    debug_assert!(
+        resp.status() == http::StatusCode::OK || has_status_line(response_headers_bytes),
+        "CGI headers for a {} response are missing the Status line",
+        resp.status()
+    );
+    if opts.dump_bytes {
+        trace!(
+            len = response_headers_bytes.len(),
+            data = %response_headers_bytes.escape_ascii(),
+            "response header bytes"
+        );
+    }
+    size.header_bytes = response_headers_bytes.len() as u64;
+    if streaming {
+        // Let the client know we're alive before the first event shows up.
+        out.flush().await?;
+    }
     trace!("done writing fcgi response headers");
 
-    // Response body can become a stream of Bytes
-    let mut body_stream = resp.into_body().into_data_stream();
+    // Go frame by frame instead of using into_data_stream(), which would quietly
+    // throw away any trailers.
+    let resp_status = resp.status();
+    let mut body = std::pin::pin!(resp.into_body());
     trace!("starting to write fcgi response body");
-    while let Some(maybe_hunk) = body_stream.next().await {
-        match maybe_hunk {
-            Ok(hunk) => {
+    while let Some(maybe_frame) =
+        futures_util::future::poll_fn(|cx| body.as_mut().poll_frame(cx)).await
+    {
+        match maybe_frame.map(http_body::Frame::into_data) {
+            Ok(Ok(hunk)) => {
                 trace!("writing bytes...");
+                if opts.dump_bytes {
+                    trace!(len = hunk.len(), data = %hunk.escape_ascii(), "response body bytes");
+                }
                 // Bytes does a Deref to [u8], so
                 out.write_all(&hunk).await?;
+                if streaming {
+                    out.flush().await?;
+                }
+                size.body_bytes += hunk.len() as u64;
+            }
+            Ok(Err(frame)) => {
+                // CGI responses are headers and a body, period; there's nowhere to
+                // put trailers, and the web server chunks the body however it likes.
+                // The rest of the response is still good, so just say what we lost.
+                if let Some(trailers) = frame.trailers_ref() {
+                    let names: Vec<&str> = trailers.keys().map(|k| k.as_str()).collect();
+                    warn!(
+                        blame = "app",
+                        ?names,
+                        "Response has trailers, which CGI can't carry; dropping them"
+                    );
+                }
             }
             Err(e) => {
-                // Literally couldn't write what we wanted to the output stream, so
-                // return Err and make em start a new connection.
-                error!(blame = "app", "Error reading reponse body from app: {}", e);
+                // The app's body broke (a file it was streaming went away, say),
+                // but the headers and maybe some of the body are already out, so
+                // it's too late for a 500. The best we can do is make sure nobody
+                // mistakes this for the whole response: returning Err means we
+                // never end the request properly, and the connection gets dropped
+                // instead, so the web server knows it's incomplete.
+                let e: BoxError = e.into();
+                error!(
+                    blame = "app",
+                    status = resp_status.as_u16(),
+                    header_bytes = size.header_bytes,
+                    body_bytes = size.body_bytes,
+                    "App's response body failed partway through; the client gets a truncated response: {}",
+                    e
+                );
                 return Err(std::io::Error::other(e));
             }
         }
     }
     trace!("finished writing fcgi response body");
+    out.flush().await
+}
 
-    Ok(())
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn http_version_comes_from_server_protocol() {
+        let cases: &[(Option<&'static str>, http::Version)] = &[
+            (Some("HTTP/1.0"), http::Version::HTTP_10),
+            (Some("HTTP/1.1"), http::Version::HTTP_11),
+            (Some("HTTP/2.0"), http::Version::HTTP_2),
+            (Some("HTTP/2"), http::Version::HTTP_2),
+            (Some("HTTP/3"), http::Version::HTTP_3),
+            (Some("HTTP/0.9"), http::Version::HTTP_09),
+            // Anything else is 1.1, which is how we treat every request anyway.
+            (None, http::Version::HTTP_11),
+            (Some(""), http::Version::HTTP_11),
+            (Some("garbage"), http::Version::HTTP_11),
+            (Some("INCLUDED"), http::Version::HTTP_11),
+        ];
+        for (protocol, expected) in cases {
+            let vars: FcgiVars = protocol
+                .iter()
+                .map(|p| (cgi::SERVER_PROTOCOL, *p))
+                .collect();
+            assert_eq!(
+                http_version_from_vars(&vars),
+                *expected,
+                "for SERVER_PROTOCOL {:?}",
+                protocol
+            );
+        }
+    }
+
+    #[test]
+    fn http_dates_look_like_imf_fixdates() {
+        let at = |secs| SystemTime::UNIX_EPOCH + Duration::from_secs(secs);
+        // RFC 7231's own example.
+        assert_eq!(http_date(at(784111777)), "Sun, 06 Nov 1994 08:49:37 GMT");
+        assert_eq!(http_date(at(0)), "Thu, 01 Jan 1970 00:00:00 GMT");
+        // A leap day, and the last second of a year.
+        assert_eq!(http_date(at(951782400)), "Tue, 29 Feb 2000 00:00:00 GMT");
+        assert_eq!(http_date(at(1735689599)), "Tue, 31 Dec 2024 23:59:59 GMT");
+    }
+
+    #[test]
+    fn bind_errors_say_where() {
+        // Something that's in the way and isn't a socket, which we won't clear away.
+        let path = std::env::temp_dir().join(format!("busride-bind-test-{}", std::process::id()));
+        std::fs::write(&path, b"not a socket").unwrap();
+        let err = match bind_unix_listener(&path, 0o660, None, None) {
+            Err(e) => e,
+            Ok(_) => panic!("bound over a regular file"),
+        };
+        std::fs::remove_file(&path).unwrap();
+        let message = err.to_string();
+        assert!(matches!(err, ServeError::Bind(..)), "{}", message);
+        assert!(message.contains(&*path.to_string_lossy()), "{}", message);
+    }
+
+    /// Adopting a descriptor that's wrong for us has to fail, and leave the
+    /// descriptor open for whoever really owns it.
+    fn refuse_to_adopt(fd: RawFd) -> ServeError {
+        let err = match adopt_unix_listener(fd) {
+            Err(e) => e,
+            Ok(_) => panic!("adopted fd {}, which isn't a listening Unix socket", fd),
+        };
+        assert!(dup_for_inspection(fd).is_ok(), "fd {} got closed", fd);
+        err
+    }
+
+    #[test]
+    fn adopting_a_regular_file_says_its_not_a_socket() {
+        let file = std::fs::File::open("/dev/null").unwrap();
+        let fd = file.as_raw_fd();
+        assert!(matches!(refuse_to_adopt(fd), ServeError::NotASocket(f) if f == fd));
+    }
+
+    #[test]
+    fn adopting_the_wrong_kind_of_socket_says_what_kind_it_is() {
+        let tcp = std::net::TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let fd = tcp.as_raw_fd();
+        let err = refuse_to_adopt(fd);
+        assert!(
+            matches!(err, ServeError::WrongKindOfSocket(f, "not a Unix domain socket") if f == fd),
+            "{:?}",
+            err
+        );
+
+        let (unix, _other_end) = std::os::unix::net::UnixStream::pair().unwrap();
+        let fd = unix.as_raw_fd();
+        let err = refuse_to_adopt(fd);
+        assert!(
+            matches!(err, ServeError::WrongKindOfSocket(f, "not in listening mode") if f == fd),
+            "{:?}",
+            err
+        );
+
+        let (datagram, _other_end) = std::os::unix::net::UnixDatagram::pair().unwrap();
+        let fd = datagram.as_raw_fd();
+        let err = refuse_to_adopt(fd);
+        assert!(
+            matches!(err, ServeError::WrongKindOfSocket(f, p) if f == fd && p.contains("SOCK_DGRAM")),
+            "{:?}",
+            err
+        );
+    }
+
+    #[tokio::test]
+    async fn adopting_a_listening_unix_socket_works() {
+        let path = std::env::temp_dir().join(format!("busride-adopt-test-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let fd = StdUnixListener::bind(&path).unwrap().into_raw_fd();
+        let adopted = adopt_unix_listener(fd);
+        std::fs::remove_file(&path).unwrap();
+        // The listener owns the fd now, and closes it when it drops.
+        assert_eq!(adopted.unwrap().as_raw_fd(), fd);
+    }
+
+    #[test]
+    fn systemd_activation_is_checked_like_sd_listen_fds() {
+        // All in one test, since the environment is shared with every other test
+        // thread. Nothing else looks at these vars.
+        fn refused() -> String {
+            match systemd_listen_fd() {
+                Err(ServeError::NotSocketActivated(why)) => why,
+                other => panic!("expected NotSocketActivated, got {:?}", other),
+            }
+        }
+        let us = std::process::id().to_string();
+
+        std::env::remove_var("LISTEN_PID");
+        std::env::remove_var("LISTEN_FDS");
+        assert!(refused().contains("LISTEN_PID is missing"));
+
+        std::env::set_var("LISTEN_PID", "1x");
+        assert!(refused().contains("LISTEN_PID isn't a number"));
+
+        // Meant for our parent, say, and inherited by accident.
+        std::env::set_var("LISTEN_PID", (std::process::id() + 1).to_string());
+        assert!(refused().contains("but we're process"));
+
+        std::env::set_var("LISTEN_PID", &us);
+        assert!(refused().contains("LISTEN_FDS is missing"));
+
+        std::env::set_var("LISTEN_FDS", "0");
+        assert!(refused().contains("LISTEN_FDS is 0"));
+
+        std::env::set_var("LISTEN_FDS", "2");
+        assert_eq!(systemd_listen_fd().unwrap(), SYSTEMD_LISTEN_FDS_START);
+
+        std::env::remove_var("LISTEN_PID");
+        std::env::remove_var("LISTEN_FDS");
+    }
 }
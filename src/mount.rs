@@ -0,0 +1,227 @@
+//! Serving an app written for the root of a domain from somewhere else, without
+//! the app having to know where.
+use crate::ScriptName;
+use axum::BoxError;
+use bytes::Bytes;
+use futures_util::future::BoxFuture;
+use http_body::Body as HttpBody;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tower::{Layer, Service};
+
+/// A tower layer that mounts an app at a sub-path: it strips the mount point off
+/// the front of each request's path before the app sees it, so an app whose
+/// routes all assume it lives at `/` works unchanged at `/some/dir/`. The mount
+/// point goes in a [`ScriptName`] request extension (for building links back to
+/// yourself), the untouched URI goes in `axum::extract::OriginalUri`, and requests
+/// for paths outside the mount point get a `404 Not Found` without reaching the app.
+///
+/// It only looks at the request's URI, so it works the same over FastCGI as over
+/// plain HTTP. That makes it the easy way to write a dual-mode app that can be
+/// mounted anywhere: wrap the whole app (not just its routes; apply it with
+/// [`Layer::layer`], not `Router::layer`, since the path has to change before
+/// routing happens) and serve the result either way.
+///
+/// ```ignore
+/// use tower::Layer;
+/// let app = busride_rs::MountLayer::new("/some/dir/").layer(my_app());
+/// // In FastCGI mode:
+/// busride_rs::serve_fcgid(app.clone(), 50.try_into().unwrap()).await?;
+/// // In HTTP mode, axum::serve wants a make-service:
+/// use axum::ServiceExt;
+/// axum::serve(listener, app.into_make_service()).await?;
+/// ```
+///
+/// If your web server splits the path into `SCRIPT_NAME` and `PATH_INFO` the way
+/// you'd expect, [`Settings::path_from_path_info`](crate::Settings::path_from_path_info)
+/// gets you the same de-nesting in FastCGI mode without naming the mount point
+/// at all. This is for when it doesn't, or when you want the same behavior in
+/// both modes.
+#[derive(Debug, Clone)]
+pub struct MountLayer {
+    /// The mount point, with a leading slash and no trailing one. Empty means the
+    /// root, where there's nothing to strip.
+    prefix: Arc<str>,
+}
+
+impl MountLayer {
+    /// Mount at the given path. Leading and trailing slashes are optional; `"/"`
+    /// or `""` mean the root, which makes this a no-op.
+    pub fn new(mount_point: &str) -> Self {
+        let trimmed = mount_point.trim_matches('/');
+        let prefix = if trimmed.is_empty() {
+            String::new()
+        } else {
+            format!("/{}", trimmed)
+        };
+        Self {
+            prefix: prefix.into(),
+        }
+    }
+}
+
+impl<S> Layer<S> for MountLayer {
+    type Service = Mount<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        Mount {
+            inner,
+            prefix: self.prefix.clone(),
+        }
+    }
+}
+
+/// The service made by [`MountLayer`].
+#[derive(Debug, Clone)]
+pub struct Mount<S> {
+    inner: S,
+    prefix: Arc<str>,
+}
+
+impl<S, ReqBody, B> Service<http::Request<ReqBody>> for Mount<S>
+where
+    S: Service<http::Request<ReqBody>, Response = http::Response<B>>,
+    S::Future: Send + 'static,
+    B: HttpBody<Data = Bytes> + Send + 'static,
+    B::Error: Into<BoxError>,
+{
+    type Response = http::Response<axum::body::Body>;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: http::Request<ReqBody>) -> Self::Future {
+        if !self.prefix.is_empty() {
+            let Some(uri) = strip_mount_point(req.uri(), &self.prefix) else {
+                let mut resp = http::Response::new(axum::body::Body::empty());
+                *resp.status_mut() = http::StatusCode::NOT_FOUND;
+                return Box::pin(async move { Ok(resp) });
+            };
+            let original = std::mem::replace(req.uri_mut(), uri);
+            let extensions = req.extensions_mut();
+            if extensions.get::<axum::extract::OriginalUri>().is_none() {
+                extensions.insert(axum::extract::OriginalUri(original));
+            }
+            // Stack on top of whatever SCRIPT_NAME already took off, if anything.
+            let script_name = match extensions.remove::<ScriptName>() {
+                Some(ScriptName(outer)) => {
+                    format!("{}{}", outer.trim_end_matches('/'), self.prefix)
+                }
+                None => self.prefix.to_string(),
+            };
+            extensions.insert(ScriptName(script_name));
+        }
+        let fut = self.inner.call(req);
+        Box::pin(async move { fut.await.map(|resp| resp.map(axum::body::Body::new)) })
+    }
+}
+
+/// The same URI, minus the mount point at the start of its path. None if the path
+/// isn't under the mount point (`/dir` is under `/dir`, but `/directory` isn't).
+fn strip_mount_point(uri: &http::Uri, prefix: &str) -> Option<http::Uri> {
+    let rest = uri.path().strip_prefix(prefix)?;
+    let rest = match rest {
+        "" => "/",
+        r if r.starts_with('/') => r,
+        _ => return None,
+    };
+    let path_and_query = match uri.query() {
+        Some(query) => format!("{}?{}", rest, query),
+        None => rest.to_string(),
+    };
+    let mut parts = uri.clone().into_parts();
+    parts.path_and_query = Some(path_and_query.parse().ok()?);
+    http::Uri::from_parts(parts).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strip(uri: &str, mount_point: &str) -> Option<String> {
+        let layer = MountLayer::new(mount_point);
+        strip_mount_point(&uri.parse().unwrap(), &layer.prefix).map(|uri| uri.to_string())
+    }
+
+    /// Send a request through a mounted app that reports what it was handed.
+    async fn mounted(mount_point: &str, uri: &str) -> (http::StatusCode, String) {
+        let app = axum::Router::new().fallback(|req: axum::extract::Request| async move {
+            let script_name = req.extensions().get::<ScriptName>().map(|s| s.0.clone());
+            format!("{} {:?}", req.uri(), script_name)
+        });
+        let mut svc = MountLayer::new(mount_point).layer(app);
+        let req = http::Request::get(uri)
+            .body(axum::body::Body::empty())
+            .unwrap();
+        let resp = svc.call(req).await.unwrap();
+        let status = resp.status();
+        let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[test]
+    fn the_mount_point_itself_is_the_root() {
+        assert_eq!(strip("/dir", "/dir").as_deref(), Some("/"));
+        assert_eq!(strip("/dir?q=1", "/dir").as_deref(), Some("/?q=1"));
+    }
+
+    #[test]
+    fn paths_under_the_mount_point_lose_it() {
+        assert_eq!(strip("/dir/a/b", "/dir").as_deref(), Some("/a/b"));
+        assert_eq!(strip("/dir/a?q=1&x", "/dir").as_deref(), Some("/a?q=1&x"));
+        assert_eq!(strip("/some/dir/a", "/some/dir").as_deref(), Some("/a"));
+    }
+
+    #[test]
+    fn paths_outside_the_mount_point_dont_match() {
+        assert_eq!(strip("/directory", "/dir"), None);
+        assert_eq!(strip("/other/dir", "/dir"), None);
+        assert_eq!(strip("/", "/dir"), None);
+    }
+
+    #[test]
+    fn trailing_slashes_are_optional() {
+        // On the mount point, that is; on the request, it's the app's business.
+        assert_eq!(strip("/dir/", "/dir/").as_deref(), Some("/"));
+        assert_eq!(strip("/dir/", "/dir").as_deref(), Some("/"));
+        assert_eq!(strip("/dir", "/dir/").as_deref(), Some("/"));
+        assert_eq!(strip("/dir/a/", "dir").as_deref(), Some("/a/"));
+    }
+
+    #[test]
+    fn encoded_paths_are_compared_as_sent() {
+        // The rest of the path stays encoded, for the app to decode.
+        assert_eq!(strip("/dir/a%20b", "/dir").as_deref(), Some("/a%20b"));
+        // An encoded slash isn't a path separator, so it doesn't end the mount point.
+        assert_eq!(strip("/dir%2Fa", "/dir"), None);
+        // And a mount point spelled with escapes doesn't count as a match. The web
+        // server hands us the URI the client sent, so we don't guess at it.
+        assert_eq!(strip("/d%69r/a", "/dir"), None);
+    }
+
+    #[tokio::test]
+    async fn the_app_sees_the_stripped_path_and_a_script_name() {
+        let (status, body) = mounted("/dir/", "/dir/a?q=1").await;
+        assert_eq!(status, http::StatusCode::OK);
+        assert_eq!(body, r#"/a?q=1 Some("/dir")"#);
+    }
+
+    #[tokio::test]
+    async fn requests_outside_the_mount_point_get_404() {
+        let (status, body) = mounted("/dir", "/directory").await;
+        assert_eq!(status, http::StatusCode::NOT_FOUND);
+        assert_eq!(body, "");
+    }
+
+    #[tokio::test]
+    async fn the_root_mount_point_changes_nothing() {
+        let (status, body) = mounted("/", "/a/b").await;
+        assert_eq!(status, http::StatusCode::OK);
+        assert_eq!(body, "/a/b None");
+    }
+}
@@ -0,0 +1,58 @@
+//! Hooks for collecting per-request metrics.
+use crate::Role;
+use std::time::Duration;
+
+/// Gets told about every request as it starts and finishes, so you can feed
+/// counters and latency histograms to `metrics`, `prometheus`, or whatever
+/// you're into. Register one with [`Settings::observer`](crate::Settings::observer).
+///
+/// These get called inline on the task serving the request, so keep them quick.
+/// Both methods do nothing by default, so implement whichever ones you need.
+pub trait RequestObserver: Send + Sync + 'static {
+    /// Called when a request arrives, before we've done anything with it.
+    fn request_started(&self, info: &RequestStarted) {
+        let _ = info;
+    }
+
+    /// Called once we're done with a request, successfully or otherwise.
+    fn request_finished(&self, info: &RequestFinished) {
+        let _ = info;
+    }
+}
+
+/// Details about a request that just arrived.
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct RequestStarted {
+    /// The role the app is serving in.
+    pub role: Role,
+}
+
+/// Details about a request we just finished with.
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct RequestFinished {
+    /// The role the app is serving in.
+    pub role: Role,
+    /// The status code we sent. None if we never got as far as sending a response
+    /// (because the request was unservable, or the connection died).
+    pub status: Option<http::StatusCode>,
+    /// How long the whole thing took, from arrival to the last byte of response.
+    pub elapsed: Duration,
+}
+
+/// How a request served by [`serve_one_fcgi_request`](crate::serve_one_fcgi_request)
+/// went, by the numbers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct RequestStats {
+    /// The status code we sent. None if we never got as far as sending a response.
+    pub status: Option<http::StatusCode>,
+    /// How much of the request body we read. Anything past our limits (or the
+    /// request's own Content-Length) doesn't count.
+    pub bytes_in: u64,
+    /// How much response we wrote, CGI headers and body both.
+    pub bytes_out: u64,
+    /// How long the whole thing took, from arrival to the last byte of response.
+    pub elapsed: Duration,
+}
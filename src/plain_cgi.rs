@@ -0,0 +1,147 @@
+//! Plain old CGI, where the web server starts a fresh process for every request,
+//! hands it the request in environment variables and stdin, and reads the
+//! response back from stdout. It's slow, but it's the one thing every shared host
+//! supports, and it's a handy fallback for when FastCGI isn't set up yet.
+use crate::response_buffer::ResponseBuffer;
+use crate::scgi::serve_scgi_request;
+use crate::{FcgiVars, Gateway, ServerHandle, Settings};
+use axum::BoxError;
+use bytes::Bytes;
+use http_body::Body as HttpBody;
+use std::io;
+use std::os::unix::ffi::OsStringExt;
+use tokio_util::compat::{TokioAsyncReadCompatExt, TokioAsyncWriteCompatExt};
+use tower::Service;
+use tracing::trace;
+
+/// Serve exactly one request as a plain CGI program: read the CGI vars from our
+/// environment and the body from stdin, run the app, and write the response to
+/// stdout. Returns once the response is out, and then your `main` should just
+/// return too, since the web server waits for us to exit before it's done.
+///
+/// ```ignore
+/// #[tokio::main(flavor = "current_thread")]
+/// async fn main() -> std::io::Result<()> {
+///     busride_rs::serve_cgi_once(app(), busride_rs::Settings::new()).await
+/// }
+/// ```
+///
+/// Every request pays for starting the process and building the runtime and the
+/// app, so this is for low-traffic apps and for hosts without FastCGI. A
+/// single-threaded runtime (or [`tame_current_thread_runtime`](crate::tame_current_thread_runtime))
+/// makes more sense than a full one, since there's only ever one request.
+///
+/// The app sees the same requests it would over FastCGI or SCGI, with a
+/// [`TransportInfo`](crate::TransportInfo) of [`Gateway::Cgi`]. [`Settings`]
+/// apply the way they do for SCGI (see [`FcgiServer::scgi_tcp`](crate::FcgiServer::scgi_tcp)).
+/// Our whole environment goes into the request's [`FcgiVars`], so it'll have
+/// things like `PATH` in it too, besides what the web server set.
+///
+/// **Keep your logs off stdout.** That's where the response goes, so anything else
+/// written there (a `tracing_subscriber::fmt()` subscriber with its default writer,
+/// say, or a stray `println!`) ends up mixed into it, and the web server will
+/// probably throw out the whole response as malformed. Log to stderr instead,
+/// which the web server puts in its error log:
+///
+/// ```ignore
+/// tracing_subscriber::fmt().with_writer(std::io::stderr).init();
+/// ```
+///
+/// Errors: Returns an error if stdin or stdout broke partway through.
+pub async fn serve_cgi_once<S, B>(app: S, settings: Settings) -> io::Result<()>
+where
+    S: Service<http::Request<axum::body::Body>, Response = http::Response<B>> + Send,
+    S::Future: Send,
+    S::Error: Into<BoxError>,
+    B: HttpBody<Data = Bytes> + Send + 'static,
+    B::Error: Into<BoxError>,
+{
+    // Names have to be UTF-8 to be vars at all, and the web server only sets
+    // ASCII ones, so anything else in the environment isn't ours to worry about.
+    let env = std::env::vars_os().filter_map(|(k, v)| Some((k.into_string().ok()?, v.into_vec())));
+    let vars = FcgiVars::from_env(env);
+    trace!(target: "busride_rs", "Read CGI vars from the environment");
+    let stdin = tokio::io::stdin().compat();
+    let stdout = ResponseBuffer::new(
+        settings.response_buffer.get(),
+        tokio::io::stdout().compat_write(),
+    );
+    // No FcgiServer here, so a handle of our own, with no ready_when to wait on.
+    let handle = ServerHandle::default();
+    serve_scgi_request(app, &settings, &handle, stdin, stdout, vars, Gateway::Cgi).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TransportInfo;
+    use axum::routing::post;
+    use axum::Router;
+
+    /// Serve one request the way `serve_cgi_once` would, but with the environment,
+    /// stdin, and stdout all in memory, and hand back what went to stdout.
+    async fn serve(env: &[(&str, &str)], stdin: &[u8]) -> String {
+        let app = Router::new().route(
+            "/cgi",
+            post(|req: axum::extract::Request| async move {
+                let transport = req.extensions().get::<TransportInfo>().cloned();
+                let body = axum::body::to_bytes(req.into_body(), usize::MAX)
+                    .await
+                    .unwrap();
+                format!("{:?} {:?}", transport, body)
+            }),
+        );
+        let vars = FcgiVars::from_env(env.iter().copied());
+        let mut stdout = Vec::new();
+        let handle = ServerHandle::default();
+        serve_scgi_request(
+            app,
+            &Settings::new(),
+            &handle,
+            futures_util::io::Cursor::new(stdin.to_vec()),
+            ResponseBuffer::new(64, &mut stdout),
+            vars,
+            Gateway::Cgi,
+        )
+        .await
+        .unwrap();
+        String::from_utf8(stdout).unwrap()
+    }
+
+    const ENV: [(&str, &str); 5] = [
+        ("GATEWAY_INTERFACE", "CGI/1.1"),
+        ("REQUEST_METHOD", "POST"),
+        ("SCRIPT_NAME", "/cgi"),
+        ("SERVER_PROTOCOL", "HTTP/1.1"),
+        // Plain CGI environments come with all sorts of things in them.
+        ("PATH", "/usr/bin:/bin"),
+    ];
+
+    #[tokio::test]
+    async fn cgi_requests_say_they_came_over_cgi() {
+        let mut env = ENV.to_vec();
+        env.extend([("CONTENT_LENGTH", "5"), ("HTTPS", "on")]);
+        let stdout = serve(&env, b"hello, and then some").await;
+        let expected = format!(
+            "{:?} b\"hello\"",
+            Some(TransportInfo {
+                gateway: Gateway::Cgi,
+                scheme: http::uri::Scheme::HTTPS,
+            })
+        );
+        assert!(stdout.ends_with(&expected), "stdout was: {}", stdout);
+
+        let stdout = serve(&ENV, b"").await;
+        assert!(
+            stdout.contains("gateway: Cgi, scheme: \"http\""),
+            "stdout was: {}",
+            stdout
+        );
+    }
+
+    #[tokio::test]
+    async fn cgi_requests_without_a_content_length_have_no_body() {
+        let stdout = serve(&ENV, b"not for the app").await;
+        assert!(stdout.ends_with(" b\"\""), "stdout was: {}", stdout);
+    }
+}
@@ -0,0 +1,56 @@
+//! Request IDs, for matching up our logs with the web server's (or whatever's
+//! in front of it).
+use crate::{FcgiVars, Settings};
+use http::{HeaderName, HeaderValue};
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::OnceLock;
+
+/// A request's ID, along with the header it goes in.
+#[derive(Debug, Clone)]
+pub(crate) struct RequestId {
+    pub(crate) header: HeaderName,
+    pub(crate) value: HeaderValue,
+}
+
+impl RequestId {
+    /// Find the request's ID in its vars, or make one up if the settings say to.
+    /// A made-up ID goes into the vars too, so the app sees the same one we log.
+    pub(crate) fn from_vars(vars: &mut FcgiVars, settings: &Settings) -> Option<Self> {
+        let header = settings.request_id_header.clone()?;
+        // Web servers turn `X-Request-Id` into `HTTP_X_REQUEST_ID`, and so on.
+        let var_name = format!(
+            "HTTP_{}",
+            header.as_str().to_ascii_uppercase().replace('-', "_")
+        );
+        // An ID that can't be a header value won't do us any good, so it counts
+        // as missing.
+        if let Some(value) = vars
+            .get(&var_name)
+            .and_then(|v| HeaderValue::from_bytes(v).ok())
+        {
+            return Some(Self { header, value });
+        }
+        if !settings.generate_request_id {
+            return None;
+        }
+        let value = generate();
+        vars.insert(var_name, value.as_bytes().to_vec());
+        Some(Self { header, value })
+    }
+}
+
+/// A fresh ID that won't repeat within this process, and probably won't collide
+/// with other processes' either. It's not a UUID, but nobody needs it to be;
+/// it just has to be unique enough for grepping logs.
+fn generate() -> HeaderValue {
+    static STATE: OnceLock<std::collections::hash_map::RandomState> = OnceLock::new();
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    // RandomState gets seeded randomly once per process, so hashing a counter
+    // with it gives us unpredictable-looking IDs without pulling in a rand crate.
+    let mut hasher = STATE.get_or_init(Default::default).build_hasher();
+    hasher.write_u32(std::process::id());
+    hasher.write_u64(COUNTER.fetch_add(1, Ordering::Relaxed));
+    let id = format!("{:016x}", hasher.finish());
+    HeaderValue::try_from(id).expect("hex digits are always a valid header value")
+}
@@ -0,0 +1,260 @@
+//! The buffer responses get written through, for
+//! [`Settings::response_buffer`](crate::Settings::response_buffer).
+//!
+//! It's futures-util's `BufWriter`, minus the part where the buffer's private.
+//! fastcgi-server's `http_headers` only knows how to write to a sync `Write`, so
+//! with a `BufWriter`, response headers had to go into a `Vec` of their own first,
+//! and then get copied into the buffer; that's an extra allocation and an extra
+//! copy for every response. Here, they get serialized straight into the buffer,
+//! and go out along with the start of the body.
+//!
+//! And since it's our buffer, a FastCGI connection can hang on to it between
+//! requests (see [`ConnectionBuffers`]), so a web server that keeps its
+//! connections open (mod_fcgid does) doesn't cost us a fresh one for every
+//! response.
+use futures_util::AsyncWrite;
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::task::{ready, Context, Poll};
+
+/// Where a connection keeps its output buffer between requests. Requests on a
+/// connection mostly take turns, but if two do overlap, the second one just gets
+/// a buffer of its own.
+#[derive(Clone, Default)]
+pub(crate) struct SpareBuffer(Arc<Mutex<Vec<u8>>>);
+
+/// Hands out a connection's [`SpareBuffer`] to its requests.
+#[derive(Default)]
+pub(crate) struct ConnectionBuffers {
+    seen_one: bool,
+    spare: Option<SpareBuffer>,
+}
+
+impl ConnectionBuffers {
+    /// The spare for a connection's next request to use, if it gets one.
+    pub(crate) fn for_next_request(&mut self) -> Option<SpareBuffer> {
+        // Plenty of connections only ever get the one request (nginx's, unless
+        // it's told to keep them), and setting a spare aside costs an allocation
+        // of its own. So don't bother until a second one shows up.
+        if !std::mem::replace(&mut self.seen_one, true) {
+            return None;
+        }
+        Some(self.spare.get_or_insert_with(SpareBuffer::default).clone())
+    }
+}
+
+impl SpareBuffer {
+    fn take(&self) -> Vec<u8> {
+        std::mem::take(&mut *self.0.lock().unwrap_or_else(PoisonError::into_inner))
+    }
+
+    fn put_back(&self, buf: Vec<u8>) {
+        *self.0.lock().unwrap_or_else(PoisonError::into_inner) = buf;
+    }
+}
+
+/// Buffers writes to `W` and sends them along in (at most) `capacity`-sized
+/// batches, like a `BufWriter`, but lets us write into the buffer directly.
+pub(crate) struct ResponseBuffer<W> {
+    inner: W,
+    buf: Vec<u8>,
+    /// How much of `buf` has gone out already, if a flush got interrupted.
+    written: usize,
+    capacity: usize,
+    /// Where the buffer goes when we're done with it, if anywhere.
+    spare: Option<SpareBuffer>,
+}
+
+impl<W: AsyncWrite + Unpin> ResponseBuffer<W> {
+    pub(crate) fn new(capacity: usize, inner: W) -> Self {
+        Self {
+            inner,
+            buf: Vec::with_capacity(capacity),
+            written: 0,
+            capacity,
+            spare: None,
+        }
+    }
+
+    /// Like [`new`](Self::new), but borrowing the spare's buffer instead of
+    /// allocating one, if there's a spare and it's got one, and giving it back
+    /// afterwards.
+    pub(crate) fn with_spare(capacity: usize, inner: W, spare: Option<SpareBuffer>) -> Self {
+        let Some(spare) = spare else {
+            return Self::new(capacity, inner);
+        };
+        let mut buf = spare.take();
+        buf.clear();
+        buf.reserve(capacity);
+        Self {
+            inner,
+            buf,
+            written: 0,
+            capacity,
+            spare: Some(spare),
+        }
+    }
+
+    /// The buffer itself, for writing into without an await. Nothing gets sent
+    /// until the next write or flush, however much goes in; anything already
+    /// there stays put.
+    pub(crate) fn buffer_mut(&mut self) -> &mut Vec<u8> {
+        &mut self.buf
+    }
+
+    /// Send along whatever's buffered, without flushing the writer underneath.
+    fn poll_flush_buf(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.written < self.buf.len() {
+            let n = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.buf[self.written..]))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.written += n;
+        }
+        self.buf.clear();
+        self.written = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<W> Drop for ResponseBuffer<W> {
+    fn drop(&mut self) {
+        // A response with enormous headers can grow the buffer past its usual
+        // size; no sense keeping that around for every request after it.
+        if let Some(spare) = &self.spare {
+            if self.buf.capacity() <= 2 * self.capacity {
+                spare.put_back(std::mem::take(&mut self.buf));
+            }
+        }
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for ResponseBuffer<W> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        data: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.buf.len() + data.len() > this.capacity {
+            ready!(this.poll_flush_buf(cx))?;
+        }
+        // Same as BufWriter: anything too big to buffer skips the copy.
+        if data.len() >= this.capacity {
+            Pin::new(&mut this.inner).poll_write(cx, data)
+        } else {
+            this.buf.extend_from_slice(data);
+            Poll::Ready(Ok(data.len()))
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_flush_buf(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_flush_buf(cx))?;
+        Pin::new(&mut this.inner).poll_close(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::AsyncWriteExt;
+
+    /// A writer that takes at most `per_write` bytes at a time, and keeps track of
+    /// each write, so we can see how things got batched.
+    #[derive(Default)]
+    struct Trickle {
+        per_write: usize,
+        writes: Vec<Vec<u8>>,
+        flushes: usize,
+    }
+
+    impl AsyncWrite for Trickle {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            data: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            let n = data.len().min(self.per_write);
+            self.writes.push(data[..n].to_vec());
+            Poll::Ready(Ok(n))
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            self.flushes += 1;
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn direct_writes_go_out_with_the_next_batch() {
+        let inner = Trickle {
+            per_write: usize::MAX,
+            ..Default::default()
+        };
+        let mut out = ResponseBuffer::new(16, inner);
+        out.buffer_mut().extend_from_slice(b"Head: er\n\n");
+        out.write_all(b"body").await.unwrap();
+        assert!(out.inner.writes.is_empty());
+        out.flush().await.unwrap();
+        assert_eq!(out.inner.writes, [b"Head: er\n\nbody".to_vec()]);
+        assert_eq!(out.inner.flushes, 1);
+    }
+
+    #[tokio::test]
+    async fn big_writes_skip_the_buffer_and_short_writes_get_finished() {
+        let inner = Trickle {
+            per_write: 5,
+            ..Default::default()
+        };
+        let mut out = ResponseBuffer::new(8, inner);
+        out.write_all(b"small").await.unwrap();
+        // Too big to fit with what's there, so that goes first, then this one
+        // goes straight through, five bytes at a time.
+        out.write_all(b"much too big").await.unwrap();
+        out.flush().await.unwrap();
+        let writes: Vec<&[u8]> = out.inner.writes.iter().map(Vec::as_slice).collect();
+        assert_eq!(writes, [&b"small"[..], b"much ", b"too b", b"ig"]);
+        // Headers bigger than the buffer still all make it out.
+        out.buffer_mut().extend_from_slice(b"0123456789abc");
+        out.close().await.unwrap();
+        assert_eq!(out.inner.writes.concat().len(), 17 + 13);
+    }
+
+    #[tokio::test]
+    async fn spares_get_handed_from_one_response_to_the_next() {
+        let mut buffers = ConnectionBuffers::default();
+        assert!(buffers.for_next_request().is_none());
+        let spare = buffers.for_next_request().unwrap();
+        let mut sent = Vec::new();
+        let first_ptr = {
+            let mut out = ResponseBuffer::with_spare(64, &mut sent, buffers.for_next_request());
+            out.write_all(b"first").await.unwrap();
+            out.flush().await.unwrap();
+            out.buffer_mut().as_ptr()
+        };
+        {
+            let mut out = ResponseBuffer::with_spare(64, &mut sent, buffers.for_next_request());
+            // Same allocation, emptied out.
+            assert_eq!(out.buffer_mut().as_ptr(), first_ptr);
+            assert!(out.buffer_mut().is_empty());
+            out.write_all(b", second").await.unwrap();
+            out.flush().await.unwrap();
+            // Way bigger than usual, so this one doesn't get kept.
+            out.buffer_mut().reserve(1024);
+        }
+        assert_eq!(sent, b"first, second");
+        assert_eq!(spare.take().capacity(), 0);
+    }
+}
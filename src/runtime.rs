@@ -0,0 +1,59 @@
+//! A Tokio runtime that behaves itself on a shared host.
+use std::io;
+use std::num::NonZeroUsize;
+use std::time::Duration;
+
+/// Build a multi-threaded Tokio runtime sized for shared hosting, for when you'd
+/// otherwise reach for `#[tokio::main]`. Run your app with its `block_on`.
+///
+/// Tokio's defaults assume the whole machine is yours: a worker thread per CPU
+/// core, plus up to 512 threads for blocking work. On a shared host that's rude
+/// at best, and at worst it trips the process limits and gets your app killed.
+/// So this caps the workers at `threads`, keeps the blocking pool to the same
+/// size (a floor of a few, so `spawn_blocking` and file IO still have room), and
+/// lets idle blocking threads exit quickly. Consider making `threads` a command
+/// line option, since the right number depends on the host. (If the right number
+/// is one, see [`tame_current_thread_runtime`].)
+///
+/// Errors: Whatever Tokio returns if it can't build the runtime, which mostly
+/// means it couldn't spawn threads.
+pub fn tame_runtime(threads: NonZeroUsize) -> io::Result<tokio::runtime::Runtime> {
+    tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(threads.get())
+        .max_blocking_threads(threads.get().max(4))
+        .thread_keep_alive(Duration::from_secs(2))
+        .thread_name("busride-worker")
+        .enable_all()
+        .build()
+}
+
+/// Build a single-threaded Tokio runtime, for the tiniest of hosts: one thread
+/// for everything, plus a couple in reserve for blocking work (file IO, mostly).
+/// Run your app with its `block_on`, or use `#[tokio::main(flavor = "current_thread")]`,
+/// which does about the same thing with a bigger blocking pool.
+///
+/// Everything in this crate works fine on a runtime like that. We do spawn a task
+/// per connection (and a couple of helpers, for things like
+/// [`FcgiServer::ready_when`](crate::FcgiServer::ready_when)), with `tokio::spawn`,
+/// but on a current-thread runtime that just means those tasks take turns on the
+/// one thread; no `LocalSet` required. Apps still have to be `Send`, same as always.
+///
+/// What there isn't is a `serve_fcgid_local`, a `spawn_local` flavor of the serve
+/// functions for apps that aren't `Send`. That's not an oversight: fastcgi-server's
+/// connection driver takes each request's handler as a `Send` boxed future, so a
+/// non-`Send` app couldn't get through it even on a `LocalSet`, and swapping
+/// `spawn` for `spawn_local` on our end wouldn't change that. Axum apps are `Send`
+/// anyway, so until fastcgi-server grows a local driver, I'm leaving it out.
+///
+/// One thread means one request's CPU-heavy work holds up everybody else's, so
+/// move anything slow onto `spawn_blocking`.
+///
+/// Errors: Whatever Tokio returns if it can't build the runtime.
+pub fn tame_current_thread_runtime() -> io::Result<tokio::runtime::Runtime> {
+    tokio::runtime::Builder::new_current_thread()
+        .max_blocking_threads(2)
+        .thread_keep_alive(Duration::from_secs(2))
+        .thread_name("busride-blocking")
+        .enable_all()
+        .build()
+}
@@ -0,0 +1,347 @@
+//! SCGI, FastCGI's simpler sibling, as another way for a web server to reach the
+//! app. nginx (`scgi_pass`) and Apache (`mod_proxy_scgi`) both speak it. Each
+//! connection carries exactly one request: a netstring full of CGI vars, then the
+//! body, then we send back a CGI-style response and hang up. Past that wire format,
+//! it's the same translation the FastCGI side does, so it shares most of its code.
+use crate::{
+    accept_error_is_fatal, current_app, handle_request, respond_to_request,
+    response_buffer::ResponseBuffer, status_response, write_http_response, FcgiServer, FcgiVars,
+    Gateway, Listener, Reply, RequestOutcome, ResponseOptions, ResponseSize, ServeError,
+    ServerHandle, Settings, SharedApp, ACCEPT_BACKOFF_MAX, ACCEPT_BACKOFF_MIN,
+};
+use axum::BoxError;
+use bytes::Bytes;
+use fastcgi_server::cgi;
+use futures_util::io::BufReader;
+use futures_util::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use http_body::Body as HttpBody;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::num::NonZeroUsize;
+use std::sync::Arc;
+use tokio::io::{AsyncRead as TokioAsyncRead, AsyncWrite as TokioAsyncWrite};
+use tokio::task::JoinSet;
+use tokio_util::compat::{TokioAsyncReadCompatExt, TokioAsyncWriteCompatExt};
+use tower::Service;
+use tracing::{debug, error, trace, warn, Instrument};
+
+/// The most bytes of CGI vars we'll take in one request's netstring. The header
+/// limits in [`Settings`] still apply afterwards, same as for FastCGI; this just
+/// keeps a bogus length prefix from making us allocate the moon before we've
+/// looked at any of it.
+const MAX_SCGI_HEADERS_LEN: usize = 1024 * 1024;
+
+/// Serve an Axum app over SCGI, on a TCP listener we bind at this address. Point
+/// the web server at it with something like `scgi_pass 127.0.0.1:4000;` (nginx) or
+/// `ProxyPass / scgi://127.0.0.1:4000/` (Apache, with `mod_proxy_scgi`). Runs until
+/// the signal future completes, then stops accepting and waits for in-flight
+/// requests to finish. Like the FastCGI functions, `max_connections` caps how many
+/// we serve at once; since SCGI is one request per connection, that caps requests too.
+///
+/// This is shorthand for an [`FcgiServer`] with one [`scgi_tcp`](FcgiServer::scgi_tcp)
+/// listener; use that instead for anything fancier, like a [`ServerHandle`],
+/// reloads, or serving FastCGI on the side.
+///
+/// Errors: Returns an error if we couldn't bind the address, or if the listener
+/// broke later on.
+pub async fn serve_scgi<S, B, F>(
+    app: S,
+    max_connections: NonZeroUsize,
+    addr: SocketAddr,
+    settings: Settings,
+    signal: F,
+) -> Result<(), ServeError>
+where
+    S: Service<http::Request<axum::body::Body>, Response = http::Response<B>>
+        + Clone
+        + Send
+        + 'static,
+    S::Future: Send,
+    S::Error: Into<BoxError>,
+    B: HttpBody<Data = Bytes> + Send + 'static,
+    B::Error: Into<BoxError>,
+    F: Future<Output = ()> + Send + 'static,
+{
+    FcgiServer::new()
+        .max_connections(max_connections)
+        .scgi_tcp(addr)
+        .settings(settings)
+        .graceful_shutdown(signal)
+        .serve(app)
+        .await
+        .map(|_| ()) // no drain deadline, so it's always a clean shutdown
+}
+
+/// The SCGI version of the FastCGI accept loop, for SCGI listeners on an
+/// [`FcgiServer`]. There's no fastcgi-server runner handing out connection
+/// tokens here, so the handle's connection slots are the only limit, which is
+/// also what keeps SCGI and FastCGI listeners on the same server in one pool.
+/// This only returns if the listener itself breaks.
+pub(crate) async fn serve_scgi_loop<L, S, B>(
+    app: SharedApp<S>,
+    listener: L,
+    settings: Arc<Settings>,
+    connections: &mut JoinSet<()>,
+    handle: ServerHandle,
+) -> ServeError
+where
+    L: Listener,
+    S: Service<http::Request<axum::body::Body>, Response = http::Response<B>>
+        + Clone
+        + Send
+        + 'static,
+    S::Future: Send,
+    S::Error: Into<BoxError>,
+    B: HttpBody<Data = Bytes> + Send + 'static,
+    B::Error: Into<BoxError>,
+{
+    // Same deal as the FastCGI accept loop: back off on errors, reset on success.
+    let mut backoff = ACCEPT_BACKOFF_MIN;
+    loop {
+        while connections.try_join_next().is_some() {}
+        // Claim the slot before accepting, so connections past the limit wait in
+        // the listen backlog instead of with us.
+        let active = handle.connection_slot().await;
+        match listener.accept().await {
+            Err(e) if accept_error_is_fatal(&e) => {
+                error!(
+                    target: "busride_rs",
+                    protocol = "scgi",
+                    "accept failed, giving up: {}", &e
+                );
+                return ServeError::Accept(e);
+            }
+            Err(e) => {
+                error!(
+                    target: "busride_rs",
+                    protocol = "scgi",
+                    retry_in = ?backoff,
+                    "accept failed: {}", &e
+                );
+                drop(active);
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(ACCEPT_BACKOFF_MAX);
+            }
+            Ok(stream) => {
+                backoff = ACCEPT_BACKOFF_MIN;
+                let span = tracing::error_span!(
+                    target: "busride_rs",
+                    "scgi_connection",
+                    protocol = listener.protocol()
+                );
+                let app = current_app(&app);
+                let settings = settings.clone();
+                let handle = handle.clone();
+                connections.spawn(
+                    async move {
+                        let _active = active;
+                        debug!(target: "busride_rs", "new connection accepted on dedicated task");
+                        if let Err(e) =
+                            handle_scgi_connection(app, &settings, &handle, stream).await
+                        {
+                            // Same as for FastCGI: mostly a client hanging up mid-request.
+                            debug!(target: "busride_rs", "SCGI connection broke: {}", e);
+                        }
+                        debug!(target: "busride_rs", "connection closed");
+                    }
+                    .instrument(span),
+                );
+            }
+        }
+    }
+}
+
+/// Read a request off an SCGI connection, handle it with the app, and send the
+/// response back. An io::Error means the connection broke along the way.
+async fn handle_scgi_connection<S, B, C>(
+    app: S,
+    settings: &Settings,
+    handle: &ServerHandle,
+    stream: C,
+) -> io::Result<()>
+where
+    S: Service<http::Request<axum::body::Body>, Response = http::Response<B>> + Send,
+    S::Future: Send,
+    S::Error: Into<BoxError>,
+    B: HttpBody<Data = Bytes> + Send + 'static,
+    B::Error: Into<BoxError>,
+    C: TokioAsyncRead + TokioAsyncWrite + Send + 'static,
+{
+    let (r, w) = tokio::io::split(stream);
+    let mut r = BufReader::new(r.compat());
+    let out = ResponseBuffer::new(settings.response_buffer.get(), w.compat_write());
+    let vars = match read_scgi_headers(&mut r).await {
+        Ok(vars) => vars,
+        // Garbage instead of a request means there's nobody sensible to answer.
+        Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+            warn!(
+                target: "busride_rs",
+                blame = "scgi client",
+                "Couldn't parse SCGI request headers; hanging up: {}", e
+            );
+            return Ok(());
+        }
+        Err(e) => return Err(e),
+    };
+    trace!(target: "busride_rs", "Read SCGI request headers");
+    serve_scgi_request(app, settings, handle, r, out, vars, Gateway::Scgi).await
+}
+
+/// Does the actual work of [`handle_scgi_connection`], once we've got the vars:
+/// hands off to [`respond_to_request`] for the shared part (inside the same
+/// bookkeeping the FastCGI side gets from [`handle_request`]), and sends the
+/// response. Plain CGI is the same exchange minus the netstring, so
+/// `serve_cgi_once` uses it too.
+pub(crate) async fn serve_scgi_request<S, B, R, W>(
+    app: S,
+    settings: &Settings,
+    handle: &ServerHandle,
+    r: R,
+    out: ResponseBuffer<W>,
+    vars: FcgiVars,
+    gateway: Gateway,
+) -> io::Result<()>
+where
+    S: Service<http::Request<axum::body::Body>, Response = http::Response<B>> + Send,
+    S::Future: Send,
+    S::Error: Into<BoxError>,
+    B: HttpBody<Data = Bytes> + Send + 'static,
+    B::Error: Into<BoxError>,
+    R: AsyncRead + Unpin + Send,
+    W: AsyncWrite + Unpin,
+{
+    let ready = handle.is_ready();
+    let (result, _stats) = handle_request(settings, handle, vars, |vars, request_id| async move {
+        let mut outcome = RequestOutcome::default();
+        let opts = ResponseOptions::new(settings, request_id.as_ref(), &vars, gateway);
+        // SCGI makes CONTENT_LENGTH mandatory (read_scgi_headers checked), so unlike
+        // with FastCGI, we always know exactly how much body is coming, and take()
+        // makes sure we don't read past it. Plain CGI doesn't, but there a missing
+        // one means there's no body.
+        let content_length = scgi_content_length(&vars).unwrap_or(0);
+        let reply = match respond_to_request(
+            app,
+            settings,
+            vars,
+            r.take(content_length),
+            gateway,
+            ready,
+            &mut outcome.bytes_in,
+        )
+        .await
+        {
+            Ok(reply) => reply,
+            Err(e) => return (Err(e), outcome),
+        };
+        let resp = match reply {
+            Reply::Respond(resp) | Reply::Failed(Some(resp), _) => resp,
+            // With FastCGI we could hand back a failing exit code here, but SCGI
+            // doesn't have those; hanging up without a response would just look
+            // like we crashed.
+            Reply::Failed(None, _) => status_response(http::StatusCode::INTERNAL_SERVER_ERROR),
+        };
+        outcome.status = Some(resp.status());
+        trace!(target: "busride_rs", "writing response as scgi response");
+        let result = send_response(out, resp, &opts).await.map(|size| {
+            outcome.size = size;
+        });
+        (result, outcome)
+    })
+    .await;
+    result
+}
+
+/// Write a response and hang up, which is how SCGI says the response is over.
+async fn send_response<W>(
+    mut out: ResponseBuffer<W>,
+    resp: http::Response<axum::body::Body>,
+    opts: &ResponseOptions<'_>,
+) -> io::Result<ResponseSize>
+where
+    W: AsyncWrite + Unpin,
+{
+    // An SCGI response is just a CGI response, which is what this writes anyway.
+    // If the app's body breaks partway, we bail without the close. Hanging up is
+    // all SCGI has for "done", though, so unless the response had a Content-Length
+    // to fall short of, the web server can't tell; the error log is the only sign.
+    let size = write_http_response(&mut out, resp, opts).await?;
+    out.close().await?;
+    Ok(size)
+}
+
+/// The request's Content-Length, if it has a valid one.
+fn scgi_content_length(vars: &FcgiVars) -> Option<u64> {
+    vars.get_str(cgi::CONTENT_LENGTH)
+        .and_then(|v| v.parse::<u64>().ok())
+}
+
+/// Read the netstring at the start of an SCGI request (`<length>:<vars>,`) and
+/// split it into vars. Anything malformed comes back as an `InvalidData` error.
+async fn read_scgi_headers<R>(r: &mut R) -> io::Result<FcgiVars>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut len: usize = 0;
+    let mut digits = 0;
+    loop {
+        let mut byte = [0u8; 1];
+        r.read_exact(&mut byte).await?;
+        match byte[0] {
+            // Seven digits is already more than MAX_SCGI_HEADERS_LEN, so this can't overflow.
+            b @ b'0'..=b'9' if digits < 7 => {
+                len = len * 10 + (b - b'0') as usize;
+                digits += 1;
+            }
+            b':' if digits > 0 => break,
+            _ => return Err(invalid("bad netstring length")),
+        }
+    }
+    if len > MAX_SCGI_HEADERS_LEN {
+        return Err(invalid("request headers are too long"));
+    }
+    let mut buf = vec![0u8; len];
+    r.read_exact(&mut buf).await?;
+    let mut comma = [0u8; 1];
+    r.read_exact(&mut comma).await?;
+    if comma != *b"," {
+        return Err(invalid("netstring is missing its trailing comma"));
+    }
+    let vars = parse_scgi_headers(Bytes::from(buf))?;
+    // The spec also wants an `SCGI` var set to 1, but nothing we do depends on it,
+    // so we don't fuss. Content-Length we do need.
+    if scgi_content_length(&vars).is_none() {
+        return Err(invalid("missing or invalid CONTENT_LENGTH"));
+    }
+    Ok(vars)
+}
+
+/// Split SCGI headers (`name\0value\0name\0value\0...`) into vars, without copying
+/// them out of the buffer.
+fn parse_scgi_headers(mut buf: Bytes) -> io::Result<FcgiVars> {
+    let mut vars = Vec::new();
+    while !buf.is_empty() {
+        let name = take_field(&mut buf)?;
+        let value = take_field(&mut buf)?;
+        if std::str::from_utf8(&name).is_err() {
+            return Err(invalid("var name isn't valid UTF-8"));
+        }
+        vars.push((name, value));
+    }
+    Ok(FcgiVars::from_shared(vars))
+}
+
+/// Split a null-terminated field off the front of the buffer, minus the null.
+fn take_field(buf: &mut Bytes) -> io::Result<Bytes> {
+    let end = buf
+        .iter()
+        .position(|b| *b == 0)
+        .ok_or_else(|| invalid("header field isn't null-terminated"))?;
+    let field = buf.split_to(end);
+    let _null = buf.split_to(1);
+    Ok(field)
+}
+
+fn invalid(why: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, why)
+}
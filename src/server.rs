@@ -0,0 +1,456 @@
+//! A builder for serving apps, so options don't have to pile up as positional
+//! arguments on the free `serve_*` functions.
+#[cfg(target_os = "linux")]
+use crate::bind_abstract_unix_listener;
+use crate::{
+    adopt_unix_listener, bind_tcp_listener, bind_unix_listener,
+    serve_listeners_with_graceful_shutdown, systemd_listen_fd, AnyListener, Gateway, ServeError,
+    ServerHandle, Settings,
+};
+use axum::BoxError;
+use bytes::Bytes;
+use futures_util::{Stream, StreamExt};
+use http_body::Body as HttpBody;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::num::{NonZeroU64, NonZeroUsize};
+use std::os::fd::RawFd;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::UnixListener;
+use tower::Service;
+use tracing::info;
+
+type ShutdownSignal = Pin<Box<dyn Future<Output = ()> + Send>>;
+type ReadySignal = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// Where the listening socket comes from.
+#[derive(Debug)]
+enum Listen {
+    /// Adopt an already-open Unix socket from an inherited file descriptor.
+    Fd(RawFd),
+    /// Adopt the first socket passed via systemd socket activation.
+    Systemd,
+    /// Bind our own TCP listener.
+    Tcp(SocketAddr),
+    /// Create our own Unix socket at a path.
+    Unix(PathBuf),
+    /// Create our own Unix socket with a name in the abstract namespace.
+    #[cfg(target_os = "linux")]
+    UnixAbstract(Vec<u8>),
+    /// Use a Unix socket the caller already set up.
+    UnixListener(UnixListener),
+    /// Bind our own TCP listener, for SCGI instead of FastCGI.
+    ScgiTcp(SocketAddr),
+}
+
+/// How a server run ended, once the shutdown signal fired.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Shutdown {
+    /// Every in-flight connection wrapped up on its own.
+    Clean,
+    /// The [drain deadline](FcgiServer::drain_deadline) passed with connections
+    /// still open, so we aborted them. Those clients probably got cut off
+    /// mid-response.
+    Forced {
+        /// How many connection tasks we aborted.
+        aborted: usize,
+    },
+}
+
+/// Configures and runs a FastCGI server for an app. With no configuration, it
+/// does the same thing as [`serve_fcgid`](crate::serve_fcgid): adopt the Unix
+/// socket on fd 0 the way mod_fcgid expects, and serve until the process is killed.
+///
+/// Listener options ([`fd`](Self::fd), [`systemd`](Self::systemd),
+/// [`tcp`](Self::tcp), [`bind_unix`](Self::bind_unix), [`unix_listener`](Self::unix_listener),
+/// [`scgi_tcp`](Self::scgi_tcp), and on Linux, `bind_unix_abstract`)
+/// add up: call several of them (or the same one more than
+/// once) to serve the same app on several sockets at once, like a Unix socket for
+/// mod_fcgid plus a TCP port for nginx. All the listeners share one pool of
+/// `max_connections`, and graceful shutdown stops all of them. Fd 0 is only the
+/// default if you don't pick any listeners at all.
+/// Tuning for how individual requests get handled lives over in [`Settings`].
+pub struct FcgiServer {
+    max_connections: NonZeroUsize,
+    listen: Vec<Listen>,
+    unix_socket_mode: u32,
+    unix_socket_owner: (Option<u32>, Option<u32>),
+    settings: Settings,
+    signal: Option<ShutdownSignal>,
+    drain_deadline: Option<Duration>,
+    idle_timeout: Option<Duration>,
+    max_requests: Option<NonZeroU64>,
+    ready_when: Option<ReadySignal>,
+    handle: ServerHandle,
+}
+
+impl Default for FcgiServer {
+    fn default() -> Self {
+        let max_connections = NonZeroUsize::new(50).unwrap();
+        let handle = ServerHandle::default();
+        handle.set_connection_ceiling(max_connections);
+        Self {
+            max_connections,
+            listen: Vec::new(),
+            unix_socket_mode: 0o660,
+            unix_socket_owner: (None, None),
+            settings: Settings::default(),
+            signal: None,
+            drain_deadline: None,
+            idle_timeout: None,
+            max_requests: None,
+            ready_when: None,
+            handle,
+        }
+    }
+}
+
+impl FcgiServer {
+    /// Same as `FcgiServer::default()`.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The maximum number of FastCGI connections to serve at once. Defaults to 50.
+    /// You can turn it down (and back up) while serving, with
+    /// [`ServerHandle::set_max_connections`]; this is the most it can go back up to.
+    ///
+    /// It's also the number a web server gets if it asks how many connections we
+    /// take (FastCGI's `FCGI_MAX_CONNS`, via a `GetValues` record), even while the
+    /// handle has the limit turned down, since this is the only one fastcgi-server
+    /// knows about. Most web servers never ask; mod_fcgid doesn't, and runs its
+    /// own process limits instead.
+    pub fn max_connections(mut self, max_connections: NonZeroUsize) -> Self {
+        self.max_connections = max_connections;
+        self.handle.set_connection_ceiling(max_connections);
+        self
+    }
+
+    /// Adopt a listening Unix socket from this inherited file descriptor. Defaults
+    /// to fd 0, which is where mod_fcgid puts it. We take ownership of the descriptor,
+    /// and it gets closed when the server stops.
+    pub fn fd(mut self, fd: RawFd) -> Self {
+        self.listen.push(Listen::Fd(fd));
+        self
+    }
+
+    /// Adopt the listening socket from systemd socket activation. See
+    /// [`serve_fcgid_systemd_with_graceful_shutdown`](crate::serve_fcgid_systemd_with_graceful_shutdown)
+    /// for the details and caveats.
+    pub fn systemd(mut self) -> Self {
+        self.listen.push(Listen::Systemd);
+        self
+    }
+
+    /// Bind our own TCP listener at this address, instead of (or as well as)
+    /// adopting an inherited socket. See [`serve_fcgi_tcp_with_graceful_shutdown`](crate::serve_fcgi_tcp_with_graceful_shutdown)
+    /// for when you'd want that. It still speaks FastCGI, not HTTP, so there's still
+    /// a web server in front, handling HTTP-level things like `Expect: 100-continue`.
+    pub fn tcp(mut self, addr: SocketAddr) -> Self {
+        self.listen.push(Listen::Tcp(addr));
+        self
+    }
+
+    /// Create our own Unix socket at this path, PHP-FPM style. See
+    /// [`serve_fcgi_bind_with_graceful_shutdown`](crate::serve_fcgi_bind_with_graceful_shutdown)
+    /// for how we handle leftovers and cleanup.
+    pub fn bind_unix(mut self, path: impl AsRef<Path>) -> Self {
+        self.listen.push(Listen::Unix(path.as_ref().to_path_buf()));
+        self
+    }
+
+    /// Create our own Unix socket in Linux's abstract namespace, with this name
+    /// (leave off the leading null byte; we add it). The web server connects to
+    /// it by the same name, like `unix:@name` for some clients. There's no file,
+    /// so there's nothing to clean up afterwards, and the mode and owner options
+    /// don't apply; anything in the same network namespace can connect, so make
+    /// sure that's only things you trust.
+    #[cfg(target_os = "linux")]
+    pub fn bind_unix_abstract(mut self, name: impl AsRef<[u8]>) -> Self {
+        self.listen
+            .push(Listen::UnixAbstract(name.as_ref().to_vec()));
+        self
+    }
+
+    /// Serve on a Unix listener you've already got, from your own setup code (or a
+    /// test). We use it as-is: no checking what kind of socket it is, and no
+    /// mode or owner changes, since you're in charge of all that.
+    pub fn unix_listener(mut self, listener: UnixListener) -> Self {
+        self.listen.push(Listen::UnixListener(listener));
+        self
+    }
+
+    /// Bind our own TCP listener at this address that speaks SCGI instead of
+    /// FastCGI, for web servers where that's easier to set up: point them at it
+    /// with something like `scgi_pass 127.0.0.1:4000;` (nginx) or
+    /// `ProxyPass / scgi://127.0.0.1:4000/` (Apache, with `mod_proxy_scgi`). Its
+    /// requests count toward the [`handle`](Self::handle)'s numbers and the
+    /// `max_connections` cap like any others; since SCGI is one request per
+    /// connection, that caps requests too. Everything else about the server
+    /// applies the same as for FastCGI listeners, except what's FastCGI-specific:
+    /// SCGI only has responders, one request per connection, and no stderr stream
+    /// for [`Settings::stderr_diagnostics`] to write to. A request that the app fails
+    /// to answer at all gets a `500`, since there's no exit status to report it with.
+    pub fn scgi_tcp(mut self, addr: SocketAddr) -> Self {
+        self.listen.push(Listen::ScgiTcp(addr));
+        self
+    }
+
+    /// The permission bits for sockets made by [`bind_unix`](Self::bind_unix).
+    /// Defaults to `0o660`, so the web server can connect if it shares a group
+    /// with the app; use `0o666` if it doesn't, and you trust everyone else on
+    /// the machine.
+    pub fn unix_socket_mode(mut self, mode: u32) -> Self {
+        self.unix_socket_mode = mode;
+        self
+    }
+
+    /// Hand sockets made by [`bind_unix`](Self::bind_unix) over to this user
+    /// and/or group (by numeric id; None leaves that one alone). Pairs nicely
+    /// with a `0o660` mode and the web server's group. By default we don't
+    /// change ownership at all, so the socket belongs to whoever's running the app.
+    ///
+    /// Changing the owner generally requires root; if it fails, serving fails.
+    pub fn unix_socket_owner(mut self, uid: Option<u32>, gid: Option<u32>) -> Self {
+        self.unix_socket_owner = (uid, gid);
+        self
+    }
+
+    /// Tuning for how individual requests get handled.
+    pub fn settings(mut self, settings: Settings) -> Self {
+        self.settings = settings;
+        self
+    }
+
+    /// Stop accepting connections and shut down gracefully once this future
+    /// completes. Without one, the server runs until the process is terminated.
+    pub fn graceful_shutdown<F>(mut self, signal: F) -> Self
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        self.signal = Some(Box::pin(signal));
+        self
+    }
+
+    /// After the shutdown signal fires, wait at most this long for in-flight
+    /// requests to finish, then abort whatever connections are left. Without a
+    /// deadline, a stuck connection can hold up shutdown forever; that's the default.
+    pub fn drain_deadline(mut self, deadline: Duration) -> Self {
+        self.drain_deadline = Some(deadline);
+        self
+    }
+
+    /// Shut down on our own once there have been no open connections for this
+    /// long, exactly as if the shutdown signal had fired. That's what lets a
+    /// standalone server act like an on-demand worker: start it when there's work
+    /// (via systemd socket activation, say) and let it exit when things go quiet.
+    /// Only counts connections, not what they're up to, so a client holding a
+    /// connection open keeps us alive. No timeout (the default) means we never
+    /// get bored.
+    ///
+    /// mod_fcgid kills idle processes itself, so you don't need this with it.
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = Some(timeout);
+        self
+    }
+
+    /// Shut down on our own after serving this many requests, exactly as if the
+    /// shutdown signal had fired, like Apache's `MaxRequestsPerChild`. It's the
+    /// blunt fix for an app that leaks memory (or file descriptors, or whatever)
+    /// over time: let it serve a while, then exit cleanly and let mod_fcgid or
+    /// systemd start a fresh one. Requests that are in flight when we hit the
+    /// limit still get to finish, and so might a few more on connections that were
+    /// already open, since graceful shutdown only stops new connections; so treat
+    /// it as "at least", not "exactly". No limit is the default.
+    pub fn max_requests(mut self, max_requests: NonZeroU64) -> Self {
+        self.max_requests = Some(max_requests);
+        self
+    }
+
+    /// Start accepting connections right away, but answer every request with a
+    /// `503 Service Unavailable` (and a `Retry-After`) until this future completes,
+    /// without bothering the app. For apps with some async setup to finish first
+    /// (connecting a database pool, warming a cache), so the web server's first
+    /// request doesn't have to wait on it or time out. Without one, we're ready
+    /// as soon as we're listening.
+    pub fn ready_when<F>(mut self, ready: F) -> Self
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        self.ready_when = Some(Box::pin(ready));
+        self
+    }
+
+    /// A handle for keeping tabs on the server once it's running: how many
+    /// connections and requests are in flight. Grab it before calling
+    /// [`serve`](Self::serve), since that consumes the builder.
+    pub fn handle(&self) -> ServerHandle {
+        self.handle.clone()
+    }
+
+    /// Serve an app: an `axum::Router`, or any other cloneable tower `Service`
+    /// that handles `http::Request<axum::body::Body>`s.
+    ///
+    /// Errors: In normal operation, this just loops until shutdown. An error return
+    /// means we were unable to set up the listening socket (it wasn't a socket, the
+    /// systemd environment was wrong, the address couldn't be bound...), and never
+    /// made it to the accept() loop; or, rarely, that the listener broke later on.
+    /// Otherwise, it tells you whether shutdown was clean or had to be forced by
+    /// the drain deadline.
+    pub async fn serve<S, B>(self, app: S) -> Result<Shutdown, ServeError>
+    where
+        S: Service<http::Request<axum::body::Body>, Response = http::Response<B>>
+            + Clone
+            + Send
+            + 'static,
+        S::Future: Send,
+        S::Error: Into<BoxError>,
+        B: HttpBody<Data = Bytes> + Send + 'static,
+        B::Error: Into<BoxError>,
+    {
+        self.serve_with_reloads(app, futures_util::stream::pending())
+            .await
+    }
+
+    /// Like [`serve`](Self::serve), but every app that comes out of `reloads`
+    /// replaces the one we're serving, without a restart. Connections that are
+    /// already open finish up with the app they started with; new ones get the
+    /// new app. That's how you'd do the classic reload-on-SIGHUP, say, for picking
+    /// up a changed config file without dropping anybody:
+    ///
+    /// ```ignore
+    /// use tokio::signal::unix::{signal, SignalKind};
+    /// let hangups = signal(SignalKind::hangup())?;
+    /// let reloads = futures_util::stream::unfold(hangups, |mut hangups| async move {
+    ///     hangups.recv().await?;
+    ///     Some((build_app(load_config()), hangups))
+    /// });
+    /// FcgiServer::new().serve_with_reloads(build_app(load_config()), reloads).await?;
+    /// ```
+    ///
+    /// If the stream ends, we keep serving whatever app we had last.
+    pub async fn serve_with_reloads<S, B, R>(
+        self,
+        app: S,
+        reloads: R,
+    ) -> Result<Shutdown, ServeError>
+    where
+        S: Service<http::Request<axum::body::Body>, Response = http::Response<B>>
+            + Clone
+            + Send
+            + 'static,
+        S::Future: Send,
+        S::Error: Into<BoxError>,
+        B: HttpBody<Data = Bytes> + Send + 'static,
+        B::Error: Into<BoxError>,
+        R: Stream<Item = S> + Send + 'static,
+    {
+        let app = Arc::new(Mutex::new(app));
+        let signal = self
+            .signal
+            .unwrap_or_else(|| Box::pin(futures_util::future::pending()));
+        let max_connections = self.max_connections;
+        let settings = self.settings;
+        let drain_deadline = self.drain_deadline;
+        let handle = self.handle;
+        // Going idle is just another way to send the shutdown signal.
+        let signal: ShutdownSignal = match self.idle_timeout {
+            None => signal,
+            Some(timeout) => {
+                let handle = handle.clone();
+                Box::pin(async move {
+                    tokio::select! {
+                        _ = signal => {},
+                        _ = handle.idle_for(timeout) => {
+                            info!(target: "busride_rs", ?timeout, "no connections for a while; shutting down");
+                        },
+                    }
+                })
+            }
+        };
+        // So is running out of requests.
+        let signal: ShutdownSignal = match self.max_requests {
+            None => signal,
+            Some(max_requests) => {
+                let handle = handle.clone();
+                Box::pin(async move {
+                    tokio::select! {
+                        _ = signal => {},
+                        _ = handle.served(max_requests.get()) => {
+                            info!(target: "busride_rs", max_requests = max_requests.get(), "served our quota of requests; shutting down");
+                        },
+                    }
+                })
+            }
+        };
+        let mut listen = self.listen;
+        if listen.is_empty() {
+            listen.push(Listen::Fd(0));
+        }
+        // Set up every listener before serving on any of them, so a bad one
+        // fails fast instead of after the others are up and running.
+        let mut listeners = Vec::with_capacity(listen.len());
+        for l in listen {
+            let gateway = match l {
+                Listen::ScgiTcp(_) => Gateway::Scgi,
+                _ => Gateway::FastCgi,
+            };
+            let listener = match l {
+                Listen::Fd(fd) => AnyListener::Unix(adopt_unix_listener(fd)?),
+                Listen::Systemd => AnyListener::Unix(adopt_unix_listener(systemd_listen_fd()?)?),
+                Listen::Tcp(addr) => AnyListener::Tcp(bind_tcp_listener(addr).await?),
+                Listen::Unix(path) => {
+                    let (uid, gid) = self.unix_socket_owner;
+                    bind_unix_listener(&path, self.unix_socket_mode, uid, gid)?
+                }
+                #[cfg(target_os = "linux")]
+                Listen::UnixAbstract(name) => {
+                    AnyListener::Unix(bind_abstract_unix_listener(&name)?)
+                }
+                Listen::UnixListener(listener) => AnyListener::Unix(listener),
+                Listen::ScgiTcp(addr) => AnyListener::Tcp(bind_tcp_listener(addr).await?),
+            };
+            listeners.push((gateway, listener));
+        }
+        // Reloads happen off to the side too, whenever they happen.
+        let reloader = {
+            let app = app.clone();
+            tokio::spawn(async move {
+                let mut reloads = std::pin::pin!(reloads);
+                while let Some(new_app) = reloads.next().await {
+                    *app.lock().unwrap_or_else(|poisoned| poisoned.into_inner()) = new_app;
+                    info!(target: "busride_rs", "reloaded the app; new connections will use it");
+                }
+            })
+        };
+        // Warming up happens off to the side, so we can serve (well, 503) meanwhile.
+        let warm_up = self.ready_when.map(|ready| {
+            handle.set_ready(false);
+            let handle = handle.clone();
+            tokio::spawn(async move {
+                ready.await;
+                handle.set_ready(true);
+                info!(target: "busride_rs", "app is ready; serving requests");
+            })
+        });
+        let result = serve_listeners_with_graceful_shutdown(
+            app,
+            max_connections,
+            listeners,
+            settings,
+            signal,
+            drain_deadline,
+            handle,
+        )
+        .await;
+        reloader.abort();
+        // If we never got ready, there's no point waiting for it anymore.
+        if let Some(warm_up) = warm_up {
+            warm_up.abort();
+        }
+        result
+    }
+}
@@ -0,0 +1,497 @@
+//! Tuning knobs that don't deserve their own positional argument.
+use crate::{FcgiVars, RequestObserver};
+use bytes::Bytes;
+use std::num::NonZeroUsize;
+use std::ops::Deref;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Makes the tracing span for a request; see [`Settings::request_span`].
+pub(crate) type RequestSpanFn = dyn Fn(&FcgiVars) -> tracing::Span + Send + Sync;
+/// Adjusts a request before the app gets it; see [`Settings::on_request`].
+pub(crate) type RequestHookFn = dyn Fn(&mut http::request::Parts) + Send + Sync;
+/// Adjusts a response before we send it; see [`Settings::on_response`].
+pub(crate) type ResponseHookFn = dyn Fn(&mut http::response::Parts) + Send + Sync;
+
+/// Optional tuning for how requests get served. The defaults should be fine for
+/// most apps; to change them, start from [`Settings::new`], chain the setters you
+/// care about, and pass the result to [`serve_fcgid_with_settings`](crate::serve_fcgid_with_settings).
+#[derive(Debug, Clone)]
+pub struct Settings {
+    pub(crate) body_channel_capacity: NonZeroUsize,
+    pub(crate) max_body_bytes: Option<usize>,
+    pub(crate) role: Role,
+    pub(crate) request_timeout: Option<Duration>,
+    pub(crate) access_log: bool,
+    pub(crate) observer: Option<Shared<dyn RequestObserver>>,
+    pub(crate) path_from_path_info: bool,
+    pub(crate) max_header_count: usize,
+    pub(crate) max_header_bytes: usize,
+    pub(crate) max_header_value_bytes: usize,
+    pub(crate) stderr_diagnostics: bool,
+    pub(crate) request_span: Option<Shared<RequestSpanFn>>,
+    pub(crate) request_id_header: Option<http::HeaderName>,
+    pub(crate) generate_request_id: bool,
+    pub(crate) date_header: bool,
+    pub(crate) max_requests_per_connection: Option<NonZeroUsize>,
+    pub(crate) keep_alive: KeepAlive,
+    pub(crate) dump_bytes: bool,
+    pub(crate) required_var: Option<(String, String)>,
+    pub(crate) body_read_buffer: NonZeroUsize,
+    pub(crate) response_buffer: NonZeroUsize,
+    pub(crate) on_request: Option<Shared<RequestHookFn>>,
+    pub(crate) on_response: Option<Shared<ResponseHookFn>>,
+    pub(crate) health_check_path: Option<String>,
+    pub(crate) error_pages: Vec<ErrorPage>,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            body_channel_capacity: NonZeroUsize::new(16).unwrap(),
+            max_body_bytes: None,
+            role: Role::default(),
+            request_timeout: None,
+            access_log: false,
+            observer: None,
+            path_from_path_info: false,
+            max_header_count: 100,
+            max_header_bytes: 64 * 1024,
+            max_header_value_bytes: 16 * 1024,
+            stderr_diagnostics: false,
+            request_span: None,
+            request_id_header: Some(http::HeaderName::from_static("x-request-id")),
+            generate_request_id: false,
+            date_header: false,
+            max_requests_per_connection: None,
+            keep_alive: KeepAlive::default(),
+            dump_bytes: false,
+            required_var: None,
+            body_read_buffer: NonZeroUsize::new(64 * 1024).unwrap(),
+            response_buffer: NonZeroUsize::new(8 * 1024).unwrap(),
+            on_request: None,
+            on_response: None,
+            health_check_path: None,
+            error_pages: Vec::new(),
+        }
+    }
+}
+
+impl Settings {
+    /// Same as `Settings::default()`.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// How many chunks of request body can pile up between reading them off the
+    /// FastCGI connection and the app consuming them. Once that many are waiting,
+    /// we stop reading from the connection until the app catches up, so a fast
+    /// upload to a slow handler can't balloon the process's memory. (Chunks are
+    /// however much data was available per read, usually a FastCGI record's worth.)
+    ///
+    /// Defaults to 16.
+    pub fn body_channel_capacity(mut self, capacity: NonZeroUsize) -> Self {
+        self.body_channel_capacity = capacity;
+        self
+    }
+
+    /// The largest request body we'll pass along to the app, in bytes. Handy for
+    /// keeping one overeager upload from hogging a shared hosting account.
+    ///
+    /// If the request's `Content-Length` is already over the limit, we reply with
+    /// `413 Payload Too Large` right away, without reading the body or calling the
+    /// app. If the body turns out to be too large anyway (no `Content-Length`, or
+    /// one that undersold it), we stop forwarding it once we cross the limit and
+    /// the app's body stream ends with an error, so it can't mistake a truncated
+    /// body for a complete one. Most extractors will respond to that with a 400.
+    ///
+    /// Defaults to `None` (no limit).
+    pub fn max_body_bytes(mut self, limit: Option<usize>) -> Self {
+        self.max_body_bytes = limit;
+        self
+    }
+
+    /// Which FastCGI role the app plays. Requests for any other role get refused,
+    /// since they mean the web server's configured to use the app for something
+    /// it isn't expecting.
+    ///
+    /// Defaults to [`Role::Responder`].
+    pub fn role(mut self, role: Role) -> Self {
+        self.role = role;
+        self
+    }
+
+    /// How long to wait for the app to come up with a response before giving up on
+    /// it. When a request runs out the clock, we stop reading its body, drop the
+    /// app's in-progress future, and send `504 Gateway Timeout` instead, so one stuck
+    /// handler can't hold a connection (and one of its `max_connections` slots) forever.
+    ///
+    /// The clock covers the app reading the request body and producing its response
+    /// headers; once the response starts streaming, the timeout no longer applies.
+    ///
+    /// Defaults to `None` (wait as long as it takes).
+    pub fn request_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.request_timeout = timeout;
+        self
+    }
+
+    /// Whether to emit an `info`-level tracing event for every request we finish
+    /// serving, with the method, URI, status code, response size (headers and body
+    /// counted separately), and time taken
+    /// as structured fields. On shared hosting, your subscriber's output probably
+    /// ends up in the web server's error_log, which is why this is opt-in.
+    ///
+    /// Defaults to `false`.
+    pub fn access_log(mut self, enabled: bool) -> Self {
+        self.access_log = enabled;
+        self
+    }
+
+    /// Something to tell about every request as it starts and finishes, for
+    /// collecting metrics. When there's no observer (the default), this costs
+    /// nothing per request.
+    pub fn observer(mut self, observer: impl RequestObserver) -> Self {
+        let observer: Arc<dyn RequestObserver> = Arc::new(observer);
+        self.observer = Some(Shared(observer));
+        self
+    }
+
+    /// Build request paths from the CGI `PATH_INFO` var (plus the original query
+    /// string), instead of the full `REQUEST_URI`. The web server splits the
+    /// original path into `SCRIPT_NAME` (the part that got it to the app) and
+    /// `PATH_INFO` (the rest), so with this on, an app mounted at `/some/dir/`
+    /// sees a request for `/some/dir/hello` as a request for `/hello`, and can be
+    /// written as if it lived at the root of the domain. The mount point itself
+    /// goes in a [`ScriptName`](crate::ScriptName) request extension.
+    ///
+    /// The web server hands us `PATH_INFO` already percent-decoded, so we encode it
+    /// again; the app can decode the path exactly once, like always. An encoded slash
+    /// (`%2F`) comes out as a plain `/`, though, since that's all PATH_INFO says.
+    ///
+    /// Check what your web server actually puts in those vars before relying on this;
+    /// it depends on its config and on which parts of the path exist on disk.
+    ///
+    /// Defaults to `false`.
+    pub fn path_from_path_info(mut self, enabled: bool) -> Self {
+        self.path_from_path_info = enabled;
+        self
+    }
+
+    /// The most request headers we'll accept. Requests with more than this get a
+    /// `431 Request Header Fields Too Large` instead of reaching the app.
+    ///
+    /// Defaults to 100, which matches Apache's own `LimitRequestFields` default.
+    pub fn max_header_count(mut self, limit: usize) -> Self {
+        self.max_header_count = limit;
+        self
+    }
+
+    /// The most bytes of request headers (names plus values, all added up) we'll
+    /// accept. Requests with more than this get a `431` instead of reaching the app.
+    ///
+    /// Defaults to 64 KiB.
+    pub fn max_header_bytes(mut self, limit: usize) -> Self {
+        self.max_header_bytes = limit;
+        self
+    }
+
+    /// The most bytes we'll accept in any one request header's value. Requests
+    /// with a bigger one get a `431` instead of reaching the app. This is mostly
+    /// about `Cookie`: a client (or a site that got carried away setting cookies)
+    /// can send a huge one on every single request, and there's no point copying
+    /// it around just for the app to choke on it.
+    ///
+    /// Defaults to 16 KiB. That's twice Apache's own `LimitRequestFieldSize`,
+    /// since repeated headers reach us joined into one.
+    pub fn max_header_value_bytes(mut self, limit: usize) -> Self {
+        self.max_header_value_bytes = limit;
+        self
+    }
+
+    /// Whether to also send a short note about app failures (errors, timeouts)
+    /// down the FastCGI stderr stream, alongside the usual tracing event. The web
+    /// server writes that stream to its own error_log, tagged with the request, so
+    /// it's handy when you can't easily get at the app's own logs.
+    ///
+    /// Defaults to `false`.
+    pub fn stderr_diagnostics(mut self, enabled: bool) -> Self {
+        self.stderr_diagnostics = enabled;
+        self
+    }
+
+    /// How to make the tracing span that each request gets handled in, so that
+    /// everything the app logs while serving it can be traced back to it. By
+    /// default it's an `info`-level span named `request`, with `method` and `uri`
+    /// fields. Swap in your own if you want extra context, like a trace id from
+    /// a header (the [`FcgiVars`](crate::FcgiVars) have all of them, as `HTTP_*`).
+    pub fn request_span(
+        mut self,
+        make_span: impl Fn(&FcgiVars) -> tracing::Span + Send + Sync + 'static,
+    ) -> Self {
+        let make_span: Arc<RequestSpanFn> = Arc::new(make_span);
+        self.request_span = Some(Shared(make_span));
+        self
+    }
+
+    /// Which request header carries a request ID from whatever's in front of us,
+    /// for correlating our logs with the proxy's. When a request has one, we
+    /// record it as the `request_id` field on the request's tracing span, and echo
+    /// it back in the same header on the response (unless the app already set
+    /// that header itself). Custom [`request_span`](Self::request_span)s need to
+    /// declare a `request_id = tracing::field::Empty` field to get it.
+    ///
+    /// This is the way to correlate with the web server's logs; the FastCGI
+    /// protocol's own request IDs won't help there, since they're only unique
+    /// within a connection (mod_fcgid always uses 1). With Apache, `mod_unique_id`
+    /// plus `RequestHeader set X-Request-Id "%{UNIQUE_ID}e"` gets you a header
+    /// that matches `%{UNIQUE_ID}e` in its log format.
+    ///
+    /// Defaults to `x-request-id`. Set it to None to ignore request IDs entirely.
+    pub fn request_id_header(mut self, header: Option<http::HeaderName>) -> Self {
+        self.request_id_header = header;
+        self
+    }
+
+    /// Whether to make up a request ID when a request arrives without one (in
+    /// the [`request_id_header`](Self::request_id_header)). The made-up ID gets
+    /// logged and echoed like a real one, and the app sees it as a request header.
+    ///
+    /// Defaults to `false`.
+    pub fn generate_request_id(mut self, enabled: bool) -> Self {
+        self.generate_request_id = enabled;
+        self
+    }
+
+    /// Whether to add a `Date` header to responses that don't already have one,
+    /// like a normal HTTP server would. Apache adds one itself, but some FastCGI
+    /// clients (nginx, for one) pass along whatever we send them, so a response
+    /// without one reaches the client without one.
+    ///
+    /// Defaults to `false`, in case you're deliberately in charge of your headers.
+    pub fn date_header(mut self, enabled: bool) -> Self {
+        self.date_header = enabled;
+        self
+    }
+
+    /// The most requests a single connection can have in progress at once. FastCGI
+    /// lets a client multiplex several requests over one connection (mod_fcgid
+    /// never does, but other clients might); past this limit, that connection's
+    /// extra requests wait their turn, so one busy connection can't crowd out the
+    /// others.
+    ///
+    /// This is about requests at the same time; for how many a connection gets
+    /// to serve one after another, see [`keep_alive`](Self::keep_alive).
+    ///
+    /// Defaults to `None` (no limit besides whatever the client does).
+    pub fn max_requests_per_connection(mut self, limit: Option<NonZeroUsize>) -> Self {
+        self.max_requests_per_connection = limit;
+        self
+    }
+
+    /// Whether a FastCGI connection stays open for more requests after it's served
+    /// one. The web server asks for that with the "keep conn" flag on each request,
+    /// and by default we do what it asks. On a host that's short on fds, or to make
+    /// long-lived connections get rebalanced every so often, you can close them
+    /// sooner: after every request, or after a connection's served so many. When
+    /// the web server didn't ask to keep the connection, it gets closed regardless.
+    ///
+    /// fastcgi-server is the one that honors the flag, and it doesn't let us change
+    /// its mind, so we close a connection by ending its input once it's used up its
+    /// requests and has none left in progress (its last response is already on its
+    /// way by then). The web server sees that as us hanging up between requests,
+    /// which it has to be ready for anyway. It's cheaper to have the web server not
+    /// ask in the first place, if you can: nginx doesn't unless you say
+    /// `fastcgi_keep_conn on`, and Apache's `mod_proxy_fcgi` stops with `disablereuse=On`.
+    ///
+    /// Only affects FastCGI; SCGI and CGI are one request per connection anyway.
+    ///
+    /// Defaults to [`KeepAlive::Auto`].
+    pub fn keep_alive(mut self, policy: KeepAlive) -> Self {
+        self.keep_alive = policy;
+        self
+    }
+
+    /// Whether to log the raw bytes of every request body and response (headers
+    /// included) as `trace`-level events, for debugging an integration with a
+    /// finicky FastCGI client. Non-printable bytes come out escaped.
+    ///
+    /// **This logs everything:** passwords, cookies, session tokens, uploaded
+    /// files. Only turn it on while you're actively debugging, and don't leave
+    /// those logs lying around afterwards. It's also slow, with big bodies.
+    ///
+    /// Defaults to `false`, which costs nothing.
+    pub fn dump_bytes(mut self, enabled: bool) -> Self {
+        self.dump_bytes = enabled;
+        self
+    }
+
+    /// Refuse any request (with a `403 Forbidden`) unless the web server set this CGI
+    /// var to this value. The classic is `require_var("REDIRECT_STATUS", "200")`,
+    /// which PHP uses to make sure requests came through the web server's handler
+    /// config instead of poking the app directly; it's worth doing if your socket
+    /// is reachable by anything other than the web server.
+    ///
+    /// Defaults to no requirement.
+    pub fn require_var(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.required_var = Some((name.into(), value.into()));
+        self
+    }
+
+    /// How big a buffer to read the request body into, in bytes. Each chunk we
+    /// hand the app is at most this big, so for big uploads, a bigger buffer means
+    /// fewer, larger chunks and less per-chunk overhead; with the default, a chunk
+    /// can hold a whole FastCGI record, which is as much as the web server sends
+    /// at a time anyway. Every request in flight has one of these, so don't go wild.
+    ///
+    /// Defaults to 64 KiB.
+    pub fn body_read_buffer(mut self, bytes: NonZeroUsize) -> Self {
+        self.body_read_buffer = bytes;
+        self
+    }
+
+    /// How big a buffer to write each response through, in bytes. Whenever it
+    /// fills up, what's in it goes out to the web server as FastCGI records (up to
+    /// 64 KiB apiece), so for big responses, a bigger buffer means fewer records
+    /// and fewer syscalls; for lots of tiny responses, a smaller one saves memory,
+    /// since every response in flight has one. Streaming responses get flushed
+    /// chunk by chunk regardless, so this doesn't hold up their events.
+    ///
+    /// Defaults to 8 KiB, which is what futures-util's `BufWriter` picks on its own.
+    pub fn response_buffer(mut self, bytes: NonZeroUsize) -> Self {
+        self.response_buffer = bytes;
+        self
+    }
+
+    /// Something to run on every request's head (method, URI, headers, and
+    /// extensions) after we've built it and before the app sees it. Handy for
+    /// stripping a header the web server adds, or renaming a vendor-specific one,
+    /// without writing a whole middleware for it. The [`FcgiVars`] are already in
+    /// the extensions, if you need them.
+    pub fn on_request(
+        mut self,
+        hook: impl Fn(&mut http::request::Parts) + Send + Sync + 'static,
+    ) -> Self {
+        let hook: Arc<RequestHookFn> = Arc::new(hook);
+        self.on_request = Some(Shared(hook));
+        self
+    }
+
+    /// Something to run on every response's head (status, headers, extensions)
+    /// right before we send it, for adding or removing headers in one place:
+    /// security headers, say, or stripping a `Server` header. That includes the
+    /// responses we make up ourselves (timeouts, rejected requests) as well as the
+    /// app's, and it runs after we've added our own headers, so it gets the last word.
+    pub fn on_response(
+        mut self,
+        hook: impl Fn(&mut http::response::Parts) + Send + Sync + 'static,
+    ) -> Self {
+        let hook: Arc<ResponseHookFn> = Arc::new(hook);
+        self.on_response = Some(Shared(hook));
+        self
+    }
+
+    /// A request path we'll answer with a bare `200 OK` ourselves, without calling
+    /// the app at all, for monitoring that shouldn't touch the app's state (or its
+    /// database). Like for the app, the path we match against comes from
+    /// `REQUEST_URI`, or from `PATH_INFO` if [`path_from_path_info`](Self::path_from_path_info)
+    /// is on; the query string doesn't count. While the server's still warming up
+    /// (see [`FcgiServer::ready_when`](crate::FcgiServer::ready_when)), it gets a 503
+    /// like everything else, since that's the truth.
+    ///
+    /// Defaults to `None`.
+    pub fn health_check_path(mut self, path: Option<String>) -> Self {
+        self.health_check_path = path;
+        self
+    }
+
+    /// A body to use for the app's responses with this status, when the app didn't
+    /// give them one of its own. Axum's 404 for an unmatched route is empty, for
+    /// example, so this is a way to get a branded "not found" page on every app
+    /// without adding a fallback route to each one. Only empty bodies get replaced,
+    /// so a response the app went to the trouble of filling in goes out as-is. We
+    /// set the `Content-Type` to match the new body.
+    ///
+    /// Call it once per status; calling it again for the same status replaces the
+    /// earlier page. It only covers responses from the app, not the ones we make up
+    /// ourselves (timeouts and such). Defaults to no pages.
+    pub fn error_page(
+        mut self,
+        status: http::StatusCode,
+        content_type: http::HeaderValue,
+        body: impl Into<Bytes>,
+    ) -> Self {
+        self.error_pages.retain(|page| page.status != status);
+        self.error_pages.push(ErrorPage {
+            status,
+            content_type,
+            body: body.into(),
+        });
+        self
+    }
+}
+
+/// How long a FastCGI connection stays open; see [`Settings::keep_alive`].
+///
+/// There's no option to keep a connection open when the web server didn't ask for
+/// it: without the "keep conn" flag, the FastCGI spec makes closing it our job, and
+/// the web server isn't going to send anything else down it anyway.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum KeepAlive {
+    /// Keep connections open for as long as the web server asks.
+    #[default]
+    Auto,
+    /// Close every connection after its first request.
+    Never,
+    /// Close a connection once it's served this many requests.
+    UpTo(NonZeroUsize),
+}
+
+/// The FastCGI roles an app can play.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Role {
+    /// The normal one: handle the request and send a response to the client.
+    #[default]
+    Responder,
+    /// Gatekeep access to something else the web server serves, like static files
+    /// (with mod_fcgid, that's the `FcgidAuthorizer` directive). The app gets the
+    /// request's metadata (but no body) and decides whether to let it through:
+    ///
+    /// - Any 2xx response means "authorized," and gets sent to the web server as
+    ///   a bodyless `200 OK`. Only the `Variable-*` response headers survive; the web
+    ///   server can pass them along as environment variables (minus the `Variable-`
+    ///   prefix) to whatever ends up handling the request.
+    /// - Anything else is a denial, which the web server sends to the client as-is.
+    Authorizer,
+}
+
+/// A stand-in body for one status; see [`Settings::error_page`].
+#[derive(Debug, Clone)]
+pub(crate) struct ErrorPage {
+    pub(crate) status: http::StatusCode,
+    pub(crate) content_type: http::HeaderValue,
+    pub(crate) body: Bytes,
+}
+
+/// A shared, opaque value that can live in Settings without stopping it from
+/// being Clone + Debug. For trait objects and closures, mostly.
+pub(crate) struct Shared<T: ?Sized>(pub(crate) Arc<T>);
+
+impl<T: ?Sized> Clone for Shared<T> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<T: ?Sized> std::fmt::Debug for Shared<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Shared(..)")
+    }
+}
+
+impl<T: ?Sized> Deref for Shared<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
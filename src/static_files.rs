@@ -0,0 +1,380 @@
+//! Serving static files from the web server's `DOCUMENT_ROOT`, for when every
+//! request gets routed to the app (a catch-all rewrite rule in an `.htaccess`,
+//! say) but some of them are for plain old files.
+//!
+//! Only available with the `static-files` feature. It's a helper you call, not
+//! something the serve functions do on their own, so nothing changes unless you
+//! put it somewhere in your app:
+//!
+//! ```ignore
+//! use busride_rs::static_files::StaticFiles;
+//! let assets = StaticFiles::new("/assets");
+//! let app = Router::new()
+//!     .route("/", get(home))
+//!     .fallback(move |req: axum::extract::Request| async move {
+//!         match assets.respond(&req).await {
+//!             Some(resp) => resp,
+//!             None => StatusCode::NOT_FOUND.into_response(),
+//!         }
+//!     });
+//! ```
+use crate::FcgiVars;
+use axum::body::Body;
+use std::ffi::OsStr;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use tracing::warn;
+
+/// Serves the files under a URL prefix from the same paths under the document
+/// root, the way the web server would have if nothing had rewritten the request:
+/// `/assets/site.css` comes from `$DOCUMENT_ROOT/assets/site.css`.
+///
+/// By default we read and stream the files ourselves. With a
+/// [`sendfile_header`](Self::sendfile_header), we just check that the file's there
+/// and answer with an empty body and a header naming it, and the web server sends
+/// the file itself, which it's better at.
+#[derive(Debug, Clone)]
+pub struct StaticFiles {
+    /// With a leading slash and no trailing one; empty means everything.
+    url_prefix: String,
+    root: Option<PathBuf>,
+    sendfile_header: Option<http::HeaderName>,
+}
+
+impl StaticFiles {
+    /// Serve files for requests under this URL prefix (`"/assets"`, say; `"/"`
+    /// means every request, which is rarely what you want).
+    pub fn new(url_prefix: &str) -> Self {
+        let trimmed = url_prefix.trim_matches('/');
+        let url_prefix = if trimmed.is_empty() {
+            String::new()
+        } else {
+            format!("/{}", trimmed)
+        };
+        Self {
+            url_prefix,
+            root: None,
+            sendfile_header: None,
+        }
+    }
+
+    /// Look for files here instead of in `DOCUMENT_ROOT`. You'll need this in
+    /// plain HTTP mode, where there's no `DOCUMENT_ROOT` to go by.
+    pub fn root(mut self, root: impl Into<PathBuf>) -> Self {
+        self.root = Some(root.into());
+        self
+    }
+
+    /// Hand files off to the web server with this header, naming the file's full
+    /// path: `X-Sendfile` for Apache's `mod_xsendfile`, or `X-LIGHTTPD-send-file`
+    /// for lighttpd. The web server has to be set up to honor it (and to allow
+    /// the document root), or clients get empty responses. nginx's
+    /// `X-Accel-Redirect` wants a URI instead of a path, so it doesn't fit here.
+    pub fn sendfile_header(mut self, name: http::HeaderName) -> Self {
+        self.sendfile_header = Some(name);
+        self
+    }
+
+    /// Answer a request with a file, if it's one of ours: a GET or HEAD under the
+    /// URL prefix, with a document root to look in. Returns None for anything
+    /// else, so the app can carry on with it. Missing files get a `404`, and
+    /// paths that try to climb out of the document root (with `..`) get a `400`.
+    ///
+    /// We go by the path the app sees. If a [`MountLayer`](crate::MountLayer)
+    /// stripped a mount point off it, we use the original instead, since that's
+    /// what matches the files on disk.
+    pub async fn respond<B>(&self, req: &http::Request<B>) -> Option<http::Response<Body>> {
+        if req.method() != http::Method::GET && req.method() != http::Method::HEAD {
+            return None;
+        }
+        let path = match req.extensions().get::<axum::extract::OriginalUri>() {
+            Some(original) => original.path(),
+            None => req.uri().path(),
+        };
+        let rest = path.strip_prefix(self.url_prefix.as_str())?;
+        if !(rest.is_empty() || rest.starts_with('/')) {
+            return None;
+        }
+        let root = match &self.root {
+            Some(root) => root.clone(),
+            None => {
+                let vars = req.extensions().get::<FcgiVars>()?;
+                PathBuf::from(OsStr::from_bytes(vars.get("DOCUMENT_ROOT")?))
+            }
+        };
+        let Some(file) = file_under(&root, path) else {
+            return Some(status_response(http::StatusCode::BAD_REQUEST));
+        };
+        Some(self.serve_file(&file).await)
+    }
+
+    async fn serve_file(&self, file: &Path) -> http::Response<Body> {
+        let metadata = match tokio::fs::metadata(file).await {
+            Ok(m) if m.is_file() => m,
+            Ok(_) => return status_response(http::StatusCode::NOT_FOUND),
+            Err(e) => return error_response(file, e),
+        };
+        let Some(header) = &self.sendfile_header else {
+            return stream_file(file, metadata.len()).await;
+        };
+        let Ok(path) = http::HeaderValue::from_bytes(file.as_os_str().as_bytes()) else {
+            // Can't name it in a header, so we'll have to send it ourselves.
+            return stream_file(file, metadata.len()).await;
+        };
+        let mut resp = http::Response::new(Body::empty());
+        let headers = resp.headers_mut();
+        headers.insert(header.clone(), path);
+        headers.insert(http::header::CONTENT_TYPE, content_type_for(file));
+        resp
+    }
+}
+
+/// Read a file out to the client ourselves.
+async fn stream_file(file: &Path, len: u64) -> http::Response<Body> {
+    let opened = match tokio::fs::File::open(file).await {
+        Ok(f) => f,
+        Err(e) => return error_response(file, e),
+    };
+    let body = Body::from_stream(tokio_util::io::ReaderStream::new(opened));
+    let mut resp = http::Response::new(body);
+    let headers = resp.headers_mut();
+    headers.insert(http::header::CONTENT_TYPE, content_type_for(file));
+    headers.insert(http::header::CONTENT_LENGTH, http::HeaderValue::from(len));
+    resp
+}
+
+/// Where a URL path lives under the root, or None if it tries to go anywhere
+/// else. The path's still percent-encoded, the way it came in.
+fn file_under(root: &Path, url_path: &str) -> Option<PathBuf> {
+    let decoded = percent_decode(url_path)?;
+    let mut file = root.to_path_buf();
+    for segment in decoded.split(|b| *b == b'/') {
+        match segment {
+            b"" | b"." => {}
+            b".." => return None,
+            s if s.contains(&0) => return None,
+            s => file.push(OsStr::from_bytes(s)),
+        }
+    }
+    Some(file)
+}
+
+/// Undo percent-encoding. None if there's a broken escape, since no file's
+/// going to match that anyway.
+fn percent_decode(s: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(s.len());
+    let mut bytes = s.bytes();
+    while let Some(b) = bytes.next() {
+        if b == b'%' {
+            let hex = [bytes.next()?, bytes.next()?];
+            // from_str_radix would let a sign slip through, so check first.
+            if !hex.iter().all(u8::is_ascii_hexdigit) {
+                return None;
+            }
+            let hex = std::str::from_utf8(&hex).ok()?;
+            out.push(u8::from_str_radix(hex, 16).ok()?);
+        } else {
+            out.push(b);
+        }
+    }
+    Some(out)
+}
+
+/// A guess at a file's type from its extension, covering what usually ends up
+/// in an assets directory. Anything else is just bytes.
+fn content_type_for(file: &Path) -> http::HeaderValue {
+    let ext = file
+        .extension()
+        .and_then(OsStr::to_str)
+        .map(str::to_ascii_lowercase);
+    let mime = match ext.as_deref() {
+        Some("html" | "htm") => "text/html; charset=utf-8",
+        Some("css") => "text/css; charset=utf-8",
+        Some("js" | "mjs") => "text/javascript; charset=utf-8",
+        Some("json") => "application/json",
+        Some("txt") => "text/plain; charset=utf-8",
+        Some("xml") => "application/xml",
+        Some("svg") => "image/svg+xml",
+        Some("png") => "image/png",
+        Some("jpg" | "jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("webp") => "image/webp",
+        Some("avif") => "image/avif",
+        Some("ico") => "image/x-icon",
+        Some("woff") => "font/woff",
+        Some("woff2") => "font/woff2",
+        Some("pdf") => "application/pdf",
+        Some("wasm") => "application/wasm",
+        _ => "application/octet-stream",
+    };
+    http::HeaderValue::from_static(mime)
+}
+
+/// The response for a file we couldn't get at.
+fn error_response(file: &Path, e: io::Error) -> http::Response<Body> {
+    match e.kind() {
+        io::ErrorKind::NotFound => status_response(http::StatusCode::NOT_FOUND),
+        io::ErrorKind::PermissionDenied => {
+            warn!(
+                target: "busride_rs",
+                blame = "filesystem permissions",
+                file = %file.display(),
+                "Can't read static file; sending a 403"
+            );
+            status_response(http::StatusCode::FORBIDDEN)
+        }
+        _ => {
+            warn!(
+                target: "busride_rs",
+                blame = "filesystem",
+                file = %file.display(),
+                "Couldn't read static file; sending a 500: {}",
+                e
+            );
+            status_response(http::StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+fn status_response(status: http::StatusCode) -> http::Response<Body> {
+    let mut resp = http::Response::new(Body::empty());
+    *resp.status_mut() = status;
+    resp
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn under_root(url_path: &str) -> Option<String> {
+        file_under(Path::new("/srv/www"), url_path).map(|p| p.to_string_lossy().into_owned())
+    }
+
+    /// A document root with one file in it, cleaned up on drop.
+    struct DocRoot(PathBuf);
+
+    impl DocRoot {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!(
+                "busride-static-{}-{}",
+                name,
+                std::process::id()
+            ));
+            std::fs::create_dir_all(dir.join("assets")).unwrap();
+            std::fs::write(dir.join("assets/site.css"), b"body {}").unwrap();
+            Self(dir)
+        }
+    }
+
+    impl Drop for DocRoot {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    async fn respond(files: &StaticFiles, method: &str, uri: &str) -> Option<(u16, Vec<u8>)> {
+        let req = http::Request::builder()
+            .method(method)
+            .uri(uri)
+            .body(())
+            .unwrap();
+        let resp = files.respond(&req).await?;
+        let status = resp.status().as_u16();
+        let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        Some((status, body.to_vec()))
+    }
+
+    #[test]
+    fn paths_land_under_the_root() {
+        assert_eq!(
+            under_root("/assets/site.css").as_deref(),
+            Some("/srv/www/assets/site.css")
+        );
+        assert_eq!(under_root("/a/./b//c").as_deref(), Some("/srv/www/a/b/c"));
+        assert_eq!(
+            under_root("/a%20b.txt").as_deref(),
+            Some("/srv/www/a b.txt")
+        );
+    }
+
+    #[test]
+    fn climbing_out_is_refused() {
+        assert_eq!(under_root("/../etc/passwd"), None);
+        assert_eq!(under_root("/assets/../../etc/passwd"), None);
+        // Escapes get decoded before we look at the segments, so these count too.
+        assert_eq!(under_root("/assets/%2e%2e/%2E%2E/etc/passwd"), None);
+        assert_eq!(under_root("/assets/..%2f..%2fetc/passwd"), None);
+    }
+
+    #[test]
+    fn embedded_nuls_are_refused() {
+        assert_eq!(under_root("/assets/site.css%00.png"), None);
+    }
+
+    #[test]
+    fn broken_escapes_are_refused() {
+        assert_eq!(percent_decode("/a%"), None);
+        assert_eq!(percent_decode("/a%2"), None);
+        assert_eq!(percent_decode("/a%zz"), None);
+        assert_eq!(percent_decode("/a%+1"), None);
+        assert_eq!(under_root("/assets/%g0.css"), None);
+        assert_eq!(percent_decode("/a%2Fb%41").as_deref(), Some(&b"/a/bA"[..]));
+    }
+
+    #[tokio::test]
+    async fn files_under_the_prefix_are_served() {
+        let root = DocRoot::new("served");
+        let files = StaticFiles::new("/assets/").root(&root.0);
+        let (status, body) = respond(&files, "GET", "/assets/site.css").await.unwrap();
+        assert_eq!(status, 200);
+        assert_eq!(body, b"body {}");
+        let (status, _) = respond(&files, "GET", "/assets/missing.css").await.unwrap();
+        assert_eq!(status, 404);
+        let (status, _) = respond(&files, "GET", "/assets/%2e%2e/assets/site.css")
+            .await
+            .unwrap();
+        assert_eq!(status, 400);
+    }
+
+    #[tokio::test]
+    async fn the_prefix_has_to_match_a_whole_segment() {
+        let root = DocRoot::new("prefix");
+        std::fs::write(root.0.join("assetsfoo"), b"not an asset").unwrap();
+        let files = StaticFiles::new("/assets").root(&root.0);
+        assert_eq!(respond(&files, "GET", "/assetsfoo").await, None);
+        assert_eq!(respond(&files, "GET", "/other/site.css").await, None);
+        // The prefix itself is under the prefix, but it's a directory, not a file.
+        assert_eq!(respond(&files, "GET", "/assets").await.unwrap().0, 404);
+    }
+
+    #[tokio::test]
+    async fn only_gets_and_heads_are_ours() {
+        let root = DocRoot::new("methods");
+        let files = StaticFiles::new("/assets").root(&root.0);
+        assert_eq!(respond(&files, "POST", "/assets/site.css").await, None);
+        assert_eq!(
+            respond(&files, "HEAD", "/assets/site.css").await.unwrap().0,
+            200
+        );
+    }
+
+    #[tokio::test]
+    async fn the_root_defaults_to_document_root() {
+        let root = DocRoot::new("docroot");
+        let files = StaticFiles::new("/assets");
+        let mut req = http::Request::get("/assets/site.css").body(()).unwrap();
+        // Without any CGI vars, there's nowhere to look.
+        assert!(files.respond(&req).await.is_none());
+        // FcgiVars only collects from 'static strs.
+        let docroot: &'static str = Box::leak(root.0.to_str().unwrap().into());
+        let vars: FcgiVars = [("DOCUMENT_ROOT", docroot)].into_iter().collect();
+        req.extensions_mut().insert(vars);
+        assert_eq!(
+            files.respond(&req).await.unwrap().status(),
+            http::StatusCode::OK
+        );
+    }
+}
@@ -0,0 +1,516 @@
+//! An in-process FastCGI client, for testing apps (and this crate) without a real
+//! web server. Build a [`TestRequest`] the way Apache would, [`send`](TestRequest::send)
+//! it to your app, and poke at the [`TestResponse`]. Everything in between is the
+//! real thing: actual FastCGI records over a socket pair, through the same accept
+//! loop and handler that serve production traffic.
+//!
+//! Only available with the `testing` feature, so you'll usually want it in your
+//! dev-dependencies:
+//!
+//! ```toml
+//! [dev-dependencies]
+//! busride-rs = { version = "...", features = ["testing"] }
+//! ```
+//!
+//! [`TestRequest::send`] sets up a whole server for every request, which is the
+//! least fuss for a one-off. When you're sending lots of them (in a benchmark,
+//! say), start a [`TestServer`] once and [`send`](TestServer::send) them all
+//! through that instead. Those each get a connection of their own; for several on
+//! one connection, [`connect`](TestServer::connect) to it.
+use crate::{serve_loop, Listener, Role, ServerHandle, Settings};
+use axum::BoxError;
+use bytes::Bytes;
+use fastcgi_server::Config;
+use http_body::Body as HttpBody;
+use std::io;
+use std::num::NonZeroUsize;
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::UnixStream;
+use tokio::sync::mpsc;
+use tokio::task::{JoinHandle, JoinSet};
+use tower::Service;
+
+// Record types, from the FastCGI spec.
+const FCGI_BEGIN_REQUEST: u8 = 1;
+const FCGI_END_REQUEST: u8 = 3;
+const FCGI_PARAMS: u8 = 4;
+const FCGI_STDIN: u8 = 5;
+const FCGI_STDOUT: u8 = 6;
+const FCGI_STDERR: u8 = 7;
+const FCGI_GET_VALUES: u8 = 9;
+const FCGI_GET_VALUES_RESULT: u8 = 10;
+/// We only ever have one request at a time on a connection, so it always gets the
+/// same id.
+const REQUEST_ID: u16 = 1;
+/// The BeginRequest flag asking the server to keep the connection open afterwards.
+const FCGI_KEEP_CONN: u8 = 1;
+
+/// A FastCGI request, as a web server would send it. [`new`](Self::new) fills in
+/// the CGI vars a typical server sets; add headers, body, and any other vars on top.
+#[derive(Debug, Clone)]
+pub struct TestRequest {
+    role: Role,
+    params: Vec<(String, Vec<u8>)>,
+    stdin: Vec<u8>,
+}
+
+impl TestRequest {
+    /// A request with the given method and URI (path plus optional query), as
+    /// if it arrived at `http://localhost/` and the app were mounted at the root.
+    pub fn new(method: &str, uri: &str) -> Self {
+        let (path, query) = uri.split_once('?').unwrap_or((uri, ""));
+        Self {
+            role: Role::Responder,
+            params: Vec::new(),
+            stdin: Vec::new(),
+        }
+        .var("GATEWAY_INTERFACE", "CGI/1.1")
+        .var("SERVER_PROTOCOL", "HTTP/1.1")
+        .var("SERVER_NAME", "localhost")
+        .var("SERVER_PORT", "80")
+        .var("REMOTE_ADDR", "127.0.0.1")
+        .var("REMOTE_PORT", "54321")
+        .var("REQUEST_METHOD", method)
+        .var("REQUEST_URI", uri)
+        .var("SCRIPT_NAME", "")
+        .var("PATH_INFO", path)
+        .var("QUERY_STRING", query)
+    }
+
+    /// Send the request in this role instead of as a Responder. Remember to give
+    /// the server matching [`Settings::role`].
+    pub fn role(mut self, role: Role) -> Self {
+        self.role = role;
+        self
+    }
+
+    /// Set a CGI var, replacing any previous value.
+    pub fn var(mut self, name: &str, value: impl AsRef<[u8]>) -> Self {
+        let value = value.as_ref().to_vec();
+        match self.params.iter_mut().find(|(n, _)| n == name) {
+            Some((_, v)) => *v = value,
+            None => self.params.push((name.to_string(), value)),
+        }
+        self
+    }
+
+    /// Set a request header, translated into a CGI var the way web servers do it
+    /// (`X-Thing` becomes `HTTP_X_THING`, and so on).
+    pub fn header(self, name: &str, value: impl AsRef<[u8]>) -> Self {
+        let var = name.to_ascii_uppercase().replace('-', "_");
+        match var.as_str() {
+            "CONTENT_TYPE" | "CONTENT_LENGTH" => self.var(&var, value),
+            _ => self.var(&format!("HTTP_{}", var), value),
+        }
+    }
+
+    /// Set the request body, along with a matching `CONTENT_LENGTH`.
+    pub fn body(mut self, body: impl Into<Vec<u8>>) -> Self {
+        self.stdin = body.into();
+        let len = self.stdin.len().to_string();
+        self.var("CONTENT_LENGTH", len)
+    }
+
+    /// Set the request body without a `CONTENT_LENGTH` (dropping any earlier one),
+    /// the way some web servers pass along chunked uploads.
+    pub fn body_without_length(mut self, body: impl Into<Vec<u8>>) -> Self {
+        self.stdin = body.into();
+        self.params.retain(|(name, _)| name != "CONTENT_LENGTH");
+        self
+    }
+
+    /// Serve this request with an app, and collect what comes back. This starts
+    /// a [`TestServer`] just for the one request.
+    ///
+    /// Errors: Returns an error if the connection broke before the request was
+    /// finished, which usually means the server end gave up on it entirely.
+    pub async fn send<S, B>(self, app: S, settings: Settings) -> io::Result<TestResponse>
+    where
+        S: Service<http::Request<axum::body::Body>, Response = http::Response<B>>
+            + Clone
+            + Send
+            + 'static,
+        S::Future: Send,
+        S::Error: Into<BoxError>,
+        B: HttpBody<Data = Bytes> + Send + 'static,
+        B::Error: Into<BoxError>,
+    {
+        TestServer::new(app, settings).send(self).await
+    }
+
+    /// Play the web server's part in the conversation. Unless `keep_conn`, the
+    /// server's supposed to close the connection afterwards.
+    async fn exchange(self, client: &mut UnixStream, keep_conn: bool) -> io::Result<TestResponse> {
+        let role: u16 = match self.role {
+            Role::Responder => 1,
+            Role::Authorizer => 2,
+        };
+        let mut out = Vec::new();
+        let flags = if keep_conn { FCGI_KEEP_CONN } else { 0 };
+        let [role_hi, role_lo] = role.to_be_bytes();
+        write_record(
+            &mut out,
+            FCGI_BEGIN_REQUEST,
+            &[role_hi, role_lo, flags, 0, 0, 0, 0, 0],
+        );
+        let mut params = Vec::new();
+        for (name, value) in &self.params {
+            write_length(&mut params, name.len());
+            write_length(&mut params, value.len());
+            params.extend_from_slice(name.as_bytes());
+            params.extend_from_slice(value);
+        }
+        write_stream(&mut out, FCGI_PARAMS, &params);
+        // Authorizers don't get a request body, not even an empty one.
+        if self.role == Role::Responder {
+            write_stream(&mut out, FCGI_STDIN, &self.stdin);
+        }
+        client.write_all(&out).await?;
+
+        let mut resp = TestResponse::default();
+        loop {
+            let mut header = [0u8; 8];
+            client.read_exact(&mut header).await?;
+            let kind = header[1];
+            let content_len = u16::from_be_bytes([header[4], header[5]]) as usize;
+            let padding_len = header[6] as usize;
+            let mut content = vec![0u8; content_len + padding_len];
+            client.read_exact(&mut content).await?;
+            content.truncate(content_len);
+            match kind {
+                FCGI_STDOUT => resp.stdout.extend_from_slice(&content),
+                FCGI_STDERR => resp.stderr.extend_from_slice(&content),
+                FCGI_END_REQUEST => {
+                    if let Some(status) = content.get(..4) {
+                        resp.app_status = u32::from_be_bytes(status.try_into().unwrap());
+                    }
+                    return Ok(resp);
+                }
+                _ => {} // nothing else should show up, but it doesn't matter if it does
+            }
+        }
+    }
+}
+
+/// What the app sent back to the "web server", in raw CGI form.
+#[derive(Debug, Clone, Default)]
+pub struct TestResponse {
+    stdout: Vec<u8>,
+    stderr: Vec<u8>,
+    app_status: u32,
+}
+
+impl TestResponse {
+    /// Everything written to the stdout stream: CGI headers, blank line, body.
+    pub fn raw(&self) -> &[u8] {
+        &self.stdout
+    }
+
+    /// Everything written to the stderr stream.
+    pub fn stderr(&self) -> &[u8] {
+        &self.stderr
+    }
+
+    /// The exit status from the end-of-request record. 0 means the request was
+    /// handled, even if the answer was an HTTP error.
+    pub fn app_status(&self) -> u32 {
+        self.app_status
+    }
+
+    /// The CGI header lines, as (name, value) pairs, in the order they were sent.
+    /// That includes the `Status` pseudo-header, if there was one.
+    pub fn headers(&self) -> Vec<(String, String)> {
+        let (head, _) = self.split();
+        String::from_utf8_lossy(head)
+            .lines()
+            .filter_map(|line| line.split_once(':'))
+            .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+            .collect()
+    }
+
+    /// The first value of the named header (case-insensitively), if any.
+    pub fn header(&self, name: &str) -> Option<String> {
+        self.headers()
+            .into_iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v)
+    }
+
+    /// The HTTP status the web server would send, from the CGI `Status` header.
+    /// CGI says a response without one is a 200. Returns None if nothing at all
+    /// was written to stdout.
+    pub fn status(&self) -> Option<http::StatusCode> {
+        if self.stdout.is_empty() {
+            return None;
+        }
+        match self.header("Status") {
+            Some(status) => {
+                let code = status.split_whitespace().next()?;
+                http::StatusCode::from_bytes(code.as_bytes()).ok()
+            }
+            None => Some(http::StatusCode::OK),
+        }
+    }
+
+    /// The response body: whatever came after the blank line.
+    pub fn body(&self) -> &[u8] {
+        self.split().1
+    }
+
+    /// Split stdout at the end of the header block.
+    fn split(&self) -> (&[u8], &[u8]) {
+        for (sep, len) in [(&b"\r\n\r\n"[..], 4), (&b"\n\n"[..], 2)] {
+            if let Some(at) = self.stdout.windows(len).position(|w| w == sep) {
+                return (&self.stdout[..at], &self.stdout[at + len..]);
+            }
+        }
+        (&self.stdout, &[])
+    }
+}
+
+/// A server running an app in the background, for sending it lots of
+/// [`TestRequest`]s without setting everything up again for each one. Each request
+/// gets a fresh connection (like mod_fcgid, which doesn't reuse them), but the
+/// accept loop, the app, and the settings stick around between them. The server
+/// stops when this is dropped.
+pub struct TestServer {
+    connect: mpsc::UnboundedSender<UnixStream>,
+    task: JoinHandle<()>,
+}
+
+impl TestServer {
+    /// How many requests the server will handle at once, if you send them
+    /// concurrently. Any more wait their turn.
+    const MAX_CONNECTIONS: NonZeroUsize = NonZeroUsize::new(16).unwrap();
+
+    /// Start serving an app in the background.
+    ///
+    /// Panics if called outside of a Tokio runtime.
+    pub fn new<S, B>(app: S, settings: Settings) -> Self
+    where
+        S: Service<http::Request<axum::body::Body>, Response = http::Response<B>>
+            + Clone
+            + Send
+            + 'static,
+        S::Future: Send,
+        S::Error: Into<BoxError>,
+        B: HttpBody<Data = Bytes> + Send + 'static,
+        B::Error: Into<BoxError>,
+    {
+        Self::with_max_connections(app, settings, Self::MAX_CONNECTIONS)
+    }
+
+    /// Same as [`new`](Self::new), but with a connection limit of your choosing,
+    /// like [`FcgiServer::max_connections`](crate::FcgiServer::max_connections).
+    ///
+    /// Panics if called outside of a Tokio runtime.
+    pub fn with_max_connections<S, B>(
+        app: S,
+        settings: Settings,
+        max_connections: NonZeroUsize,
+    ) -> Self
+    where
+        S: Service<http::Request<axum::body::Body>, Response = http::Response<B>>
+            + Clone
+            + Send
+            + 'static,
+        S::Future: Send,
+        S::Error: Into<BoxError>,
+        B: HttpBody<Data = Bytes> + Send + 'static,
+        B::Error: Into<BoxError>,
+    {
+        let (connect, accept) = mpsc::unbounded_channel();
+        let task = tokio::spawn(async move {
+            let runner = Config::with_conns(max_connections).async_runner();
+            let mut connections = JoinSet::new();
+            // The accept loop never finishes on its own (the listener just waits
+            // for more connections), so this runs until we abort it.
+            serve_loop(
+                &runner,
+                Arc::new(Mutex::new(app)),
+                ChannelListener(tokio::sync::Mutex::new(accept)),
+                Arc::new(settings),
+                &mut connections,
+                ServerHandle::default(),
+            )
+            .await;
+        });
+        Self { connect, task }
+    }
+
+    /// Send a request, and collect what comes back.
+    ///
+    /// Errors: Returns an error if the connection broke before the request was
+    /// finished, which usually means the server end gave up on it entirely.
+    pub async fn send(&self, req: TestRequest) -> io::Result<TestResponse> {
+        let mut client = self.open()?;
+        req.exchange(&mut client, false).await
+    }
+
+    /// Open a connection that asks to stay open between requests, the way nginx
+    /// does with `fastcgi_keep_conn on`, for sending several requests down one.
+    ///
+    /// Errors: Returns an error if the server isn't running anymore.
+    pub fn connect(&self) -> io::Result<TestConnection> {
+        Ok(TestConnection {
+            client: self.open()?,
+        })
+    }
+
+    fn open(&self) -> io::Result<UnixStream> {
+        let (client, server) = UnixStream::pair()?;
+        self.connect
+            .send(server)
+            .map_err(|_| io::Error::other("the test server isn't running"))?;
+        Ok(client)
+    }
+}
+
+/// A connection to a [`TestServer`] that stays open between requests, from
+/// [`TestServer::connect`].
+pub struct TestConnection {
+    client: UnixStream,
+}
+
+impl TestConnection {
+    /// Talk FastCGI over a connection you opened yourself, to a server you started
+    /// yourself (an [`FcgiServer`](crate::FcgiServer) on a Unix socket, say) instead
+    /// of a [`TestServer`]. Like with [`TestServer::connect`], every request asks to
+    /// keep the connection open afterwards.
+    pub fn new(stream: UnixStream) -> Self {
+        Self { client: stream }
+    }
+
+    /// Send a request (asking to keep the connection open afterwards), and collect
+    /// what comes back.
+    ///
+    /// Errors: Returns an error if the connection broke before the request was
+    /// finished, including if the server had already closed it.
+    pub async fn send(&mut self, req: TestRequest) -> io::Result<TestResponse> {
+        req.exchange(&mut self.client, true).await
+    }
+
+    /// Ask the server about itself with a `GetValues` management record, the way a
+    /// web server might before deciding how many connections to open, and return
+    /// the values it reports. Names it doesn't know get left out, per the spec.
+    ///
+    /// Errors: Returns an error if the connection broke, or if what came back
+    /// wasn't a `GetValuesResult` we could make sense of.
+    pub async fn get_values(&mut self, names: &[&str]) -> io::Result<Vec<(String, String)>> {
+        let mut query = Vec::new();
+        for name in names {
+            write_length(&mut query, name.len());
+            write_length(&mut query, 0);
+            query.extend_from_slice(name.as_bytes());
+        }
+        let [len_hi, len_lo] = (query.len() as u16).to_be_bytes();
+        // Management records are about the connection, so they're request id 0.
+        let mut out = vec![1, FCGI_GET_VALUES, 0, 0, len_hi, len_lo, 0, 0];
+        out.extend_from_slice(&query);
+        self.client.write_all(&out).await?;
+
+        let mut header = [0u8; 8];
+        self.client.read_exact(&mut header).await?;
+        let content_len = u16::from_be_bytes([header[4], header[5]]) as usize;
+        let mut content = vec![0u8; content_len + header[6] as usize];
+        self.client.read_exact(&mut content).await?;
+        content.truncate(content_len);
+        if header[1] != FCGI_GET_VALUES_RESULT {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("expected a GetValuesResult record, got type {}", header[1]),
+            ));
+        }
+        read_pairs(&content)
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        // Aborting the accept loop drops its join set too, which takes any
+        // connections still in progress down with it.
+        self.task.abort();
+    }
+}
+
+/// A listener whose connections come from a [`TestServer`], and which waits
+/// forever once there aren't going to be any more.
+struct ChannelListener(tokio::sync::Mutex<mpsc::UnboundedReceiver<UnixStream>>);
+
+impl Listener for ChannelListener {
+    type Stream = UnixStream;
+
+    fn protocol(&self) -> &'static str {
+        "test"
+    }
+
+    async fn accept(&self) -> io::Result<Self::Stream> {
+        let next = self.0.lock().await.recv().await;
+        match next {
+            Some(stream) => Ok(stream),
+            None => futures_util::future::pending().await,
+        }
+    }
+}
+
+/// Write a single record for our one request. Content has to fit in 64K.
+fn write_record(out: &mut Vec<u8>, kind: u8, content: &[u8]) {
+    let [id_hi, id_lo] = REQUEST_ID.to_be_bytes();
+    let [len_hi, len_lo] = (content.len() as u16).to_be_bytes();
+    out.extend_from_slice(&[1, kind, id_hi, id_lo, len_hi, len_lo, 0, 0]);
+    out.extend_from_slice(content);
+}
+
+/// Write a whole stream (params or stdin), split into records as needed, and
+/// then the empty record that ends it.
+fn write_stream(out: &mut Vec<u8>, kind: u8, data: &[u8]) {
+    for chunk in data.chunks(u16::MAX as usize) {
+        write_record(out, kind, chunk);
+    }
+    write_record(out, kind, &[]);
+}
+
+/// Split a run of name-value pairs back apart.
+fn read_pairs(mut data: &[u8]) -> io::Result<Vec<(String, String)>> {
+    let truncated = || io::Error::new(io::ErrorKind::InvalidData, "truncated name-value pair");
+    let read_length = |data: &mut &[u8]| -> io::Result<usize> {
+        match **data {
+            [b, ..] if b < 128 => {
+                *data = &data[1..];
+                Ok(b as usize)
+            }
+            [a, b, c, d, ..] => {
+                *data = &data[4..];
+                Ok((u32::from_be_bytes([a, b, c, d]) & 0x7fff_ffff) as usize)
+            }
+            _ => Err(truncated()),
+        }
+    };
+    let mut pairs = Vec::new();
+    while !data.is_empty() {
+        let name_len = read_length(&mut data)?;
+        let value_len = read_length(&mut data)?;
+        if data.len() < name_len + value_len {
+            return Err(truncated());
+        }
+        let (name, rest) = data.split_at(name_len);
+        let (value, rest) = rest.split_at(value_len);
+        pairs.push((
+            String::from_utf8_lossy(name).into_owned(),
+            String::from_utf8_lossy(value).into_owned(),
+        ));
+        data = rest;
+    }
+    Ok(pairs)
+}
+
+/// Name-value pair lengths take one byte if they're short, or four (with the
+/// high bit set) if they're not.
+fn write_length(out: &mut Vec<u8>, len: usize) {
+    if len < 128 {
+        out.push(len as u8);
+    } else {
+        out.extend_from_slice(&(len as u32 | 0x8000_0000).to_be_bytes());
+    }
+}
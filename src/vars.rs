@@ -0,0 +1,114 @@
+//! The CGI environment that came in with a FastCGI request.
+use bytes::{Bytes, BytesMut};
+
+/// The CGI variables a request arrived with, like `REMOTE_ADDR`, `PATH_INFO`,
+/// and `HTTPS`. Every request gets one in its extensions, so handlers can read
+/// the vars we don't otherwise translate (`SERVER_SOFTWARE`, `DOCUMENT_ROOT`,
+/// vendor-specific `REDIRECT_*` vars...). Variable values are bytes, since CGI
+/// doesn't promise any particular encoding; [`get_str`](Self::get_str) is there
+/// for when you're expecting text.
+///
+/// [`FcgiEnrichLayer`](crate::FcgiEnrichLayer) reads one of these out of the
+/// request extensions, so in tests you can build one by hand (it's a
+/// `FromIterator` over name/value pairs) and pretend you're behind a web server.
+#[derive(Debug, Clone, Default)]
+pub struct FcgiVars {
+    // There are only a few dozen of these per request, so a plain list is quicker
+    // to build (and not meaningfully slower to search) than a hash map. Names are
+    // Bytes instead of Strings so that, for real requests, they can all share one
+    // buffer with the values; they're always valid UTF-8, though.
+    vars: Vec<(Bytes, Bytes)>,
+}
+
+impl FcgiVars {
+    /// An empty set of variables.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set a variable, replacing any previous value.
+    pub fn insert(&mut self, name: impl Into<String>, value: impl Into<Bytes>) {
+        let name = Bytes::from(name.into());
+        let value = value.into();
+        match self.vars.iter_mut().find(|(n, _)| *n == name) {
+            Some((_, v)) => *v = value,
+            None => self.vars.push((name, value)),
+        }
+    }
+
+    /// The raw value of a variable, if it was set.
+    pub fn get(&self, name: &str) -> Option<&[u8]> {
+        self.vars
+            .iter()
+            .find(|(n, _)| n == name.as_bytes())
+            .map(|(_, v)| v.as_ref())
+    }
+
+    /// The value of a variable, if it was set and is valid UTF-8.
+    pub fn get_str(&self, name: &str) -> Option<&str> {
+        self.get(name).and_then(|v| std::str::from_utf8(v).ok())
+    }
+
+    /// All the variables, in the order they arrived.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &[u8])> {
+        self.vars
+            .iter()
+            .map(|(n, v)| (std::str::from_utf8(n).unwrap_or_default(), v.as_ref()))
+    }
+
+    /// Copy a request's environment into one shared buffer, instead of allocating
+    /// a name and a value for every var. We trust the web server not to send
+    /// duplicates.
+    pub(crate) fn from_env<K, V>(env: impl Iterator<Item = (K, V)>) -> Self
+    where
+        K: AsRef<str>,
+        V: AsRef<[u8]>,
+    {
+        let mut buf = BytesMut::new();
+        let mut spans = Vec::new();
+        for (k, v) in env {
+            let (k, v) = (k.as_ref().as_bytes(), v.as_ref());
+            let start = buf.len();
+            buf.extend_from_slice(k);
+            buf.extend_from_slice(v);
+            spans.push((start, start + k.len(), start + k.len() + v.len()));
+        }
+        let buf = buf.freeze();
+        let vars = spans
+            .into_iter()
+            .map(|(start, mid, end)| (buf.slice(start..mid), buf.slice(mid..end)))
+            .collect();
+        Self { vars }
+    }
+
+    /// Wrap up vars whose names and values already share a buffer, the way SCGI's
+    /// netstring delivers them. Names have to be valid UTF-8, and like with
+    /// `from_env`, we trust the web server not to send duplicates.
+    pub(crate) fn from_shared(vars: Vec<(Bytes, Bytes)>) -> Self {
+        Self { vars }
+    }
+
+    /// How many variables there are.
+    pub fn len(&self) -> usize {
+        self.vars.len()
+    }
+
+    /// Whether there are no variables at all.
+    pub fn is_empty(&self) -> bool {
+        self.vars.is_empty()
+    }
+}
+
+impl<N, V> FromIterator<(N, V)> for FcgiVars
+where
+    N: Into<String>,
+    V: Into<Bytes>,
+{
+    fn from_iter<I: IntoIterator<Item = (N, V)>>(iter: I) -> Self {
+        let mut vars = Self::new();
+        for (name, value) in iter {
+            vars.insert(name, value);
+        }
+        vars
+    }
+}
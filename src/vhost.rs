@@ -0,0 +1,222 @@
+//! Serving several sites from one process, picked by hostname.
+use crate::FcgiVars;
+use axum::BoxError;
+use axum::Router;
+use bytes::Bytes;
+use futures_util::future::BoxFuture;
+use http_body::Body as HttpBody;
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tower::Service;
+
+/// A service that hands each request to one of several apps, depending on which
+/// site it's for. On shared hosting, that lets one FastCGI process serve every
+/// vhost that points at it, instead of starting one per site.
+///
+/// We go by the `SERVER_NAME` CGI var first, since that's whichever name the
+/// web server matched the request to, and then by the `Host` header (minus any
+/// port), so aliases the web server doesn't canonicalize still work, and so does
+/// plain HTTP mode. Names match case-insensitively. Requests for a host we don't
+/// know go to the [`fallback`](Self::fallback) app, or get a `404 Not Found` if
+/// there isn't one.
+///
+/// It's a regular tower service, so serve it with any of the serve functions or
+/// [`FcgiServer::serve`](crate::FcgiServer::serve), the same as a single app:
+///
+/// ```ignore
+/// let sites = busride_rs::VhostRouter::new()
+///     .host("example.com", main_site())
+///     .host("blog.example.com", blog())
+///     .fallback(main_site());
+/// busride_rs::serve_fcgid(sites, 50.try_into().unwrap()).await?;
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct VhostRouter {
+    hosts: Arc<HashMap<String, Router>>,
+    fallback: Option<Router>,
+}
+
+impl VhostRouter {
+    /// A router with no sites yet, which answers everything with a 404.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Serve this app for this hostname (no port), replacing any app already
+    /// registered for it. Call it once per name, aliases included.
+    pub fn host(mut self, name: &str, app: Router) -> Self {
+        Arc::make_mut(&mut self.hosts).insert(name.to_ascii_lowercase(), app);
+        self
+    }
+
+    /// Serve this app for any host we don't otherwise know. Without one, those
+    /// requests get a 404.
+    pub fn fallback(mut self, app: Router) -> Self {
+        self.fallback = Some(app);
+        self
+    }
+
+    /// The app for a request, if any.
+    fn app_for<B>(&self, req: &http::Request<B>) -> Option<&Router> {
+        let server_name = req
+            .extensions()
+            .get::<FcgiVars>()
+            .and_then(|vars| vars.get_str(fastcgi_server::cgi::SERVER_NAME));
+        let host_header = req
+            .headers()
+            .get(http::header::HOST)
+            .and_then(|host| host.to_str().ok())
+            .map(strip_port);
+        [server_name, host_header]
+            .into_iter()
+            .flatten()
+            .find_map(|name| self.hosts.get(&name.to_ascii_lowercase()))
+            .or(self.fallback.as_ref())
+    }
+}
+
+impl<B> Service<http::Request<B>> for VhostRouter
+where
+    B: HttpBody<Data = Bytes> + Send + 'static,
+    B::Error: Into<BoxError>,
+{
+    type Response = http::Response<axum::body::Body>;
+    type Error = Infallible;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        // Routers are always ready, and we'd have to know the request to pick one anyway.
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: http::Request<B>) -> Self::Future {
+        match self.app_for(&req) {
+            // Routers are always ready (see poll_ready), so there's no need to ask.
+            Some(app) => Box::pin(app.clone().call(req)),
+            None => {
+                let mut resp = http::Response::new(axum::body::Body::empty());
+                *resp.status_mut() = http::StatusCode::NOT_FOUND;
+                Box::pin(async move { Ok(resp) })
+            }
+        }
+    }
+}
+
+/// A Host header's hostname, without the port (bracketed IPv6 literals included).
+fn strip_port(host: &str) -> &str {
+    match host.rfind(':') {
+        Some(colon) if !host[colon..].contains(']') => &host[..colon],
+        _ => host,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn site(name: &'static str) -> Router {
+        Router::new().fallback(move || async move { name })
+    }
+
+    fn sites() -> VhostRouter {
+        VhostRouter::new()
+            .host("example.com", site("main"))
+            .host("Blog.Example.com", site("blog"))
+            .host("[::1]", site("loopback"))
+    }
+
+    fn request(
+        server_name: Option<&'static str>,
+        host: Option<&str>,
+    ) -> http::Request<axum::body::Body> {
+        let mut req = http::Request::get("/")
+            .body(axum::body::Body::empty())
+            .unwrap();
+        if let Some(host) = host {
+            req.headers_mut()
+                .insert(http::header::HOST, host.parse().unwrap());
+        }
+        if let Some(name) = server_name {
+            let vars: FcgiVars = [(fastcgi_server::cgi::SERVER_NAME, name)]
+                .into_iter()
+                .collect();
+            req.extensions_mut().insert(vars);
+        }
+        req
+    }
+
+    /// Which site answered, or the status if none did.
+    async fn answered_by(
+        mut router: VhostRouter,
+        server_name: Option<&'static str>,
+        host: Option<&str>,
+    ) -> String {
+        let resp = router.call(request(server_name, host)).await.unwrap();
+        if resp.status() != http::StatusCode::OK {
+            return resp.status().to_string();
+        }
+        let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        String::from_utf8(body.to_vec()).unwrap()
+    }
+
+    #[test]
+    fn ports_come_off_the_host() {
+        assert_eq!(strip_port("example.com:8080"), "example.com");
+        assert_eq!(strip_port("example.com"), "example.com");
+        assert_eq!(strip_port("[::1]:8080"), "[::1]");
+        assert_eq!(strip_port("[::1]"), "[::1]");
+        assert_eq!(strip_port("127.0.0.1:80"), "127.0.0.1");
+    }
+
+    #[tokio::test]
+    async fn server_name_wins_over_the_host_header() {
+        let by = answered_by(sites(), Some("blog.example.com"), Some("example.com")).await;
+        assert_eq!(by, "blog");
+    }
+
+    #[tokio::test]
+    async fn the_host_header_is_used_when_server_name_is_unknown() {
+        // A web server alias that it didn't canonicalize into SERVER_NAME.
+        let by = answered_by(sites(), Some("www.example.net"), Some("example.com")).await;
+        assert_eq!(by, "main");
+        // And plain HTTP mode, with no CGI vars at all.
+        assert_eq!(
+            answered_by(sites(), None, Some("example.com:8080")).await,
+            "main"
+        );
+        assert_eq!(
+            answered_by(sites(), None, Some("[::1]:8080")).await,
+            "loopback"
+        );
+    }
+
+    #[tokio::test]
+    async fn names_match_case_insensitively() {
+        assert_eq!(
+            answered_by(sites(), Some("EXAMPLE.COM"), None).await,
+            "main"
+        );
+        assert_eq!(
+            answered_by(sites(), None, Some("blog.example.COM")).await,
+            "blog"
+        );
+    }
+
+    #[tokio::test]
+    async fn unknown_hosts_go_to_the_fallback() {
+        let router = sites().fallback(site("fallback"));
+        let by = answered_by(router, Some("elsewhere.org"), Some("elsewhere.org")).await;
+        assert_eq!(by, "fallback");
+    }
+
+    #[tokio::test]
+    async fn unknown_hosts_get_404_without_a_fallback() {
+        let by = answered_by(sites(), Some("elsewhere.org"), Some("elsewhere.org")).await;
+        assert_eq!(by, "404 Not Found");
+        assert_eq!(answered_by(sites(), None, None).await, "404 Not Found");
+    }
+}
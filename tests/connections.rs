@@ -0,0 +1,147 @@
+//! What goes on at the level of a whole FastCGI connection: how long it stays open
+//! when the web server asks to keep it around for more requests, and what we say
+//! when it asks about our limits.
+use axum::routing::{get, post};
+use axum::Router;
+use busride_rs::testing::{TestRequest, TestServer};
+use busride_rs::{KeepAlive, Settings};
+use bytes::Bytes;
+use http::StatusCode;
+use std::num::NonZeroUsize;
+use std::time::Duration;
+
+fn app() -> Router {
+    Router::new().route("/hello", get(|| async { "hello" }))
+}
+
+/// Send requests down one kept-alive connection until one fails, and say how
+/// many made it (giving up at `most`).
+async fn requests_served(settings: Settings, most: usize) -> usize {
+    let server = TestServer::new(app(), settings);
+    let mut conn = server.connect().unwrap();
+    for served in 0..most {
+        match conn.send(TestRequest::new("GET", "/hello")).await {
+            Ok(resp) => assert_eq!(resp.status(), Some(StatusCode::OK)),
+            Err(_) => return served,
+        }
+    }
+    most
+}
+
+#[tokio::test]
+async fn auto_keeps_the_connection_as_long_as_asked() {
+    assert_eq!(requests_served(Settings::new(), 5).await, 5);
+}
+
+#[tokio::test]
+async fn never_closes_after_the_first_request() {
+    let settings = Settings::new().keep_alive(KeepAlive::Never);
+    assert_eq!(requests_served(settings, 5).await, 1);
+}
+
+#[tokio::test]
+async fn up_to_closes_after_that_many() {
+    let limit = NonZeroUsize::new(3).unwrap();
+    let settings = Settings::new().keep_alive(KeepAlive::UpTo(limit));
+    assert_eq!(requests_served(settings, 5).await, 3);
+}
+
+#[tokio::test]
+async fn separate_connections_get_separate_budgets() {
+    // The server keeps going after a connection's used up; only that one closes.
+    let server = TestServer::new(app(), Settings::new().keep_alive(KeepAlive::Never));
+    for _ in 0..3 {
+        let mut conn = server.connect().unwrap();
+        let resp = conn.send(TestRequest::new("GET", "/hello")).await.unwrap();
+        assert_eq!(resp.body(), b"hello");
+        assert!(conn.send(TestRequest::new("GET", "/hello")).await.is_err());
+    }
+}
+
+#[tokio::test]
+async fn get_values_reports_our_connection_limit() {
+    let max = NonZeroUsize::new(3).unwrap();
+    let server = TestServer::with_max_connections(app(), Settings::new(), max);
+    let mut conn = server.connect().unwrap();
+    let values = conn
+        .get_values(&["FCGI_MAX_CONNS", "FCGI_MAX_REQS", "FCGI_MPXS_CONNS"])
+        .await
+        .unwrap();
+    let max_conns = values
+        .iter()
+        .find(|(name, _)| name == "FCGI_MAX_CONNS")
+        .map(|(_, value)| value.as_str());
+    assert_eq!(max_conns, Some("3"), "values were: {:?}", values);
+    // And asking didn't get in the way of serving requests on the same connection.
+    let resp = conn.send(TestRequest::new("GET", "/hello")).await.unwrap();
+    assert_eq!(resp.body(), b"hello");
+}
+
+/// Bigger than max_body_bytes below, and than one FastCGI record.
+const BIG: usize = 200_000;
+
+/// Send a request whose body we won't read all of, then make sure the connection
+/// still works, which it only does if the leftovers got cleared off it.
+async fn still_usable_after(req: TestRequest, settings: Settings, expected: StatusCode) {
+    let app = app()
+        .route("/echo", post(|body: Bytes| async move { body }))
+        .route(
+            "/slow",
+            // Hangs on to the body without reading it, so it can't get cleared off
+            // on the way to the timeout.
+            post(|body: axum::body::Body| async move {
+                let _held = body;
+                tokio::time::sleep(Duration::from_secs(10)).await;
+                "too late"
+            }),
+        );
+    let server = TestServer::new(app, settings);
+    let mut conn = server.connect().unwrap();
+    let resp = conn.send(req).await.unwrap();
+    assert_eq!(resp.status(), Some(expected));
+    let next = TestRequest::new("GET", "/hello").var("BUSRIDE_OK", "1");
+    let resp = conn.send(next).await.unwrap();
+    assert_eq!(resp.body(), b"hello");
+}
+
+#[tokio::test]
+async fn bodies_over_the_limit_get_cleared_off_the_connection() {
+    let limited = Settings::new().max_body_bytes(Some(1000));
+    // Turned away for its Content-Length, before reading any of it...
+    let req = TestRequest::new("POST", "/echo").body(vec![b'x'; BIG]);
+    still_usable_after(req, limited.clone(), StatusCode::PAYLOAD_TOO_LARGE).await;
+    // ...or cut off partway, when there's no Content-Length to go on. (Axum makes a
+    // 400 of the error the app's body hands it.)
+    let req = TestRequest::new("POST", "/echo").body_without_length(vec![b'x'; BIG]);
+    still_usable_after(req, limited, StatusCode::BAD_REQUEST).await;
+}
+
+#[tokio::test]
+async fn bodies_past_their_content_length_get_cleared_off_the_connection() {
+    let req = TestRequest::new("POST", "/echo")
+        .body(vec![b'x'; BIG])
+        .var("CONTENT_LENGTH", "5");
+    still_usable_after(req, Settings::new(), StatusCode::OK).await;
+}
+
+#[tokio::test]
+async fn bodies_of_timed_out_requests_get_cleared_off_the_connection() {
+    let req = TestRequest::new("POST", "/slow").body(vec![b'x'; BIG]);
+    // With room for the whole body in the channel, we'd have read it all by the
+    // time the app gave up on it.
+    let settings = Settings::new()
+        .request_timeout(Some(Duration::from_millis(100)))
+        .body_channel_capacity(NonZeroUsize::MIN);
+    still_usable_after(req, settings, StatusCode::GATEWAY_TIMEOUT).await;
+}
+
+#[tokio::test]
+async fn bodies_nobody_asked_for_get_cleared_off_the_connection() {
+    // Answered without asking the app, for lack of a var the next request has...
+    let req = TestRequest::new("POST", "/echo").body("unwanted");
+    let settings = Settings::new().require_var("BUSRIDE_OK", "1");
+    still_usable_after(req, settings, StatusCode::FORBIDDEN).await;
+    // ...or by an app that never reads it.
+    let req = TestRequest::new("POST", "/nowhere").body("unwanted");
+    still_usable_after(req, Settings::new(), StatusCode::NOT_FOUND).await;
+}
@@ -0,0 +1,70 @@
+//! Round trips through the in-process FastCGI client, to make sure it works at
+//! all: a plain request, one with a body, one that never makes it to the app, and
+//! a few in a row through the same server.
+use axum::routing::{get, post};
+use axum::Router;
+use busride_rs::testing::{TestRequest, TestServer};
+use busride_rs::Settings;
+use bytes::Bytes;
+use http::StatusCode;
+
+fn app() -> Router {
+    Router::new()
+        .route("/hello", get(|| async { "hello" }))
+        .route("/echo", post(|body: Bytes| async move { body }))
+}
+
+#[tokio::test]
+async fn plain_get_round_trip() {
+    let resp = TestRequest::new("GET", "/hello")
+        .send(app(), Settings::new())
+        .await
+        .unwrap();
+    assert_eq!(resp.app_status(), 0);
+    assert_eq!(resp.status(), Some(StatusCode::OK));
+    assert_eq!(
+        resp.header("Content-Type").as_deref(),
+        Some("text/plain; charset=utf-8")
+    );
+    assert_eq!(resp.header("Content-Length").as_deref(), Some("5"));
+    assert_eq!(resp.body(), b"hello");
+}
+
+#[tokio::test]
+async fn request_body_reaches_the_app() {
+    let resp = TestRequest::new("POST", "/echo")
+        .header("Content-Type", "application/octet-stream")
+        .body("some bytes, for the app to send back")
+        .send(app(), Settings::new())
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), Some(StatusCode::OK));
+    assert_eq!(resp.body(), b"some bytes, for the app to send back");
+}
+
+#[tokio::test]
+async fn unparseable_request_gets_a_400_without_the_app() {
+    // A method with a space in it can't become an http::Method.
+    let resp = TestRequest::new("NOT A METHOD", "/hello")
+        .send(app(), Settings::new())
+        .await
+        .unwrap();
+    // We answered it, so as far as FastCGI's concerned, it went fine.
+    assert_eq!(resp.app_status(), 0);
+    assert_eq!(resp.status(), Some(StatusCode::BAD_REQUEST));
+    assert_eq!(resp.body(), b"");
+}
+
+#[tokio::test]
+async fn test_server_handles_request_after_request() {
+    let server = TestServer::new(app(), Settings::new());
+    for i in 0..3 {
+        let body = format!("round {}", i);
+        let resp = server
+            .send(TestRequest::new("POST", "/echo").body(body.clone()))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), Some(StatusCode::OK));
+        assert_eq!(resp.body(), body.as_bytes());
+    }
+}
@@ -0,0 +1,387 @@
+//! What the app gets to see of a request, once it's been through the CGI vars
+//! and back.
+mod common;
+
+use axum::routing::post;
+use axum::Router;
+use busride_rs::testing::TestRequest;
+use busride_rs::Settings;
+use bytes::Bytes;
+use common::Logs;
+use http::StatusCode;
+
+fn echo() -> Router {
+    Router::new().route("/echo", post(|body: Bytes| async move { body }))
+}
+
+#[tokio::test]
+async fn body_shorter_than_content_length_ends_early() {
+    let logs = Logs::default();
+    let _guard = logs.capture();
+    let resp = TestRequest::new("POST", "/echo")
+        .body("short")
+        .var("CONTENT_LENGTH", "20")
+        .send(echo(), Settings::new())
+        .await
+        .unwrap();
+    // The app gets what there was, instead of waiting forever for the rest.
+    assert_eq!(resp.status(), Some(StatusCode::OK));
+    assert_eq!(resp.body(), b"short");
+    assert!(
+        logs.contents()
+            .contains("Request body ended before its Content-Length"),
+        "logs were: {}",
+        logs.contents()
+    );
+}
+
+#[tokio::test]
+async fn body_longer_than_content_length_gets_cut_off() {
+    let logs = Logs::default();
+    let _guard = logs.capture();
+    let resp = TestRequest::new("POST", "/echo")
+        .body("0123456789")
+        .var("CONTENT_LENGTH", "4")
+        .send(echo(), Settings::new())
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), Some(StatusCode::OK));
+    assert_eq!(resp.body(), b"0123");
+    assert!(
+        logs.contents()
+            .contains("Request body ran past its Content-Length"),
+        "logs were: {}",
+        logs.contents()
+    );
+}
+
+#[tokio::test]
+async fn body_matching_content_length_is_quiet() {
+    let logs = Logs::default();
+    let _guard = logs.capture();
+    let resp = TestRequest::new("POST", "/echo")
+        .body("just right")
+        .send(echo(), Settings::new())
+        .await
+        .unwrap();
+    assert_eq!(resp.body(), b"just right");
+    assert!(
+        !logs.contents().contains("Content-Length"),
+        "logs were: {}",
+        logs.contents()
+    );
+}
+
+#[tokio::test]
+async fn bodies_over_max_body_bytes_get_a_413() {
+    let settings = Settings::new().max_body_bytes(Some(8));
+    let resp = TestRequest::new("POST", "/echo")
+        .body("0123456789")
+        .send(echo(), settings.clone())
+        .await
+        .unwrap();
+    // Turned away on the Content-Length alone, so the app never echoed anything.
+    assert_eq!(resp.status(), Some(StatusCode::PAYLOAD_TOO_LARGE));
+    assert_eq!(resp.body(), b"");
+
+    // Right at the limit is fine.
+    let resp = TestRequest::new("POST", "/echo")
+        .body("01234567")
+        .send(echo(), settings)
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), Some(StatusCode::OK));
+    assert_eq!(resp.body(), b"01234567");
+}
+
+#[tokio::test]
+async fn app_sees_the_clients_http_version() {
+    let app = Router::new().route(
+        "/version",
+        axum::routing::get(
+            |req: axum::extract::Request| async move { format!("{:?}", req.version()) },
+        ),
+    );
+    for (protocol, expected) in [
+        ("HTTP/1.0", "HTTP/1.0"),
+        ("HTTP/2.0", "HTTP/2.0"),
+        ("whatever", "HTTP/1.1"),
+    ] {
+        let resp = TestRequest::new("GET", "/version")
+            .var("SERVER_PROTOCOL", protocol)
+            .send(app.clone(), Settings::new())
+            .await
+            .unwrap();
+        assert_eq!(resp.body(), expected.as_bytes(), "for {}", protocol);
+    }
+}
+
+/// An app that sends back the request headers it sees, one `name: value` per
+/// line, sorted, leaving out the ones every request has.
+fn header_echo() -> Router {
+    Router::new().route(
+        "/headers",
+        axum::routing::get(|headers: http::HeaderMap| async move {
+            let mut lines: Vec<String> = headers
+                .iter()
+                .filter(|(name, _)| *name != http::header::HOST)
+                .map(|(name, value)| format!("{}: {}", name, value.to_str().unwrap()))
+                .collect();
+            lines.sort();
+            lines.join("\n")
+        }),
+    )
+}
+
+// Set as raw CGI vars, the way the web server sends them, so this checks our
+// translation and not the test client's.
+#[tokio::test]
+async fn conditional_headers_reach_the_app_intact() {
+    let resp = TestRequest::new("GET", "/headers")
+        .var("HTTP_IF_MODIFIED_SINCE", "Wed, 21 Oct 2015 07:28:00 GMT")
+        .var("HTTP_IF_UNMODIFIED_SINCE", "Thu, 01 Jan 1970 00:00:00 GMT")
+        .var(
+            "HTTP_IF_NONE_MATCH",
+            r#""abc123", W/"weak-1", "with,comma""#,
+        )
+        .var("HTTP_IF_MATCH", "*")
+        .var("HTTP_IF_RANGE", r#"W/"range-tag""#)
+        .send(header_echo(), Settings::new())
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), Some(StatusCode::OK));
+    assert_eq!(
+        std::str::from_utf8(resp.body()).unwrap(),
+        [
+            "if-match: *",
+            "if-modified-since: Wed, 21 Oct 2015 07:28:00 GMT",
+            r#"if-none-match: "abc123", W/"weak-1", "with,comma""#,
+            r#"if-range: W/"range-tag""#,
+            "if-unmodified-since: Thu, 01 Jan 1970 00:00:00 GMT",
+        ]
+        .join("\n")
+    );
+}
+
+#[tokio::test]
+async fn tricky_header_names_and_values_round_trip() {
+    let resp = TestRequest::new("GET", "/headers")
+        .var("HTTP_X_FORWARDED_FOR", "203.0.113.7, 198.51.100.2")
+        .var("HTTP_X_A_B_C", "lots of   spaces, and a\ttab")
+        .var("HTTP_X_1", "digits are fine")
+        .var("HTTP_ACCEPT_LANGUAGE", "en-US,en;q=0.5")
+        .var("HTTP_X_QUOTED", r#"a="b c"; d='e'"#)
+        // Not HTTP_ anything, so it's a CGI var, not a header, and stays out.
+        .var("X_NOT_A_HEADER", "nope")
+        .send(header_echo(), Settings::new())
+        .await
+        .unwrap();
+    assert_eq!(
+        std::str::from_utf8(resp.body()).unwrap(),
+        [
+            "accept-language: en-US,en;q=0.5",
+            "x-1: digits are fine",
+            "x-a-b-c: lots of   spaces, and a\ttab",
+            "x-forwarded-for: 203.0.113.7, 198.51.100.2",
+            r#"x-quoted: a="b c"; d='e'"#,
+        ]
+        .join("\n")
+    );
+}
+
+#[tokio::test]
+async fn app_can_answer_a_conditional_request_with_304() {
+    let app = Router::new().route(
+        "/page",
+        axum::routing::get(|headers: http::HeaderMap| async move {
+            let etag = r#"W/"v1""#;
+            let fresh = headers
+                .get(http::header::IF_NONE_MATCH)
+                .and_then(|v| v.to_str().ok())
+                .is_some_and(|tags| tags.split(',').any(|tag| tag.trim() == etag));
+            let status = if fresh {
+                StatusCode::NOT_MODIFIED
+            } else {
+                StatusCode::OK
+            };
+            let body = if fresh { "" } else { "the page" };
+            (status, [(http::header::ETAG, etag)], body)
+        }),
+    );
+    let resp = TestRequest::new("GET", "/page")
+        .var("HTTP_IF_NONE_MATCH", r#""other", W/"v1""#)
+        .send(app.clone(), Settings::new())
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), Some(StatusCode::NOT_MODIFIED));
+    assert_eq!(resp.header("ETag").as_deref(), Some(r#"W/"v1""#));
+    assert_eq!(resp.body(), b"");
+
+    let resp = TestRequest::new("GET", "/page")
+        .var("HTTP_IF_NONE_MATCH", r#""stale""#)
+        .send(app, Settings::new())
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), Some(StatusCode::OK));
+    assert_eq!(resp.body(), b"the page");
+}
+
+/// A GET for the header echo with `count` made-up headers, each `size` bytes long.
+fn with_headers(count: usize, size: usize) -> TestRequest {
+    (0..count).fold(TestRequest::new("GET", "/headers"), |req, i| {
+        req.header(&format!("X-Extra-{}", i), "v".repeat(size))
+    })
+}
+
+#[tokio::test]
+async fn too_many_headers_get_a_431() {
+    let settings = Settings::new().max_header_count(3);
+    let resp = with_headers(3, 1)
+        .send(header_echo(), settings.clone())
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), Some(StatusCode::OK));
+    let resp = with_headers(4, 1)
+        .send(header_echo(), settings)
+        .await
+        .unwrap();
+    assert_eq!(
+        resp.status(),
+        Some(StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE)
+    );
+    assert_eq!(resp.body(), b"");
+}
+
+#[tokio::test]
+async fn too_many_header_bytes_get_a_431() {
+    // Names count too (as CGI has them, minus the HTTP_): "X_EXTRA_0" is 9 bytes.
+    let settings = Settings::new().max_header_bytes(2 * (9 + 10));
+    let resp = with_headers(2, 10)
+        .send(header_echo(), settings.clone())
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), Some(StatusCode::OK));
+    let resp = with_headers(2, 11)
+        .send(header_echo(), settings)
+        .await
+        .unwrap();
+    assert_eq!(
+        resp.status(),
+        Some(StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE)
+    );
+}
+
+#[tokio::test]
+async fn unparseable_headers_get_a_400() {
+    // A newline can't go in an HTTP header value, however the web server let it by.
+    let resp = TestRequest::new("GET", "/headers")
+        .var("HTTP_X_SPLIT", "one\ntwo")
+        .send(header_echo(), Settings::new())
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), Some(StatusCode::BAD_REQUEST));
+    assert_eq!(resp.body(), b"");
+}
+
+#[tokio::test]
+async fn one_oversized_header_value_gets_a_431() {
+    let logs = Logs::default();
+    let _guard = logs.capture();
+    // Well under the total, but one value's too big on its own.
+    let settings = Settings::new().max_header_value_bytes(16);
+    let resp = TestRequest::new("GET", "/headers")
+        .header("X-Small", "fine")
+        .header("X-Huge", "v".repeat(17))
+        .send(header_echo(), settings.clone())
+        .await
+        .unwrap();
+    assert_eq!(
+        resp.status(),
+        Some(StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE)
+    );
+    // The log says which one, so nobody has to guess.
+    assert!(
+        logs.contents().contains("HTTP_X_HUGE"),
+        "logs were: {}",
+        logs.contents()
+    );
+
+    let resp = TestRequest::new("GET", "/headers")
+        .header("X-Huge", "v".repeat(16))
+        .send(header_echo(), settings)
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), Some(StatusCode::OK));
+}
+
+#[tokio::test]
+async fn requests_without_the_required_var_get_a_403() {
+    let settings = Settings::new().require_var("REDIRECT_STATUS", "200");
+    let missing = TestRequest::new("GET", "/headers");
+    let wrong = TestRequest::new("GET", "/headers").var("REDIRECT_STATUS", "404");
+    for req in [missing, wrong] {
+        let resp = req.send(header_echo(), settings.clone()).await.unwrap();
+        assert_eq!(resp.status(), Some(StatusCode::FORBIDDEN));
+        assert_eq!(resp.body(), b"");
+    }
+    let resp = TestRequest::new("GET", "/headers")
+        .var("REDIRECT_STATUS", "200")
+        .send(header_echo(), settings)
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), Some(StatusCode::OK));
+}
+
+/// An app that says what request ID it was handed, if any.
+fn id_echo() -> Router {
+    Router::new().route(
+        "/id",
+        axum::routing::get(|headers: http::HeaderMap| async move {
+            let id = headers.get("x-request-id").or(headers.get("x-trace"));
+            format!("{:?}", id.map(|v| v.to_str().unwrap().to_string()))
+        }),
+    )
+}
+
+#[tokio::test]
+async fn incoming_request_ids_get_echoed() {
+    let resp = TestRequest::new("GET", "/id")
+        .header("X-Request-Id", "from-the-web-server")
+        .send(id_echo(), Settings::new().generate_request_id(true))
+        .await
+        .unwrap();
+    assert_eq!(resp.body(), br#"Some("from-the-web-server")"#);
+    assert_eq!(
+        resp.header("X-Request-Id").as_deref(),
+        Some("from-the-web-server")
+    );
+
+    // Whichever header the settings say.
+    let trace = http::HeaderName::from_static("x-trace");
+    let resp = TestRequest::new("GET", "/id")
+        .header("X-Trace", "abc123")
+        .send(id_echo(), Settings::new().request_id_header(Some(trace)))
+        .await
+        .unwrap();
+    assert_eq!(resp.header("X-Trace").as_deref(), Some("abc123"));
+}
+
+#[tokio::test]
+async fn missing_request_ids_only_get_made_up_if_asked() {
+    let resp = TestRequest::new("GET", "/id")
+        .send(id_echo(), Settings::new())
+        .await
+        .unwrap();
+    assert_eq!(resp.body(), b"None");
+    assert_eq!(resp.header("X-Request-Id"), None);
+
+    let resp = TestRequest::new("GET", "/id")
+        .send(id_echo(), Settings::new().generate_request_id(true))
+        .await
+        .unwrap();
+    let echoed = resp.header("X-Request-Id").expect("no ID in the response");
+    assert_eq!(echoed.len(), 16, "{}", echoed);
+    assert!(echoed.bytes().all(|b| b.is_ascii_hexdigit()), "{}", echoed);
+    // The app saw the same one we sent back.
+    assert_eq!(resp.body(), format!("Some({:?})", echoed).as_bytes());
+}
@@ -0,0 +1,616 @@
+//! What the app's responses look like once they've been turned into CGI.
+mod common;
+
+use axum::response::IntoResponse;
+use axum::routing::get;
+use axum::Router;
+use busride_rs::testing::{TestConnection, TestRequest, TestServer};
+use busride_rs::{FcgiServer, Role, Settings};
+use bytes::Bytes;
+use common::Logs;
+use http::{header, StatusCode};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+// The web server only learns the real status from the CGI Status header, so
+// each of these has to carry one, or the client gets a 200.
+#[tokio::test]
+async fn non_200_statuses_get_a_status_line() {
+    let app = Router::new()
+        .route(
+            "/moved",
+            get(|| async {
+                (
+                    StatusCode::MOVED_PERMANENTLY,
+                    [(header::LOCATION, "/elsewhere")],
+                )
+            }),
+        )
+        .route(
+            "/broken",
+            get(|| async { StatusCode::INTERNAL_SERVER_ERROR.into_response() }),
+        );
+    let cases = [
+        ("/moved", "301 Moved Permanently"),
+        ("/nowhere", "404 Not Found"),
+        ("/broken", "500 Internal Server Error"),
+    ];
+    for (path, status_line) in cases {
+        let resp = TestRequest::new("GET", path)
+            .send(app.clone(), Settings::new())
+            .await
+            .unwrap();
+        assert_eq!(
+            resp.header("Status").as_deref(),
+            Some(status_line),
+            "for {}",
+            path
+        );
+        assert_eq!(resp.app_status(), 0, "for {}", path);
+    }
+}
+
+#[tokio::test]
+async fn redirects_keep_their_location() {
+    let app = Router::new().route(
+        "/moved",
+        get(|| async {
+            (
+                StatusCode::MOVED_PERMANENTLY,
+                [(header::LOCATION, "/elsewhere")],
+            )
+        }),
+    );
+    let resp = TestRequest::new("GET", "/moved")
+        .send(app, Settings::new())
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), Some(StatusCode::MOVED_PERMANENTLY));
+    assert_eq!(resp.header("Location").as_deref(), Some("/elsewhere"));
+}
+
+/// A service that answers everything with a body, HEAD or not, the way a
+/// hand-rolled service might. (Axum's Router strips HEAD bodies itself, which
+/// would hide whether we do.)
+#[derive(Clone)]
+struct AlwaysBody;
+
+impl tower::Service<http::Request<axum::body::Body>> for AlwaysBody {
+    type Response = http::Response<axum::body::Body>;
+    type Error = std::convert::Infallible;
+    type Future = std::future::Ready<Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(
+        &mut self,
+        _cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Result<(), Self::Error>> {
+        std::task::Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, _req: http::Request<axum::body::Body>) -> Self::Future {
+        let mut resp = http::Response::new(axum::body::Body::from("twelve bytes"));
+        resp.headers_mut()
+            .insert(header::CONTENT_TYPE, "text/plain".parse().unwrap());
+        std::future::ready(Ok(resp))
+    }
+}
+
+#[tokio::test]
+async fn head_responses_have_headers_but_no_body() {
+    let resp = TestRequest::new("HEAD", "/")
+        .send(AlwaysBody, Settings::new())
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), Some(StatusCode::OK));
+    assert_eq!(resp.header("Content-Type").as_deref(), Some("text/plain"));
+    // Still says how big the body would have been...
+    assert_eq!(resp.header("Content-Length").as_deref(), Some("12"));
+    // ...but doesn't send it.
+    assert_eq!(resp.body(), b"");
+
+    // And the same service does send it for a GET, so it's not just empty.
+    let resp = TestRequest::new("GET", "/")
+        .send(AlwaysBody, Settings::new())
+        .await
+        .unwrap();
+    assert_eq!(resp.body(), b"twelve bytes");
+}
+
+/// "hello, hello, hello, compressed world\n", gzipped. Real gzip, so there's a
+/// zero byte and a few that aren't valid UTF-8 in there.
+const GZIPPED: [u8; 46] = [
+    0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0xff, 0xcb, 0x48, 0xcd, 0xc9, 0xc9, 0xd7,
+    0x51, 0xc8, 0x40, 0xa1, 0x92, 0xf3, 0x73, 0x0b, 0x8a, 0x52, 0x8b, 0x8b, 0x53, 0x53, 0x14, 0xca,
+    0xf3, 0x8b, 0x72, 0x52, 0xb8, 0x00, 0x6d, 0xdc, 0x53, 0xad, 0x26, 0x00, 0x00, 0x00,
+];
+
+#[tokio::test]
+async fn pre_compressed_responses_pass_through_untouched() {
+    // Like an app behind a compression layer: it only compresses when asked.
+    let app = Router::new().route(
+        "/",
+        get(|headers: http::HeaderMap| async move {
+            let wants_gzip = headers
+                .get(header::ACCEPT_ENCODING)
+                .and_then(|v| v.to_str().ok())
+                .is_some_and(|v| v.contains("gzip"));
+            assert!(wants_gzip, "app didn't get the Accept-Encoding");
+            (
+                [
+                    (header::CONTENT_TYPE, "text/plain"),
+                    (header::CONTENT_ENCODING, "gzip"),
+                    (header::CONTENT_LENGTH, "46"),
+                    (header::VARY, "accept-encoding"),
+                ],
+                GZIPPED.to_vec(),
+            )
+        }),
+    );
+    let resp = TestRequest::new("GET", "/")
+        .header("Accept-Encoding", "gzip, deflate, br")
+        .send(app, Settings::new())
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), Some(StatusCode::OK));
+    assert_eq!(resp.header("Content-Encoding").as_deref(), Some("gzip"));
+    assert_eq!(resp.header("Content-Length").as_deref(), Some("46"));
+    assert_eq!(resp.header("Vary").as_deref(), Some("accept-encoding"));
+    assert_eq!(resp.body(), GZIPPED);
+}
+
+// The pattern from the crate docs, for handing a file off to the web server.
+#[tokio::test]
+async fn sendfile_headers_pass_through_with_an_empty_body() {
+    let app = Router::new()
+        .route(
+            "/apache",
+            get(|| async { ([("x-sendfile", "/home/me/files/big.zip")], ()) }),
+        )
+        .route(
+            "/nginx",
+            get(|| async { ([("x-accel-redirect", "/protected/big.zip")], ()) }),
+        );
+    for (path, name, value) in [
+        ("/apache", "X-Sendfile", "/home/me/files/big.zip"),
+        ("/nginx", "X-Accel-Redirect", "/protected/big.zip"),
+    ] {
+        let resp = TestRequest::new("GET", path)
+            .send(app.clone(), Settings::new())
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), Some(StatusCode::OK), "for {}", path);
+        assert_eq!(resp.header(name).as_deref(), Some(value), "for {}", path);
+        assert_eq!(resp.body(), b"", "for {}", path);
+        // Nothing claims there's a body on the way, either.
+        assert!(
+            matches!(resp.header("Content-Length").as_deref(), None | Some("0")),
+            "for {}: {:?}",
+            path,
+            resp.headers()
+        );
+    }
+}
+
+#[tokio::test]
+async fn apps_that_take_too_long_get_a_504() {
+    /// Notices when the app's handler gets dropped partway through.
+    struct Cancelled(Arc<AtomicBool>);
+
+    impl Drop for Cancelled {
+        fn drop(&mut self) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    let cancelled = Arc::new(AtomicBool::new(false));
+    let flag = cancelled.clone();
+    let app = Router::new().route(
+        "/slow",
+        get(move || async move {
+            let _guard = Cancelled(flag);
+            tokio::time::sleep(Duration::from_secs(30)).await;
+            "too late"
+        }),
+    );
+    let settings = Settings::new().request_timeout(Some(Duration::from_millis(50)));
+    let resp = TestRequest::new("GET", "/slow")
+        .send(app, settings)
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), Some(StatusCode::GATEWAY_TIMEOUT));
+    assert_eq!(resp.body(), b"");
+    // And the app didn't keep going in the background.
+    assert!(cancelled.load(Ordering::SeqCst));
+}
+
+#[tokio::test]
+async fn panicking_apps_get_a_500_and_the_server_carries_on() {
+    let app = Router::new()
+        .route(
+            "/panic",
+            get(|| async {
+                panic!("on purpose");
+                #[allow(unreachable_code)]
+                ""
+            }),
+        )
+        .route("/fine", get(|| async { "fine" }));
+    let server = TestServer::new(app, Settings::new());
+    let mut conn = server.connect().unwrap();
+    let resp = conn.send(TestRequest::new("GET", "/panic")).await.unwrap();
+    assert_eq!(resp.status(), Some(StatusCode::INTERNAL_SERVER_ERROR));
+    assert_eq!(resp.body(), b"");
+    // Same connection, even, since the panic never got past us.
+    let resp = conn.send(TestRequest::new("GET", "/fine")).await.unwrap();
+    assert_eq!(resp.body(), b"fine");
+}
+
+#[tokio::test]
+async fn requests_get_a_503_until_the_app_is_ready() {
+    let path = std::env::temp_dir().join(format!("busride-warm-up-{}", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let listener = tokio::net::UnixListener::bind(&path).unwrap();
+    let (warmed_up, ready) = tokio::sync::oneshot::channel::<()>();
+    let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+    let server = FcgiServer::new()
+        .unix_listener(listener)
+        .ready_when(async move {
+            let _ = ready.await;
+        })
+        .graceful_shutdown(async move {
+            let _ = stopped.await;
+        });
+    let handle = server.handle();
+    let app = Router::new().route("/", get(|| async { "ready" }));
+    let serving = tokio::spawn(server.serve(app));
+
+    let stream = tokio::net::UnixStream::connect(&path).await.unwrap();
+    std::fs::remove_file(&path).unwrap();
+    let mut conn = TestConnection::new(stream);
+    let resp = conn.send(TestRequest::new("GET", "/")).await.unwrap();
+    assert_eq!(resp.status(), Some(StatusCode::SERVICE_UNAVAILABLE));
+    assert!(resp.header("Retry-After").is_some(), "{:?}", resp.headers());
+    assert_eq!(resp.body(), b"");
+    assert!(!handle.is_ready());
+
+    warmed_up.send(()).unwrap();
+    while !handle.is_ready() {
+        tokio::time::sleep(Duration::from_millis(1)).await;
+    }
+    let resp = conn.send(TestRequest::new("GET", "/")).await.unwrap();
+    assert_eq!(resp.status(), Some(StatusCode::OK));
+    assert_eq!(resp.body(), b"ready");
+
+    drop(conn);
+    stop.send(()).unwrap();
+    serving.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn health_checks_get_answered_without_the_app() {
+    // The app has a route at the same path, to show it never gets asked.
+    let app = Router::new()
+        .route("/healthz", get(|| async { "the app" }))
+        .route("/other", get(|| async { "the app" }));
+    let settings = Settings::new().health_check_path(Some("/healthz".to_string()));
+    for uri in ["/healthz", "/healthz?verbose=1"] {
+        let resp = TestRequest::new("GET", uri)
+            .send(app.clone(), settings.clone())
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), Some(StatusCode::OK), "for {}", uri);
+        assert_eq!(resp.body(), b"", "for {}", uri);
+    }
+    let resp = TestRequest::new("GET", "/other")
+        .send(app.clone(), settings.clone())
+        .await
+        .unwrap();
+    assert_eq!(resp.body(), b"the app");
+
+    // Matched against the same path the app would see.
+    let resp = TestRequest::new("GET", "/mounted/healthz")
+        .var("SCRIPT_NAME", "/mounted")
+        .var("PATH_INFO", "/healthz")
+        .send(app, settings.path_from_path_info(true))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), Some(StatusCode::OK));
+    assert_eq!(resp.body(), b"");
+}
+
+#[tokio::test]
+async fn error_pages_fill_in_empty_bodies() {
+    let app = Router::new().route(
+        "/gone",
+        get(|| async { (StatusCode::NOT_FOUND, "the app's own page") }),
+    );
+    let html = http::HeaderValue::from_static("text/html");
+    let settings = Settings::new()
+        .error_page(StatusCode::NOT_FOUND, html.clone(), "<h1>Not here</h1>")
+        .error_page(StatusCode::PAYLOAD_TOO_LARGE, html, "<h1>Too big</h1>")
+        .max_body_bytes(Some(4));
+
+    // Axum's own 404 for a route that doesn't exist is empty, so it gets the page.
+    let resp = TestRequest::new("GET", "/nowhere")
+        .send(app.clone(), settings.clone())
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), Some(StatusCode::NOT_FOUND));
+    assert_eq!(resp.header("Content-Type").as_deref(), Some("text/html"));
+    assert_eq!(resp.body(), b"<h1>Not here</h1>");
+
+    // A 404 the app filled in itself goes out as-is.
+    let resp = TestRequest::new("GET", "/gone")
+        .send(app.clone(), settings.clone())
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), Some(StatusCode::NOT_FOUND));
+    assert_eq!(resp.body(), b"the app's own page");
+
+    // And the responses we make up ourselves don't get one.
+    let resp = TestRequest::new("POST", "/gone")
+        .body("too big")
+        .send(app, settings)
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), Some(StatusCode::PAYLOAD_TOO_LARGE));
+    assert_eq!(resp.body(), b"");
+}
+
+#[tokio::test]
+async fn upgrades_get_a_501() {
+    // What a WebSocket handshake looks like from the app's side.
+    let app = Router::new().route(
+        "/ws",
+        get(|| async {
+            (
+                StatusCode::SWITCHING_PROTOCOLS,
+                [
+                    (header::UPGRADE, "websocket"),
+                    (header::CONNECTION, "upgrade"),
+                ],
+            )
+        }),
+    );
+    let resp = TestRequest::new("GET", "/ws")
+        .header("Upgrade", "websocket")
+        .header("Connection", "Upgrade")
+        .send(app, Settings::new())
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), Some(StatusCode::NOT_IMPLEMENTED));
+    // None of the app's upgrade headers make it out, since there's no upgrade.
+    assert_eq!(resp.header("Upgrade"), None);
+    assert_eq!(resp.body(), b"");
+}
+
+#[tokio::test]
+async fn bare_responses_get_a_content_type_except_from_authorizers() {
+    let app = Router::new().route("/", get(|| async { [("variable-user", "me")] }));
+    // A responder's bare 200 would be a "malformed header" to Apache, so it gets one.
+    let resp = TestRequest::new("GET", "/")
+        .send(app.clone(), Settings::new())
+        .await
+        .unwrap();
+    assert_eq!(
+        resp.header("Content-Type").as_deref(),
+        Some("application/octet-stream")
+    );
+    // But an authorizer's yes is supposed to be bare, so it stays that way.
+    let resp = TestRequest::new("GET", "/")
+        .role(Role::Authorizer)
+        .send(app, Settings::new().role(Role::Authorizer))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), Some(StatusCode::OK));
+    assert_eq!(resp.header("Content-Type"), None, "{:?}", resp.headers());
+    assert_eq!(resp.header("Variable-User").as_deref(), Some("me"));
+}
+
+/// An authorizer that lets in anyone who says who they are.
+fn authorizer() -> Router {
+    Router::new().fallback(|headers: http::HeaderMap| async move {
+        match headers.get("x-user") {
+            Some(user) => (
+                StatusCode::NO_CONTENT,
+                [
+                    ("variable-remote-user", user.clone()),
+                    ("x-not-a-variable", "dropped".parse().unwrap()),
+                    ("content-type", "text/plain".parse().unwrap()),
+                ],
+                "welcome",
+            )
+                .into_response(),
+            None => (
+                StatusCode::UNAUTHORIZED,
+                [(header::WWW_AUTHENTICATE, "Basic realm=\"here\"")],
+                "who are you?",
+            )
+                .into_response(),
+        }
+    })
+}
+
+#[tokio::test]
+async fn authorizers_saying_yes_only_pass_on_variables() {
+    let resp = TestRequest::new("GET", "/private")
+        .role(Role::Authorizer)
+        .header("X-User", "me")
+        .send(authorizer(), Settings::new().role(Role::Authorizer))
+        .await
+        .unwrap();
+    // Any 2xx counts, but the spec wants a 200.
+    assert_eq!(resp.status(), Some(StatusCode::OK));
+    assert_eq!(resp.header("Variable-Remote-User").as_deref(), Some("me"));
+    assert_eq!(resp.header("X-Not-A-Variable"), None);
+    assert_eq!(resp.header("Content-Type"), None);
+    assert_eq!(resp.body(), b"");
+}
+
+#[tokio::test]
+async fn authorizers_saying_no_go_out_as_is() {
+    let resp = TestRequest::new("GET", "/private")
+        .role(Role::Authorizer)
+        .send(authorizer(), Settings::new().role(Role::Authorizer))
+        .await
+        .unwrap();
+    // The web server sends these straight to the client.
+    assert_eq!(resp.status(), Some(StatusCode::UNAUTHORIZED));
+    assert_eq!(
+        resp.header("WWW-Authenticate").as_deref(),
+        Some("Basic realm=\"here\"")
+    );
+    assert_eq!(resp.body(), b"who are you?");
+}
+
+#[tokio::test]
+async fn date_headers_only_when_asked_and_missing() {
+    let app = Router::new().route("/now", get(|| async { "now" })).route(
+        "/then",
+        get(|| async { ([(header::DATE, "Sun, 06 Nov 1994 08:49:37 GMT")], "then") }),
+    );
+    let resp = TestRequest::new("GET", "/now")
+        .send(app.clone(), Settings::new())
+        .await
+        .unwrap();
+    assert_eq!(resp.header("Date"), None);
+
+    let settings = Settings::new().date_header(true);
+    let resp = TestRequest::new("GET", "/now")
+        .send(app.clone(), settings.clone())
+        .await
+        .unwrap();
+    let date = resp.header("Date").expect("no Date header");
+    // Like "Sun, 06 Nov 1994 08:49:37 GMT".
+    assert_eq!(date.len(), 29, "{}", date);
+    assert!(date.ends_with(" GMT"), "{}", date);
+    assert_eq!(&date[3..5], ", ", "{}", date);
+
+    // The app's own Date wins.
+    let resp = TestRequest::new("GET", "/then")
+        .send(app, settings)
+        .await
+        .unwrap();
+    assert_eq!(
+        resp.header("Date").as_deref(),
+        Some("Sun, 06 Nov 1994 08:49:37 GMT")
+    );
+}
+
+/// A bare service that answers with whatever response its function builds. Axum's
+/// Router fills in Content-Length on its own, which would hide whether we do.
+#[derive(Clone)]
+struct Answers(fn() -> http::Response<axum::body::Body>);
+
+impl tower::Service<http::Request<axum::body::Body>> for Answers {
+    type Response = http::Response<axum::body::Body>;
+    type Error = std::convert::Infallible;
+    type Future = std::future::Ready<Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(
+        &mut self,
+        _cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Result<(), Self::Error>> {
+        std::task::Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, _req: http::Request<axum::body::Body>) -> Self::Future {
+        std::future::ready(Ok((self.0)()))
+    }
+}
+
+async fn content_length_of(make: fn() -> http::Response<axum::body::Body>) -> Option<String> {
+    let resp = TestRequest::new("GET", "/")
+        .send(Answers(make), Settings::new())
+        .await
+        .unwrap();
+    resp.header("Content-Length")
+}
+
+#[tokio::test]
+async fn bodies_that_know_their_length_get_a_content_length() {
+    let known = content_length_of(|| http::Response::new("twelve bytes".into())).await;
+    assert_eq!(known.as_deref(), Some("12"));
+
+    let stream = content_length_of(|| {
+        let hunks = ["no ", "idea"].map(Ok::<_, std::io::Error>);
+        http::Response::new(axum::body::Body::from_stream(futures_util::stream::iter(
+            hunks,
+        )))
+    })
+    .await;
+    assert_eq!(stream, None);
+
+    // Wrong on purpose: it's the app's call, so it goes out as-is.
+    let said_so = content_length_of(|| {
+        let mut resp = http::Response::new("hello".into());
+        resp.headers_mut()
+            .insert(header::CONTENT_LENGTH, "5000".parse().unwrap());
+        resp
+    })
+    .await;
+    assert_eq!(said_so.as_deref(), Some("5000"));
+
+    let no_content = content_length_of(|| {
+        let mut resp = http::Response::new(axum::body::Body::empty());
+        *resp.status_mut() = StatusCode::NO_CONTENT;
+        resp
+    })
+    .await;
+    assert_eq!(no_content, None);
+}
+
+/// A body that's one hunk of data and then some trailers, like a gRPC response.
+struct WithTrailers(Option<http_body::Frame<Bytes>>, Option<http::HeaderMap>);
+
+impl http_body::Body for WithTrailers {
+    type Data = Bytes;
+    type Error = std::convert::Infallible;
+
+    fn poll_frame(
+        mut self: std::pin::Pin<&mut Self>,
+        _cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Option<Result<http_body::Frame<Bytes>, Self::Error>>> {
+        let frame = match self.0.take() {
+            Some(data) => Some(data),
+            None => self.1.take().map(http_body::Frame::trailers),
+        };
+        std::task::Poll::Ready(frame.map(Ok))
+    }
+}
+
+#[tokio::test]
+async fn trailers_get_dropped_out_loud() {
+    let logs = Logs::default();
+    let _guard = logs.capture();
+    let app = Router::new().route(
+        "/trailers",
+        get(|| async {
+            let mut trailers = http::HeaderMap::new();
+            trailers.insert("grpc-status", "0".parse().unwrap());
+            let data = http_body::Frame::data(Bytes::from_static(b"the good part"));
+            axum::body::Body::new(WithTrailers(Some(data), Some(trailers)))
+        }),
+    );
+    let server = TestServer::new(app, Settings::new());
+    let mut conn = server.connect().unwrap();
+    let resp = conn
+        .send(TestRequest::new("GET", "/trailers"))
+        .await
+        .unwrap();
+    // Everything but the trailers made it, and the request ended normally.
+    assert_eq!(resp.status(), Some(StatusCode::OK));
+    assert_eq!(resp.body(), b"the good part");
+    assert_eq!(resp.header("grpc-status"), None);
+    assert_eq!(resp.app_status(), 0);
+    let said = logs.contents();
+    assert!(said.contains("trailers"), "logs were: {}", said);
+    assert!(said.contains("grpc-status"), "logs were: {}", said);
+    // And the connection's still good for another one.
+    let resp = conn
+        .send(TestRequest::new("GET", "/trailers"))
+        .await
+        .unwrap();
+    assert_eq!(resp.body(), b"the good part");
+}
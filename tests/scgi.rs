@@ -0,0 +1,115 @@
+//! SCGI, end to end: a real `FcgiServer` with an `scgi_tcp` listener, and a
+//! bare-bones SCGI client over loopback TCP.
+use axum::routing::{get, post};
+use axum::Router;
+use busride_rs::{FcgiServer, Settings, TransportInfo};
+use bytes::Bytes;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+fn app() -> Router {
+    Router::new()
+        .route(
+            "/hello",
+            get(|req: axum::extract::Request| async move {
+                let gateway = req.extensions().get::<TransportInfo>().map(|t| t.gateway);
+                format!("hello over {:?}", gateway)
+            }),
+        )
+        .route("/echo", post(|body: Bytes| async move { body }))
+}
+
+/// Grab a free port. Somebody else could take it before the server binds it, but
+/// on a test machine, that's not worth worrying about.
+fn free_addr() -> SocketAddr {
+    std::net::TcpListener::bind(("127.0.0.1", 0))
+        .unwrap()
+        .local_addr()
+        .unwrap()
+}
+
+/// Connect once the server's listening; it binds in the background.
+async fn connect(addr: SocketAddr) -> TcpStream {
+    for _ in 0..100 {
+        if let Ok(stream) = TcpStream::connect(addr).await {
+            return stream;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    panic!("SCGI server never started listening at {}", addr);
+}
+
+/// Send one SCGI request and read the whole response (SCGI hangs up when it's done).
+async fn scgi_request(addr: SocketAddr, method: &str, path: &str, body: &[u8]) -> String {
+    let content_length = body.len().to_string();
+    let vars = [
+        ("CONTENT_LENGTH", content_length.as_str()),
+        ("SCGI", "1"),
+        ("REQUEST_METHOD", method),
+        ("REQUEST_URI", path),
+        ("SCRIPT_NAME", ""),
+        ("PATH_INFO", path),
+        ("SERVER_NAME", "localhost"),
+        ("SERVER_PORT", "80"),
+        ("SERVER_PROTOCOL", "HTTP/1.1"),
+    ];
+    let mut headers = Vec::new();
+    for (name, value) in vars {
+        headers.extend_from_slice(name.as_bytes());
+        headers.push(0);
+        headers.extend_from_slice(value.as_bytes());
+        headers.push(0);
+    }
+    let mut stream = connect(addr).await;
+    stream
+        .write_all(format!("{}:", headers.len()).as_bytes())
+        .await
+        .unwrap();
+    stream.write_all(&headers).await.unwrap();
+    stream.write_all(b",").await.unwrap();
+    stream.write_all(body).await.unwrap();
+    let mut resp = Vec::new();
+    stream.read_to_end(&mut resp).await.unwrap();
+    String::from_utf8(resp).unwrap()
+}
+
+#[tokio::test]
+async fn scgi_listener_serves_requests_and_counts_them() {
+    let addr = free_addr();
+    let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+    let server = FcgiServer::new()
+        .scgi_tcp(addr)
+        .settings(Settings::new())
+        .graceful_shutdown(async move {
+            let _ = stopped.await;
+        });
+    let handle = server.handle();
+    let serving = tokio::spawn(server.serve(app()));
+
+    let resp = scgi_request(addr, "GET", "/hello", b"").await;
+    // A 200 is CGI's default, so there's no Status line to look for.
+    assert!(!resp.contains("Status:"), "response was: {}", resp);
+    assert!(
+        resp.ends_with("hello over Some(Scgi)"),
+        "response was: {}",
+        resp
+    );
+
+    let resp = scgi_request(addr, "POST", "/echo", b"over the wire").await;
+    assert!(
+        resp.ends_with("\n\nover the wire"),
+        "response was: {}",
+        resp
+    );
+
+    let resp = scgi_request(addr, "HEAD", "/hello", b"").await;
+    assert!(resp.ends_with("\n\n"), "response was: {}", resp);
+
+    // Through the builder, so the handle saw them like it would FastCGI requests.
+    assert_eq!(handle.requests_served(), 3);
+
+    stop.send(()).unwrap();
+    serving.await.unwrap().unwrap();
+}
@@ -0,0 +1,163 @@
+//! The server's whole lifetime: a real `FcgiServer` on a Unix socket, and when it
+//! decides it's done, plus the one-request version for inetd-style setups.
+use axum::routing::{get, post};
+use axum::Router;
+use busride_rs::testing::{TestConnection, TestRequest};
+use busride_rs::{serve_one_fcgi_request, FcgiServer, Settings, Shutdown};
+use bytes::Bytes;
+use http::StatusCode;
+use std::num::NonZeroU64;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::net::{UnixListener, UnixStream};
+
+fn app(says: &'static str) -> Router {
+    Router::new()
+        .route("/hello", get(move || async move { says }))
+        .route("/echo", post(|body: Bytes| async move { body }))
+}
+
+/// A listener at a fresh path, named after the test so they don't collide.
+fn listen(test: &str) -> (UnixListener, PathBuf) {
+    let path = std::env::temp_dir().join(format!("busride-{}-{}", test, std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let listener = UnixListener::bind(&path).unwrap();
+    (listener, path)
+}
+
+async fn connect(path: &PathBuf) -> TestConnection {
+    TestConnection::new(UnixStream::connect(path).await.unwrap())
+}
+
+async fn hello(conn: &mut TestConnection) -> String {
+    let resp = conn.send(TestRequest::new("GET", "/hello")).await.unwrap();
+    assert_eq!(resp.status(), Some(StatusCode::OK));
+    String::from_utf8(resp.body().to_vec()).unwrap()
+}
+
+/// Wait (within reason) for the server to stop on its own.
+async fn exits(serving: tokio::task::JoinHandle<Result<Shutdown, busride_rs::ServeError>>) {
+    let shutdown = tokio::time::timeout(Duration::from_secs(5), serving)
+        .await
+        .expect("server never shut down")
+        .unwrap()
+        .unwrap();
+    assert_eq!(shutdown, Shutdown::Clean);
+}
+
+#[tokio::test]
+async fn max_requests_shuts_down_after_that_many() {
+    let (listener, path) = listen("max-requests");
+    let server = FcgiServer::new()
+        .unix_listener(listener)
+        .max_requests(NonZeroU64::new(3).unwrap());
+    let handle = server.handle();
+    let serving = tokio::spawn(server.serve(app("hello")));
+
+    for _ in 0..2 {
+        let mut conn = connect(&path).await;
+        assert_eq!(hello(&mut conn).await, "hello");
+    }
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert!(!serving.is_finished(), "quit before using up its quota");
+
+    let mut conn = connect(&path).await;
+    assert_eq!(hello(&mut conn).await, "hello");
+    // Graceful, so the open connection gets to hang up first.
+    drop(conn);
+    exits(serving).await;
+    assert_eq!(handle.requests_served(), 3);
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[tokio::test]
+async fn idle_timeout_shuts_down_once_nothing_is_connected() {
+    let (listener, path) = listen("idle-timeout");
+    let server = FcgiServer::new()
+        .unix_listener(listener)
+        .idle_timeout(Duration::from_millis(100));
+    let serving = tokio::spawn(server.serve(app("hello")));
+
+    // An open connection counts as busy, even if it's not doing anything.
+    let mut conn = connect(&path).await;
+    assert_eq!(hello(&mut conn).await, "hello");
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    assert!(!serving.is_finished(), "got bored with a connection open");
+    assert_eq!(hello(&mut conn).await, "hello");
+
+    drop(conn);
+    exits(serving).await;
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[tokio::test]
+async fn reloads_swap_the_app_for_new_connections_only() {
+    let (listener, path) = listen("reloads");
+    let (reload, reloads) = tokio::sync::mpsc::unbounded_channel();
+    let reloads = futures_util::stream::unfold(reloads, |mut reloads| async move {
+        reloads.recv().await.map(|app| (app, reloads))
+    });
+    let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+    let server = FcgiServer::new()
+        .unix_listener(listener)
+        .graceful_shutdown(async move {
+            let _ = stopped.await;
+        });
+    let serving = tokio::spawn(server.serve_with_reloads(app("old"), reloads));
+
+    let mut before = connect(&path).await;
+    assert_eq!(hello(&mut before).await, "old");
+    reload.send(app("new")).unwrap();
+
+    // The reload happens off to the side, so give it a moment to land.
+    let mut reloaded = None;
+    for _ in 0..100 {
+        let mut conn = connect(&path).await;
+        if hello(&mut conn).await == "new" {
+            reloaded = Some(conn);
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    let mut after = reloaded.expect("the new app never showed up");
+    assert_eq!(hello(&mut after).await, "new");
+    // The connection from before keeps the app it started with.
+    assert_eq!(hello(&mut before).await, "old");
+
+    drop((before, after));
+    stop.send(()).unwrap();
+    exits(serving).await;
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[tokio::test]
+async fn serve_one_fcgi_request_reports_how_it_went() {
+    let (client, server) = UnixStream::pair().unwrap();
+    let serving = tokio::spawn(serve_one_fcgi_request(
+        app("hello"),
+        Settings::new(),
+        server,
+    ));
+    let mut conn = TestConnection::new(client);
+    let resp = conn
+        .send(TestRequest::new("POST", "/echo").body("twelve bytes"))
+        .await
+        .unwrap();
+    assert_eq!(resp.body(), b"twelve bytes");
+    // We asked to keep the connection, so it's up to us to hang up.
+    drop(conn);
+
+    let stats = serving.await.unwrap().expect("no stats for the request");
+    assert_eq!(stats.status, Some(StatusCode::OK));
+    assert_eq!(stats.bytes_in, 12);
+    assert_eq!(stats.bytes_out, resp.raw().len() as u64);
+    assert!(stats.elapsed > Duration::ZERO);
+}
+
+#[tokio::test]
+async fn serve_one_fcgi_request_with_no_request_reports_nothing() {
+    let (client, server) = UnixStream::pair().unwrap();
+    drop(client);
+    let stats = serve_one_fcgi_request(app("hello"), Settings::new(), server).await;
+    assert_eq!(stats, None);
+}